@@ -0,0 +1,404 @@
+use crate::interpreter::*;
+use crate::op::*;
+use crate::*;
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Read, Write};
+use std::ops::Range;
+use std::str::FromStr;
+
+const HELP: &str = "\
+step [n]            (s)   execute n ops (default 1)
+continue            (c)   run until a breakpoint or the end
+reverse-step [n]    (rs)  undo n ops (default 1)
+reverse-continue    (rc)  run backwards until a breakpoint or the start of history
+break <ip>          (b)   set a breakpoint on an op
+delete <ip>         (d)   remove a breakpoint
+last-write <cell>   (lw)  go back to the last op that wrote to the cell
+watch <cell> [== <value>] [log]
+                    (w)   stop (or log) when the cell is written or reaches the value
+watch dp <start..end> [log]
+                    (w)   stop (or log) when the data pointer leaves the range
+unwatch <id>              remove a watchpoint
+goto <step>         (g)   go to any recorded step
+tape [start..end]   (t)   show memory cells
+quit                (q)";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Step(u64),
+    Continue,
+    ReverseStep(u64),
+    ReverseContinue,
+    Break(usize),
+    Delete(usize),
+    LastWrite(usize),
+    Watch(Watch, Action),
+    Unwatch(usize),
+    Goto(u64),
+    Tape(Range<usize>),
+    Help,
+    Quit,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let name = words.next().unwrap_or("help");
+        let arg = words.next();
+        let rest: Vec<&str> = words.collect();
+
+        fn number<T: FromStr>(arg: Option<&str>, default: Option<T>) -> Result<T, String> {
+            match (arg, default) {
+                (Some(arg), _) => arg.parse().map_err(|_| format!("invalid number `{arg}`")),
+                (None, Some(default)) => Ok(default),
+                (None, None) => Err("missing argument".to_string()),
+            }
+        }
+
+        let command = match name {
+            "step" | "s" => Command::Step(number(arg, Some(1))?),
+            "continue" | "c" => Command::Continue,
+            "reverse-step" | "rs" => Command::ReverseStep(number(arg, Some(1))?),
+            "reverse-continue" | "rc" => Command::ReverseContinue,
+            "break" | "b" => Command::Break(number(arg, None)?),
+            "delete" | "d" => Command::Delete(number(arg, None)?),
+            "last-write" | "lw" => Command::LastWrite(number(arg, None)?),
+            "watch" | "w" => {
+                let (rest, action) = match rest.split_last() {
+                    Some((&"log", rest)) => (rest, Action::Log),
+                    _ => (&rest[..], Action::Stop),
+                };
+                let watch = match (arg, rest) {
+                    (Some("dp"), [range]) => Watch::Range(parse_range(range)?),
+                    (Some(_), []) => Watch::Write(number(arg, None)?),
+                    (Some(_), ["==", value]) => {
+                        Watch::Value(number(arg, None)?, number(Some(value), None)?)
+                    }
+                    _ => return Err("invalid watchpoint (see `help`)".to_string()),
+                };
+                Command::Watch(watch, action)
+            }
+            "unwatch" => Command::Unwatch(number(arg, None)?),
+            "goto" | "g" => Command::Goto(number(arg, None)?),
+            "tape" | "t" => Command::Tape(parse_range(arg.unwrap_or("0..16"))?),
+            "help" | "h" => Command::Help,
+            "quit" | "q" => Command::Quit,
+            _ => return Err(format!("unknown command `{name}`")),
+        };
+        Ok(command)
+    }
+}
+
+pub(crate) fn parse_range(s: &str) -> Result<Range<usize>, String> {
+    let invalid = || format!("invalid range `{s}` (expected `start..end`)");
+    let (start, end) = s.split_once("..").ok_or_else(invalid)?;
+    let start: usize = start.parse().map_err(|_| invalid())?;
+    let end: usize = end.parse().map_err(|_| invalid())?;
+    if start > end || end > MEM_SIZE {
+        return Err(invalid());
+    }
+    Ok(start..end)
+}
+
+// the cell under `dp` is shown in brackets
+pub(crate) fn format_tape(memory: &Memory, dp: usize, range: Range<usize>) -> String {
+    let cells: Vec<String> = range
+        .clone()
+        .map(|i| match memory[i] {
+            value if i == dp => format!("[{value}]"),
+            value => value.to_string(),
+        })
+        .collect();
+    format!("{}..{}: {}", range.start, range.end, cells.join(" "))
+}
+
+fn symbol(kind: &OpKind) -> char {
+    match kind {
+        OpKind::Inc => '+',
+        OpKind::Dec => '-',
+        OpKind::Left => '<',
+        OpKind::Right => '>',
+        OpKind::Input => ',',
+        OpKind::Output => '.',
+        OpKind::Jeq0Forward => '[',
+        OpKind::Jne0Backward => ']',
+    }
+}
+
+pub struct Debugger<'a> {
+    interpreter: Interpreter<'a>,
+    breakpoints: BTreeSet<usize>,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(ops: Vec<Op>, memory: &'a mut Memory, history: History) -> Self {
+        Debugger {
+            interpreter: Interpreter::new(ops, memory).with_history(history),
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    pub fn interpreter(&self) -> &Interpreter<'a> {
+        &self.interpreter
+    }
+
+    // read commands line by line until `quit` or the end of `commands`
+    pub fn session<C, O, R, W>(
+        &mut self,
+        commands: C,
+        mut out: O,
+        mut stdin: R,
+        mut stdout: W,
+    ) -> io::Result<()>
+    where
+        C: BufRead,
+        O: Write,
+        R: Read,
+        W: Write,
+    {
+        self.print_position(&mut out)?;
+        for line in commands.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match line.parse() {
+                Ok(Command::Quit) => break,
+                Ok(command) => self.execute(command, &mut out, &mut stdin, &mut stdout)?,
+                Err(e) => writeln!(out, "{e}")?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn execute<O, R, W>(
+        &mut self,
+        command: Command,
+        mut out: O,
+        mut stdin: R,
+        mut stdout: W,
+    ) -> io::Result<()>
+    where
+        O: Write,
+        R: Read,
+        W: Write,
+    {
+        match command {
+            Command::Step(n) => {
+                for _ in 0..n {
+                    if !self.step(&mut out, &mut stdin, &mut stdout)? {
+                        break;
+                    }
+                }
+            }
+            Command::Continue => {
+                while self.step(&mut out, &mut stdin, &mut stdout)? {
+                    if self.breakpoints.contains(&self.interpreter.ip()) {
+                        writeln!(out, "breakpoint at ip {}", self.interpreter.ip())?;
+                        break;
+                    }
+                }
+            }
+            Command::ReverseStep(n) => {
+                for _ in 0..n {
+                    if !self.reverse_step(&mut out)? {
+                        break;
+                    }
+                }
+            }
+            Command::ReverseContinue => {
+                while self.reverse_step(&mut out)? {
+                    if self.breakpoints.contains(&self.interpreter.ip()) {
+                        writeln!(out, "breakpoint at ip {}", self.interpreter.ip())?;
+                        break;
+                    }
+                }
+            }
+            Command::Break(ip) => {
+                self.breakpoints.insert(ip);
+                return writeln!(out, "breakpoint set at ip {ip}");
+            }
+            Command::Delete(ip) => {
+                if !self.breakpoints.remove(&ip) {
+                    return writeln!(out, "no breakpoint at ip {ip}");
+                }
+                return writeln!(out, "breakpoint deleted at ip {ip}");
+            }
+            Command::LastWrite(cell) => match self.interpreter.last_write(cell) {
+                Some(step) => {
+                    self.interpreter.seek(step);
+                    writeln!(out, "cell {cell} was last written at step {step}")?;
+                }
+                None => return writeln!(out, "no write to cell {cell} in recorded history"),
+            },
+            Command::Watch(watch, action) => {
+                let id = self.interpreter.add_watchpoint(watch, action);
+                return writeln!(out, "watchpoint {id} set");
+            }
+            Command::Unwatch(id) => {
+                if !self.interpreter.remove_watchpoint(id) {
+                    return writeln!(out, "no watchpoint {id}");
+                }
+                return writeln!(out, "watchpoint {id} deleted");
+            }
+            Command::Goto(step) => {
+                if !self.interpreter.seek(step) {
+                    let history = self.interpreter.history().expect("history is always on");
+                    return writeln!(
+                        out,
+                        "step {step} is not recorded (steps {}..={} are available)",
+                        history.first_step(),
+                        history.last_step()
+                    );
+                }
+            }
+            Command::Tape(range) => return self.print_tape(&mut out, range),
+            Command::Help => return writeln!(out, "{HELP}"),
+            Command::Quit => return Ok(()),
+        }
+        self.print_position(&mut out)
+    }
+
+    // returns false when the program cannot go any further
+    fn step<O, R, W>(&mut self, mut out: O, stdin: R, stdout: W) -> io::Result<bool>
+    where
+        O: Write,
+        R: Read,
+        W: Write,
+    {
+        let status = self.interpreter.step(stdin, stdout);
+        for hit in self.interpreter.take_watch_log() {
+            writeln!(out, "{hit}")?;
+        }
+        match status {
+            Ok(Status::Running) => Ok(true),
+            Ok(Status::Watchpoint(hit)) => {
+                writeln!(out, "{hit}")?;
+                Ok(false)
+            }
+            Ok(Status::Halted) => {
+                writeln!(out, "program halted")?;
+                Ok(false)
+            }
+            Err(e) => {
+                writeln!(out, "{e}")?;
+                Ok(false)
+            }
+        }
+    }
+
+    fn reverse_step<O: Write>(&mut self, mut out: O) -> io::Result<bool> {
+        if self.interpreter.reverse_step() {
+            return Ok(true);
+        }
+        writeln!(out, "reached the start of recorded history")?;
+        Ok(false)
+    }
+
+    fn print_position<O: Write>(&self, mut out: O) -> io::Result<()> {
+        let interpreter = &self.interpreter;
+        let dp = interpreter.dp();
+        let cell = interpreter.memory().get(dp).copied().unwrap_or_default();
+        let op = match interpreter.ops().get(interpreter.ip()) {
+            Some(Op {
+                kind: kind @ (OpKind::Jeq0Forward | OpKind::Jne0Backward),
+                operand,
+            }) => format!("`{}` -> {operand}", symbol(kind)),
+            Some(Op { kind, operand }) => format!("`{}` x{operand}", symbol(kind)),
+            None => "end".to_string(),
+        };
+        writeln!(
+            out,
+            "[step {}] ip {}: {op} | dp {dp} = {cell}",
+            interpreter.steps(),
+            interpreter.ip()
+        )
+    }
+
+    fn print_tape<O: Write>(&self, mut out: O, range: Range<usize>) -> io::Result<()> {
+        let interpreter = &self.interpreter;
+        writeln!(
+            out,
+            "{}",
+            format_tape(interpreter.memory(), interpreter.dp(), range)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::generate_ops;
+    use std::io::Cursor;
+
+    fn run_session(program: &str, commands: &str) -> (String, Memory) {
+        let mut memory: Memory = [0; MEM_SIZE];
+        let mut out = Vec::new();
+        let mut debugger =
+            Debugger::new(generate_ops(program), &mut memory, History::new(1 << 20, 4));
+        debugger
+            .session(Cursor::new(commands), &mut out, Cursor::new([]), io::sink())
+            .unwrap();
+        (String::from_utf8(out).unwrap(), memory)
+    }
+
+    #[test]
+    fn should_parse_commands() {
+        assert_eq!("s".parse(), Ok(Command::Step(1)));
+        assert_eq!("rs 3".parse(), Ok(Command::ReverseStep(3)));
+        assert_eq!("tape 4..8".parse(), Ok(Command::Tape(4..8)));
+        assert_eq!("lw 2".parse(), Ok(Command::LastWrite(2)));
+        assert!("break".parse::<Command>().is_err());
+        assert!("jump 1".parse::<Command>().is_err());
+        assert_eq!(
+            "w 3 == 65 log".parse(),
+            Ok(Command::Watch(Watch::Value(3, 65), Action::Log))
+        );
+        assert_eq!(
+            "watch dp 0..8".parse(),
+            Ok(Command::Watch(Watch::Range(0..8), Action::Stop))
+        );
+        assert!("watch 3 65".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn should_reverse_to_the_beginning() {
+        let (out, memory) = run_session("++>+++", "c\nrc\n");
+        assert!(out.contains("reached the start of recorded history"));
+        assert!(out.ends_with("[step 0] ip 0: `+` x2 | dp 0 = 0\n"));
+        assert_eq!(memory[..2], [0, 0]);
+    }
+
+    #[test]
+    fn should_stop_at_breakpoints_in_both_directions() {
+        let (out, _) = run_session("++[->+<]", "b 2\nc\nc\nrc\n");
+        let hits = out.matches("breakpoint at ip 2").count();
+        assert_eq!(hits, 3);
+    }
+
+    #[test]
+    fn should_find_last_write() {
+        let (out, memory) = run_session("+>++<[-]>>", "c\nlw 1\n");
+        assert!(out.contains("cell 1 was last written at step 2"));
+        assert!(out.ends_with("[step 2] ip 2: `+` x2 | dp 1 = 0\n"));
+        assert_eq!(memory[..2], [1, 0]);
+    }
+
+    #[test]
+    fn should_stop_at_watchpoints() {
+        let (out, memory) = run_session("+++[>++<-]>>", "w 1 == 4\nc\nc\n");
+        assert!(out.contains("watchpoint 0: cell 1 changed 2 -> 4 [IP:3]"));
+        assert_eq!(out.matches("watchpoint 0:").count(), 1);
+        assert_eq!(memory[1], 6);
+    }
+
+    #[test]
+    fn should_log_watchpoints_without_stopping() {
+        let (out, _) = run_session("+++[>++<-]>>", "w 1 log\nw dp 0..2\nc\n");
+        assert_eq!(out.matches("watchpoint 0: cell 1 changed").count(), 3);
+        assert!(out.contains("watchpoint 1: dp left its range 0 -> 2 [IP:7]"));
+        assert!(!out.contains("program halted"));
+    }
+}
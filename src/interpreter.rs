@@ -1,8 +1,16 @@
+mod history;
+mod watch;
+
 use crate::ir::*;
 use crate::op::*;
 use crate::*;
+use history::Entry;
 use std::fmt;
 use std::io::{Read, Write};
+use watch::Watchpoints;
+
+pub use history::History;
+pub use watch::{Action, Event, Watch, WatchHit};
 
 #[derive(Debug)]
 pub struct RuntimeError {
@@ -10,7 +18,7 @@ pub struct RuntimeError {
 }
 
 impl RuntimeError {
-    fn with_ip(ip: usize, message: &str) -> Self {
+    pub(crate) fn with_ip(ip: usize, message: &str) -> Self {
         RuntimeError {
             message: format!("{message} [IP:{ip}]"),
         }
@@ -23,73 +31,341 @@ impl fmt::Display for RuntimeError {
     }
 }
 
+// `,` and `.` on a single cell, shared with the JIT so that both fail in the same way
+pub(crate) fn read_cell<R: Read>(mut stdin: R) -> Result<Operand, String> {
+    let mut byte = [0; 1];
+    stdin
+        .read(&mut byte[0..1])
+        .map_err(|e| format!("cannot read from stdin ({e})"))?;
+    Ok(byte[0] as i32)
+}
+
+pub(crate) fn write_cell<W: Write>(mut stdout: W, value: Operand) -> Result<(), String> {
+    let byte: u8 = value
+        .try_into()
+        .map_err(|_| "cannot reinterpret the byte into char".to_string())?;
+    if !byte.is_ascii() {
+        return Err("the value is not in the ASCII range".to_string());
+    }
+    write!(stdout, "{}", char::from(byte)).map_err(|e| format!("cannot write to stdout ({e})"))
+}
+
 pub fn interpret<R, W>(
     input: &str,
     memory: &mut Memory,
-    mut stdin: R,
-    mut stdout: W,
+    stdin: R,
+    stdout: W,
 ) -> Result<(), RuntimeError>
 where
     R: Read,
     W: Write,
 {
-    let ops = generate_ops(input);
-    let mut ip = 0; // TODO: use origianl ip without aggregation for better DX
-    let mut dp = 0;
+    Interpreter::new(generate_ops(input), memory).run(stdin, stdout)?;
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    Running,
+    Halted,
+    Watchpoint(WatchHit),
+}
+
+pub struct Interpreter<'a> {
+    ops: Vec<Op>,
+    memory: &'a mut Memory,
+    ip: usize, // TODO: use origianl ip without aggregation for better DX
+    dp: usize,
+    steps: u64,
+    history: Option<History>,
+    watchpoints: Watchpoints,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(ops: Vec<Op>, memory: &'a mut Memory) -> Self {
+        Interpreter {
+            ops,
+            memory,
+            ip: 0,
+            dp: 0,
+            steps: 0,
+            history: None,
+            watchpoints: Watchpoints::default(),
+        }
+    }
 
-    while let Some(op) = ops.get(ip) {
+    // start recording from the current state so that execution can be reversed
+    pub fn with_history(mut self, mut history: History) -> Self {
+        history.begin(self.steps, self.ip, self.dp, self.memory);
+        self.history = Some(history);
+        self
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    pub fn memory(&self) -> &Memory {
+        self.memory
+    }
+
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    pub fn dp(&self) -> usize {
+        self.dp
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    // replace the program but keep memory and the data pointer, e.g. for the next line of a REPL
+    pub fn load(&mut self, ops: Vec<Op>) {
+        self.ops = ops;
+        self.ip = 0;
+        if let Some(history) = &mut self.history {
+            history.begin(self.steps, self.ip, self.dp, self.memory);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.memory.fill(0);
+        (self.ip, self.dp, self.steps) = (0, 0, 0);
+        if let Some(history) = &mut self.history {
+            history.begin(self.steps, self.ip, self.dp, self.memory);
+        }
+    }
+
+    pub fn add_watchpoint(&mut self, watch: Watch, action: Action) -> usize {
+        self.watchpoints.add(watch, action)
+    }
+
+    pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+        self.watchpoints.remove(id)
+    }
+
+    // hits of watchpoints with `Action::Log` since the last call
+    pub fn take_watch_log(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.watchpoints.log)
+    }
+
+    // runs until the program halts or a watchpoint with `Action::Stop` is hit
+    pub fn run<R, W>(&mut self, mut stdin: R, mut stdout: W) -> Result<Status, RuntimeError>
+    where
+        R: Read,
+        W: Write,
+    {
+        // nothing looks at single steps without history or watchpoints, so ops run back to back
+        if self.history.is_none() && self.watchpoints.is_empty() {
+            while self.ip < self.ops.len() {
+                self.execute(&mut stdin, &mut stdout)?;
+                self.steps += 1;
+            }
+            return Ok(Status::Halted);
+        }
+        loop {
+            match self.step(&mut stdin, &mut stdout)? {
+                Status::Running => continue,
+                status => return Ok(status),
+            }
+        }
+    }
+
+    // Runs the loop that starts at `head` somewhere else, e.g. as compiled code, when the current
+    // op is its `[` or a `]` that jumps back to it. `run` gets the tape and the data pointer and
+    // returns the data pointer after the loop, where execution continues. When `run` fails, ip
+    // and dp stay at the loop. Steps and watchpoints do not see what `run` does.
+    pub(crate) fn run_loop<F>(&mut self, head: usize, run: F) -> Result<(), RuntimeError>
+    where
+        F: FnOnce(&mut Memory, usize) -> Result<usize, RuntimeError>,
+    {
+        self.dp = run(self.memory, self.dp)?;
+        self.ip = self.ops[head].operand as usize;
+        Ok(())
+    }
+
+    pub fn step<R, W>(&mut self, stdin: R, stdout: W) -> Result<Status, RuntimeError>
+    where
+        R: Read,
+        W: Write,
+    {
+        // after reversing, steps that were already executed are replayed without doing I/O again
+        if let Some(entry) = self.history.as_ref().and_then(|h| h.redo(self.steps)) {
+            self.redo(entry);
+            return Ok(self.status());
+        }
+        if self.ip >= self.ops.len() {
+            return Ok(Status::Halted);
+        }
+
+        let (ip, dp) = (self.ip, self.dp);
+        let old = self.memory.get(dp).copied().unwrap_or_default();
+        self.execute(stdin, stdout)?;
+        self.steps += 1;
+
+        if let Some(history) = &mut self.history {
+            let new = self.memory.get(dp).copied().unwrap_or_default();
+            let entry = Entry {
+                ip: ip as u32,
+                dp: dp as u32,
+                old,
+                new,
+            };
+            history.record(entry, self.ip, self.dp, self.memory);
+        }
+        Ok(self.status())
+    }
+
+    fn status(&mut self) -> Status {
+        match self.watchpoints.stop.take() {
+            Some(hit) => Status::Watchpoint(hit),
+            None => Status::Running,
+        }
+    }
+
+    // returns false when there is no recorded step to go back to
+    pub fn reverse_step(&mut self) -> bool {
+        let Some(entry) = self.history.as_ref().and_then(|h| h.undo(self.steps)) else {
+            return false;
+        };
+        if let Some(cell) = self.memory.get_mut(entry.dp as usize) {
+            *cell = entry.old;
+        }
+        self.ip = entry.ip as usize;
+        self.dp = entry.dp as usize;
+        self.steps -= 1;
+        true
+    }
+
+    // move to any recorded step, restoring the closest keyframe when that is cheaper
+    pub fn seek(&mut self, step: u64) -> bool {
+        let Some(history) = &self.history else {
+            return false;
+        };
+        if step < history.first_step() || step > history.last_step() {
+            return false;
+        }
+
+        if let Some(keyframe) = history.keyframe_before(step) {
+            // copying a whole memory costs roughly as much as replaying that many steps
+            let replay = step - keyframe.step + MEM_SIZE as u64;
+            if replay < self.steps.abs_diff(step) {
+                self.memory.copy_from_slice(&keyframe.memory[..]);
+                self.ip = keyframe.ip;
+                self.dp = keyframe.dp;
+                self.steps = keyframe.step;
+            }
+        }
+
+        while self.steps > step {
+            self.reverse_step();
+        }
+        while self.steps < step {
+            let entry = self.history.as_ref().and_then(|h| h.redo(self.steps));
+            self.redo(entry.expect("recorded step should be replayable"));
+        }
+        true
+    }
+
+    // the step right before the last op that wrote to `cell`
+    pub fn last_write(&self, cell: usize) -> Option<u64> {
+        self.history
+            .as_ref()
+            .and_then(|h| h.last_write(self.steps, cell, &self.ops))
+    }
+
+    fn redo(&mut self, entry: Entry) {
+        let (ip, dp) = (entry.ip as usize, entry.dp as usize);
+        let Op { kind, operand } = &self.ops[ip];
+        let operand = *operand as usize;
+
+        if let Some(cell) = self.memory.get_mut(dp) {
+            *cell = entry.new;
+        }
+        match kind {
+            OpKind::Inc | OpKind::Dec | OpKind::Input => {
+                self.watchpoints.cell_written(ip, dp, entry.old, entry.new)
+            }
+            OpKind::Left => self.watchpoints.dp_moved(ip, dp, dp - operand),
+            OpKind::Right => self.watchpoints.dp_moved(ip, dp, dp + operand),
+            _ => {}
+        }
+        (self.ip, self.dp) = match kind {
+            OpKind::Left => (ip + 1, dp - operand),
+            OpKind::Right => (ip + 1, dp + operand),
+            OpKind::Jeq0Forward if entry.new == 0 => (operand, dp),
+            OpKind::Jne0Backward if entry.new != 0 => (operand, dp),
+            _ => (ip + 1, dp),
+        };
+        self.steps += 1;
+    }
+
+    #[inline(always)]
+    fn execute<R, W>(&mut self, mut stdin: R, mut stdout: W) -> Result<(), RuntimeError>
+    where
+        R: Read,
+        W: Write,
+    {
+        let (ops, memory, ip, dp) = (&self.ops, &mut *self.memory, self.ip, &mut self.dp);
+        let watchpoints = &mut self.watchpoints;
+        let op = &ops[ip];
         let operand = op.operand as usize;
         match op.kind {
-            OpKind::Inc => memory[dp] += op.operand,
-            OpKind::Dec => memory[dp] -= op.operand,
+            OpKind::Inc => {
+                let old = memory[*dp];
+                memory[*dp] += op.operand;
+                watchpoints.cell_written(ip, *dp, old, memory[*dp]);
+            }
+            OpKind::Dec => {
+                let old = memory[*dp];
+                memory[*dp] -= op.operand;
+                watchpoints.cell_written(ip, *dp, old, memory[*dp]);
+            }
             OpKind::Left => {
-                if dp < operand {
+                if *dp < operand {
                     return Err(RuntimeError::with_ip(ip, "data pointer is negative"));
                 }
-                dp -= operand;
+                *dp -= operand;
+                watchpoints.dp_moved(ip, *dp + operand, *dp);
             }
             OpKind::Right => {
-                if dp + operand > MEM_SIZE {
+                if *dp + operand >= MEM_SIZE {
                     return Err(RuntimeError::with_ip(
                         ip,
                         "data pointer exceeded memory size",
                     ));
                 }
-                dp += operand;
+                *dp += operand;
+                watchpoints.dp_moved(ip, *dp - operand, *dp);
             }
             OpKind::Input => {
+                let old = memory[*dp];
                 for _ in 0..operand {
-                    let mut byte = [0; 1];
-                    stdin.read(&mut byte[0..1]).map_err(|e| {
-                        RuntimeError::with_ip(ip, &format!("cannot read from stdin ({e})"))
-                    })?;
-                    memory[dp] = byte[0] as i32;
+                    memory[*dp] =
+                        read_cell(&mut stdin).map_err(|e| RuntimeError::with_ip(ip, &e))?;
                 }
+                watchpoints.cell_written(ip, *dp, old, memory[*dp]);
             }
             OpKind::Output => {
                 // TODO: use buffer for optimization
                 for _ in 0..operand {
-                    let byte: u8 = memory[dp].try_into().map_err(|_| {
-                        RuntimeError::with_ip(ip, "cannot reinterpret the byte into char")
-                    })?;
-                    if !byte.is_ascii() {
-                        return Err(RuntimeError::with_ip(
-                            ip,
-                            "the value is not in the ASCII range",
-                        ));
-                    }
-                    write!(stdout, "{}", char::from(byte)).map_err(|e| {
-                        RuntimeError::with_ip(ip, &format!("cannot write to stdout ({e})"))
-                    })?;
+                    write_cell(&mut stdout, memory[*dp])
+                        .map_err(|e| RuntimeError::with_ip(ip, &e))?;
                 }
             }
             OpKind::Jeq0Forward => {
                 if MEM_SIZE < operand {
                     return Err(RuntimeError::with_ip(ip, "instruction pointer is negative"));
                 }
-                if memory[dp] == 0 {
-                    ip = operand;
-                    continue;
+                if memory[*dp] == 0 {
+                    self.ip = operand;
+                    return Ok(());
                 }
             }
             OpKind::Jne0Backward => {
@@ -99,21 +375,22 @@ where
                         "instruction pointer exceeded instruction buffer",
                     ));
                 }
-                if memory[dp] != 0 {
-                    ip = operand;
-                    continue;
+                if memory[*dp] != 0 {
+                    self.ip = operand;
+                    return Ok(());
                 }
             }
         }
-        ip += 1;
+        self.ip += 1;
+        Ok(())
     }
-    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::io::Cursor;
+    use std::mem::size_of;
 
     // Helper function to simplify tests
     fn run_interpret(
@@ -156,6 +433,18 @@ mod tests {
         }
     }
 
+    #[test]
+    fn should_keep_the_data_pointer_below_the_memory_size() {
+        // the last cell is MEM_SIZE - 1, and a move onto MEM_SIZE fails before it is made
+        let (result, ..) = run_interpret(&">".repeat(MEM_SIZE), &[]);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "RUNTIME ERROR: data pointer exceeded memory size [IP:0]"
+        );
+        let (result, ..) = run_interpret(&">".repeat(MEM_SIZE - 1), &[]);
+        assert!(result.is_ok());
+    }
+
     #[test]
     fn should_interpret_loops_correctly() {
         let (result, ..) = run_interpret("++[->+<]", &[]);
@@ -176,4 +465,123 @@ mod tests {
         let (result, ..) = run_interpret("++[->+<]>++.", &[]);
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn should_reverse_and_replay_without_repeating_io() {
+        let mut memory: Memory = [0; MEM_SIZE];
+        let mut output = Vec::new();
+        let mut interpreter =
+            Interpreter::new(generate_ops(",+.>++"), &mut memory).with_history(History::default());
+        interpreter.run(Cursor::new([65]), &mut output).unwrap();
+        assert_eq!(interpreter.steps(), 5);
+
+        while interpreter.reverse_step() {}
+        assert_eq!(
+            (interpreter.steps(), interpreter.ip(), interpreter.dp()),
+            (0, 0, 0)
+        );
+        assert_eq!(interpreter.memory()[..2], [0, 0]);
+
+        // input is not read again, and output is not written again
+        interpreter.run(Cursor::new([]), &mut output).unwrap();
+        assert_eq!(interpreter.memory()[..2], [66, 2]);
+        assert_eq!(output, b"B");
+    }
+
+    #[test]
+    fn should_run_without_history_as_it_steps() {
+        for program in ["++[->+<]>++.", "+>>+[<<]"] {
+            let (mut fast, mut slow) = ([0; MEM_SIZE], [0; MEM_SIZE]);
+            let mut fast = Interpreter::new(generate_ops(program), &mut fast);
+            let mut slow =
+                Interpreter::new(generate_ops(program), &mut slow).with_history(History::default());
+            let fast_result = fast
+                .run(Cursor::new([]), Vec::new())
+                .map_err(|e| e.to_string());
+            let slow_result = slow
+                .run(Cursor::new([]), Vec::new())
+                .map_err(|e| e.to_string());
+            assert_eq!(fast_result, slow_result);
+            assert_eq!(
+                (fast.steps(), fast.ip(), fast.dp()),
+                (slow.steps(), slow.ip(), slow.dp())
+            );
+            assert_eq!(fast.memory()[..4], slow.memory()[..4]);
+        }
+    }
+
+    #[test]
+    fn should_evict_history_by_keyframe_segments() {
+        let mut memory: Memory = [0; MEM_SIZE];
+        let history = History::new(2 * size_of::<Memory>() + 1024, 8);
+        let mut interpreter =
+            Interpreter::new(generate_ops(&"+>".repeat(100)), &mut memory).with_history(history);
+        interpreter.run(Cursor::new([]), Vec::new()).unwrap();
+
+        let history = interpreter.history().unwrap();
+        assert_eq!(history.first_step() % 8, 0);
+        assert!(history.first_step() > 0);
+        assert!(history.bytes() <= 2 * size_of::<Memory>() + 1024);
+
+        let first = history.first_step();
+        assert!(!interpreter.seek(first - 1));
+        assert!(interpreter.seek(first));
+        assert_eq!(interpreter.ip(), first as usize);
+        assert!(!interpreter.reverse_step());
+        assert!(interpreter.seek(first + 6));
+        let dp = interpreter.dp();
+        assert_eq!(dp, (first as usize + 6) / 2);
+        assert_eq!(interpreter.memory()[dp - 1..=dp], [1, 0]);
+    }
+
+    #[test]
+    fn should_keep_history_within_the_budget_between_keyframes() {
+        let budget = 2 * size_of::<Memory>() + 10 * size_of::<Entry>();
+        let mut memory: Memory = [0; MEM_SIZE];
+        let mut interpreter = Interpreter::new(generate_ops(&"+>".repeat(100)), &mut memory)
+            .with_history(History::new(budget, 8));
+        while interpreter.step(Cursor::new([]), Vec::new()).unwrap() == Status::Running {
+            let history = interpreter.history().unwrap();
+            assert!(history.bytes() <= budget, "step {}", interpreter.steps());
+        }
+        assert_eq!(interpreter.history().unwrap().first_step() % 8, 0);
+    }
+
+    #[test]
+    fn should_seek_through_keyframes() {
+        let mut memory: Memory = [0; MEM_SIZE];
+        let history = History::new(usize::MAX, 1 << 16);
+        let mut interpreter = Interpreter::new(generate_ops(&"+>+<".repeat(40000)), &mut memory)
+            .with_history(history);
+        interpreter.run(Cursor::new([]), Vec::new()).unwrap();
+
+        assert!(interpreter.seek(70000));
+        assert_eq!(interpreter.memory()[..2], [17500, 17500]);
+        assert!(interpreter.seek(70003));
+        assert_eq!(interpreter.memory()[..2], [17501, 17501]);
+        assert_eq!(interpreter.dp(), 1);
+    }
+
+    #[test]
+    fn should_stop_run_at_watchpoints() {
+        let mut memory: Memory = [0; MEM_SIZE];
+        let mut interpreter = Interpreter::new(generate_ops(">+<"), &mut memory);
+        interpreter.add_watchpoint(Watch::Range(1..2), Action::Log);
+        let id = interpreter.add_watchpoint(Watch::Write(1), Action::Stop);
+
+        let status = interpreter.run(Cursor::new([]), Vec::new()).unwrap();
+        let event = Event::Write {
+            cell: 1,
+            old: 0,
+            new: 1,
+        };
+        assert_eq!(status, Status::Watchpoint(WatchHit { id, ip: 1, event }));
+        assert!(interpreter.take_watch_log().is_empty());
+
+        assert!(interpreter.remove_watchpoint(id));
+        let status = interpreter.run(Cursor::new([]), Vec::new()).unwrap();
+        assert_eq!(status, Status::Halted);
+        let log = interpreter.take_watch_log();
+        assert_eq!(log[0].event, Event::Leave { from: 1, to: 0 });
+    }
 }
@@ -0,0 +1,136 @@
+use crate::op::*;
+use crate::Memory;
+use std::collections::VecDeque;
+use std::mem::size_of;
+
+const DEFAULT_BUDGET: usize = 256 * 1024 * 1024; // 256 MiB
+const DEFAULT_INTERVAL: u64 = 1 << 20;
+
+// Every op only writes the cell under `dp`, so the cell value before/after is all we need to
+// undo it, and the pointer move is recovered from (ip, dp) of the op itself when redoing.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Entry {
+    pub ip: u32,
+    pub dp: u32,
+    pub old: Operand,
+    pub new: Operand,
+}
+
+pub(super) struct Keyframe {
+    pub step: u64,
+    pub ip: usize,
+    pub dp: usize,
+    pub memory: Box<Memory>,
+}
+
+pub struct History {
+    budget: usize,
+    interval: u64,
+    first: u64, // step number of `entries[0]`
+    entries: VecDeque<Entry>,
+    keyframes: VecDeque<Keyframe>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new(DEFAULT_BUDGET, DEFAULT_INTERVAL)
+    }
+}
+
+impl History {
+    // `budget` is in bytes, `interval` is the number of steps between keyframes. Whole segments
+    // from a keyframe to the next are dropped to stay within the budget, except the latest, so
+    // the budget should hold one keyframe (the size of `Memory`) and `interval` steps.
+    pub fn new(budget: usize, interval: u64) -> Self {
+        assert!(interval > 0, "keyframe interval should be positive");
+        History {
+            budget,
+            interval,
+            first: 0,
+            entries: VecDeque::new(),
+            keyframes: VecDeque::new(),
+        }
+    }
+
+    // the earliest step that can still be reached by reverse execution
+    pub fn first_step(&self) -> u64 {
+        self.first
+    }
+
+    // the latest recorded step; steps before it are replayed instead of executed
+    pub fn last_step(&self) -> u64 {
+        self.first + self.entries.len() as u64
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.entries.len() * size_of::<Entry>() + self.keyframes.len() * size_of::<Memory>()
+    }
+
+    pub(super) fn begin(&mut self, step: u64, ip: usize, dp: usize, memory: &Memory) {
+        self.first = step;
+        self.entries.clear();
+        self.keyframes.clear();
+        self.keyframes.push_back(Keyframe {
+            step,
+            ip,
+            dp,
+            memory: Box::new(*memory),
+        });
+    }
+
+    // `ip`, `dp` and `memory` describe the state right after `entry` was executed
+    pub(super) fn record(&mut self, entry: Entry, ip: usize, dp: usize, memory: &Memory) {
+        self.entries.push_back(entry);
+
+        let step = self.last_step();
+        if step.is_multiple_of(self.interval) {
+            self.keyframes.push_back(Keyframe {
+                step,
+                ip,
+                dp,
+                memory: Box::new(*memory),
+            });
+        }
+
+        // evict whole segments so that the oldest reachable step always starts at a keyframe
+        while self.bytes() > self.budget && self.keyframes.len() > 1 {
+            self.keyframes.pop_front();
+            let next = self.keyframes[0].step;
+            self.entries.drain(..(next - self.first) as usize);
+            self.first = next;
+        }
+    }
+
+    // the entry that brings `step` to `step - 1`
+    pub(super) fn undo(&self, step: u64) -> Option<Entry> {
+        if step <= self.first {
+            return None;
+        }
+        self.entries.get((step - 1 - self.first) as usize).copied()
+    }
+
+    // the entry that brings `step` to `step + 1`
+    pub(super) fn redo(&self, step: u64) -> Option<Entry> {
+        if step < self.first {
+            return None;
+        }
+        self.entries.get((step - self.first) as usize).copied()
+    }
+
+    pub(super) fn keyframe_before(&self, step: u64) -> Option<&Keyframe> {
+        self.keyframes.iter().rev().find(|kf| kf.step <= step)
+    }
+
+    // the step of the last op before `step` that wrote to `cell`
+    pub(super) fn last_write(&self, step: u64, cell: usize, ops: &[Op]) -> Option<u64> {
+        let end = step.clamp(self.first, self.last_step());
+        (self.first..end).rev().find(|&s| {
+            let entry = self.entries[(s - self.first) as usize];
+            entry.dp as usize == cell
+                && matches!(
+                    ops[entry.ip as usize].kind,
+                    OpKind::Inc | OpKind::Dec | OpKind::Input
+                )
+        })
+    }
+}
@@ -0,0 +1,136 @@
+use crate::op::*;
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Watch {
+    Write(usize),
+    Value(usize, Operand),
+    Range(Range<usize>), // triggers when `dp` leaves the range
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Stop,
+    Log,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Write {
+        cell: usize,
+        old: Operand,
+        new: Operand,
+    },
+    Leave {
+        from: usize,
+        to: usize,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchHit {
+    pub id: usize,
+    pub ip: usize,
+    pub event: Event,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let WatchHit { id, ip, .. } = self;
+        match &self.event {
+            Event::Write { cell, old, new } => {
+                write!(
+                    f,
+                    "watchpoint {id}: cell {cell} changed {old} -> {new} [IP:{ip}]"
+                )
+            }
+            Event::Leave { from, to } => {
+                write!(
+                    f,
+                    "watchpoint {id}: dp left its range {from} -> {to} [IP:{ip}]"
+                )
+            }
+        }
+    }
+}
+
+// Cell and pointer watches are kept apart so that each op only looks at the kind it can trigger,
+// and an empty list is the only thing checked when nothing is watched.
+#[derive(Default)]
+pub(super) struct Watchpoints {
+    cells: Vec<(usize, Watch, Action)>,
+    ranges: Vec<(usize, Range<usize>, Action)>,
+    next_id: usize,
+    pub stop: Option<WatchHit>,
+    pub log: Vec<WatchHit>,
+}
+
+impl Watchpoints {
+    pub fn add(&mut self, watch: Watch, action: Action) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        match watch {
+            Watch::Range(range) => self.ranges.push((id, range, action)),
+            watch => self.cells.push((id, watch, action)),
+        }
+        id
+    }
+
+    pub fn remove(&mut self, id: usize) -> bool {
+        let (cells, ranges) = (self.cells.len(), self.ranges.len());
+        self.cells.retain(|(i, ..)| *i != id);
+        self.ranges.retain(|(i, ..)| *i != id);
+        cells != self.cells.len() || ranges != self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty() && self.ranges.is_empty()
+    }
+
+    #[inline]
+    pub fn cell_written(&mut self, ip: usize, cell: usize, old: Operand, new: Operand) {
+        if self.cells.is_empty() {
+            return;
+        }
+        for (id, watch, action) in &self.cells {
+            let triggered = match watch {
+                Watch::Write(c) => *c == cell,
+                Watch::Value(c, value) => *c == cell && old != *value && new == *value,
+                Watch::Range(_) => false,
+            };
+            if triggered {
+                let event = Event::Write { cell, old, new };
+                Self::hit(&mut self.stop, &mut self.log, *id, ip, event, *action);
+            }
+        }
+    }
+
+    #[inline]
+    pub fn dp_moved(&mut self, ip: usize, from: usize, to: usize) {
+        if self.ranges.is_empty() {
+            return;
+        }
+        for (id, range, action) in &self.ranges {
+            if range.contains(&from) && !range.contains(&to) {
+                let event = Event::Leave { from, to };
+                Self::hit(&mut self.stop, &mut self.log, *id, ip, event, *action);
+            }
+        }
+    }
+
+    fn hit(
+        stop: &mut Option<WatchHit>,
+        log: &mut Vec<WatchHit>,
+        id: usize,
+        ip: usize,
+        event: Event,
+        action: Action,
+    ) {
+        let hit = WatchHit { id, ip, event };
+        match action {
+            Action::Stop if stop.is_none() => *stop = Some(hit),
+            _ => log.push(hit),
+        }
+    }
+}
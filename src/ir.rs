@@ -1,32 +1,110 @@
 use crate::lexer::*;
 use crate::op::*;
 use crate::*;
+use std::fmt;
+
+mod text;
+
+pub(crate) use text::print_op;
+pub use text::{parse_ir, print_ir};
+
+#[derive(Debug)]
+pub struct ParseError {
+    message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
 
 // TODO: return Err instead of panic
 pub fn generate_ops(input: &str) -> Vec<Op> {
-    let lexer = Lexer::new(input);
-    let mut backpatches = BackPatchingStack::new();
+    let mut builder = OpsBuilder::default();
+    builder
+        .feed(input)
+        .and_then(|_| builder.finish())
+        .unwrap_or_else(|e| panic!("{e}"))
+}
+
+// the ops of the file at `path`, which is textual IR if it ends in `.ir`, and brainf*** otherwise
+pub fn load_ops(path: &str, source: &str) -> Result<Vec<Op>, ParseError> {
+    if path.ends_with(".ir") {
+        parse_ir(source)
+    } else {
+        Ok(generate_ops(source))
+    }
+}
+
+// where an op starts in brainf*** source, both 1-based
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourcePos {
+    pub line: usize,
+    pub column: usize,
+}
+
+// the position of each op that `generate_ops` makes from `input`, which aggregates runs in the
+// same way
+pub fn source_map(input: &str) -> Vec<SourcePos> {
+    let mut positions = Vec::new();
+    let mut last = None;
+    for (line, text) in input.lines().enumerate() {
+        for (column, c) in text.chars().enumerate() {
+            let Some(kind) = Lexer::new(c.encode_utf8(&mut [0; 4])).next() else {
+                continue;
+            };
+            let aggregated =
+                last == Some(kind) && kind != OpKind::Jeq0Forward && kind != OpKind::Jne0Backward;
+            if !aggregated {
+                positions.push(SourcePos {
+                    line: line + 1,
+                    column: column + 1,
+                });
+            }
+            last = Some(kind);
+        }
+    }
+    positions
+}
+
+// Builds ops from input that arrives in pieces (e.g. line by line), so that a loop can be
+// opened in one piece and closed in a later one.
+#[derive(Default)]
+pub struct OpsBuilder {
+    ops: Vec<Op>,
+    backpatches: BackPatchingStack,
+    idx: usize, // number of lexed operations so far
+}
+
+impl OpsBuilder {
+    pub fn feed(&mut self, input: &str) -> Result<(), ParseError> {
+        for op_kind in Lexer::new(input) {
+            let idx = self.idx;
+            self.idx += 1;
+            let acc = &mut self.ops;
 
-    let commands = lexer
-        .enumerate()
-        .fold(Vec::new(), |mut acc: Vec<Op>, (idx, op_kind)| {
             if let Some(Op { kind, operand, .. }) = acc.last_mut() {
                 if *kind == op_kind
                     && op_kind != OpKind::Jeq0Forward
                     && op_kind != OpKind::Jne0Backward
                 {
                     *operand += 1;
-                    return acc;
+                    continue;
                 }
             }
 
             if op_kind == OpKind::Jeq0Forward {
-                backpatches.push(acc.len() as Operand);
+                self.backpatches.push(acc.len() as Operand);
             } else if op_kind == OpKind::Jne0Backward {
                 let curr = acc.len() as Operand;
-                let matching = backpatches.pop().expect(&format!(
-                    "invalid program: `[` and `]` should match (`]` exceeds) [IDX:{idx}]"
-                ));
+                let Some(matching) = self.backpatches.pop() else {
+                    return Err(ParseError {
+                        message: format!(
+                            "invalid program: `[` and `]` should match (`]` exceeds) [IDX:{idx}]"
+                        ),
+                    });
+                };
 
                 if let Some(op) = acc.get_mut(matching as usize) {
                     op.operand = curr + 1; // set the operand of `[`
@@ -35,23 +113,44 @@ pub fn generate_ops(input: &str) -> Vec<Op> {
                     kind: op_kind,
                     operand: matching + 1, // set the operand of `]`
                 });
-                return acc;
+                continue;
             }
 
             acc.push(Op {
                 kind: op_kind,
                 operand: 1,
             });
-            acc
-        });
+        }
+        Ok(())
+    }
 
-    if !backpatches.is_empty() {
-        panic!(
-            "invalid program: `[` and `]` should match ({} `[`s left)",
-            backpatches.len()
-        );
+    // the number of `[`s still waiting for their `]`
+    pub fn open_loops(&self) -> usize {
+        self.backpatches.len()
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    // hands over the ops built so far once every loop is closed, and counts the input that comes
+    // after from 0 again, as the ops do
+    pub fn take(&mut self) -> Option<Vec<Op>> {
+        if self.open_loops() > 0 {
+            return None;
+        }
+        self.idx = 0;
+        Some(std::mem::take(&mut self.ops))
+    }
+
+    pub fn finish(mut self) -> Result<Vec<Op>, ParseError> {
+        self.take().ok_or_else(|| ParseError {
+            message: format!(
+                "invalid program: `[` and `]` should match ({} `[`s left)",
+                self.open_loops()
+            ),
+        })
     }
-    commands
 }
 
 #[cfg(test)]
@@ -176,4 +275,61 @@ mod tests {
         let result = generate_ops(input);
         assert_eq!(result, expected);
     }
+
+    #[test]
+    fn should_load_ir_only_from_files_ending_in_ir() {
+        assert_eq!(load_ops("a.ir", "inc 2").unwrap(), generate_ops("++"));
+        assert_eq!(load_ops("a.bf", "inc 2").unwrap(), generate_ops(""));
+        assert!(load_ops("a.ir", "++").is_err());
+    }
+
+    #[test]
+    fn should_match_loops_across_pieces() {
+        let mut builder = OpsBuilder::default();
+        builder.feed("+[-").unwrap();
+        assert_eq!(builder.open_loops(), 1);
+        assert_eq!(builder.take(), None);
+
+        builder.feed(">+<]").unwrap();
+        assert_eq!(builder.open_loops(), 0);
+        assert_eq!(builder.take(), Some(generate_ops("+[->+<]")));
+        assert_eq!(builder.take(), Some(vec![]));
+
+        let e = builder.feed("+]").unwrap_err();
+        assert!(e.to_string().contains("(`]` exceeds) [IDX:1]"));
+    }
+
+    #[test]
+    fn should_report_unmatched_loops_without_panicking() {
+        let mut builder = OpsBuilder::default();
+        builder.feed("+").unwrap();
+        let e = builder.feed("+]").unwrap_err();
+        assert!(e.to_string().contains("(`]` exceeds) [IDX:2]"));
+
+        let mut builder = OpsBuilder::default();
+        builder.feed("[[").unwrap();
+        let e = builder.finish().unwrap_err();
+        assert!(e.to_string().contains("(2 `[`s left)"));
+    }
+
+    #[test]
+    fn should_map_ops_to_source_positions() {
+        let input = "++ +[-\n>+ comment <]\n\n.";
+        let positions = source_map(input);
+        assert_eq!(positions.len(), generate_ops(input).len());
+        let pos = |line, column| SourcePos { line, column };
+        assert_eq!(
+            positions,
+            [
+                pos(1, 1),
+                pos(1, 5),
+                pos(1, 6),
+                pos(2, 1),
+                pos(2, 2),
+                pos(2, 12),
+                pos(2, 13),
+                pos(4, 1)
+            ]
+        );
+    }
 }
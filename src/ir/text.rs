@@ -0,0 +1,229 @@
+use super::ParseError;
+use crate::op::*;
+use crate::BackPatchingStack;
+use std::fmt::Write;
+
+// One op per line; `[` and `]` become a `loop { ... }` block, so jump targets never appear
+// in the text and are recomputed by the parser:
+//
+//     inc 2
+//     loop {
+//         dec 1
+//         right 1
+//     }
+//     out 1
+//
+// `#` starts a comment that runs to the end of the line.
+
+const INDENT: &str = "    ";
+
+fn mnemonic(kind: &OpKind) -> &'static str {
+    match kind {
+        OpKind::Inc => "inc",
+        OpKind::Dec => "dec",
+        OpKind::Left => "left",
+        OpKind::Right => "right",
+        OpKind::Input => "in",
+        OpKind::Output => "out",
+        OpKind::Jeq0Forward => "loop {",
+        OpKind::Jne0Backward => "}",
+    }
+}
+
+// a single op as it appears on its line
+pub(crate) fn print_op(op: &Op) -> String {
+    match op.kind {
+        OpKind::Jeq0Forward | OpKind::Jne0Backward => mnemonic(&op.kind).to_string(),
+        _ => format!("{} {}", mnemonic(&op.kind), op.operand),
+    }
+}
+
+pub fn print_ir(ops: &[Op]) -> String {
+    let mut text = String::new();
+    let mut depth: usize = 0;
+    for op in ops {
+        // an unmatched `}` stays at the left margin
+        if op.kind == OpKind::Jne0Backward {
+            depth = depth.saturating_sub(1);
+        }
+        writeln!(text, "{}{}", INDENT.repeat(depth), print_op(op)).unwrap();
+        if op.kind == OpKind::Jeq0Forward {
+            depth += 1;
+        }
+    }
+    text
+}
+
+pub fn parse_ir(text: &str) -> Result<Vec<Op>, ParseError> {
+    let mut ops = Vec::new();
+    let mut backpatches = BackPatchingStack::new();
+    let error = |line: usize, message: &str| ParseError {
+        message: format!("invalid IR: {message} [LINE:{}]", line + 1),
+    };
+
+    for (line, source) in text.lines().enumerate() {
+        let source = source.split('#').next().unwrap_or_default();
+        let source = source.replace('{', " { ").replace('}', " } ");
+        let mut words = source.split_whitespace().peekable();
+
+        while let Some(word) = words.next() {
+            let kind = match word {
+                "inc" => OpKind::Inc,
+                "dec" => OpKind::Dec,
+                "left" => OpKind::Left,
+                "right" => OpKind::Right,
+                "in" => OpKind::Input,
+                "out" => OpKind::Output,
+                "loop" => {
+                    if words.next() != Some("{") {
+                        return Err(error(line, "`loop` should be followed by `{`"));
+                    }
+                    backpatches.push(ops.len() as Operand);
+                    ops.push(Op {
+                        kind: OpKind::Jeq0Forward,
+                        operand: 0, // patched at the matching `}`
+                    });
+                    continue;
+                }
+                "}" => {
+                    let Some(matching) = backpatches.pop() else {
+                        return Err(error(line, "`}` does not close any loop"));
+                    };
+                    ops[matching as usize].operand = ops.len() as Operand + 1;
+                    ops.push(Op {
+                        kind: OpKind::Jne0Backward,
+                        operand: matching + 1,
+                    });
+                    continue;
+                }
+                _ => return Err(error(line, &format!("unknown op `{word}`"))),
+            };
+
+            // the operand is optional and defaults to 1
+            let operand = match words.peek().map(|w| w.parse::<Operand>()) {
+                Some(Ok(operand)) if operand > 0 => {
+                    words.next();
+                    operand
+                }
+                Some(Ok(_)) => return Err(error(line, "operand should be positive")),
+                _ => 1,
+            };
+            ops.push(Op { kind, operand });
+        }
+    }
+
+    if !backpatches.is_empty() {
+        return Err(ParseError {
+            message: format!("invalid IR: {} `loop`s left open", backpatches.len()),
+        });
+    }
+    Ok(ops)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::generate_ops;
+    use crate::MEM_SIZE;
+
+    #[test]
+    fn should_print_nested_loops() {
+        let ops = generate_ops("++[->[-]+<].");
+        let expected = "\
+inc 2
+loop {
+    dec 1
+    right 1
+    loop {
+        dec 1
+    }
+    inc 1
+    left 1
+}
+out 1
+";
+        assert_eq!(print_ir(&ops), expected);
+    }
+
+    #[test]
+    fn should_parse_what_it_prints() {
+        for program in [
+            "",
+            "+++---",
+            "++[->+<]>.-",
+            include_str!("../../example/hello.bf"),
+            include_str!("../../example/bsort.bf"),
+            &">".repeat(MEM_SIZE),
+        ] {
+            let ops = generate_ops(program);
+            let text = print_ir(&ops);
+            assert_eq!(parse_ir(&text).unwrap(), ops);
+        }
+    }
+
+    #[test]
+    fn should_print_unmatched_loop_ends_without_panicking() {
+        let ops = [
+            Op {
+                kind: OpKind::Jne0Backward,
+                operand: 0,
+            },
+            Op {
+                kind: OpKind::Inc,
+                operand: 1,
+            },
+        ];
+        assert_eq!(print_ir(&ops), "}\ninc 1\n");
+    }
+
+    #[test]
+    fn should_aggregate_runs_into_the_expected_ir() {
+        let cases = [
+            ("+++--->><<<", "inc 3\ndec 3\nright 2\nleft 3\n"),
+            (
+                "[[-]>>,,..]",
+                "loop {\n    loop {\n        dec 1\n    }\n    right 2\n    in 2\n    out 2\n}\n",
+            ),
+            ("+ comment +\n+[]", "inc 3\nloop {\n}\n"),
+        ];
+        for (program, expected) in cases {
+            let ops = generate_ops(program);
+            assert_eq!(print_ir(&ops), expected, "{program}");
+            assert_eq!(parse_ir(expected).unwrap(), ops, "{program}");
+        }
+    }
+
+    #[test]
+    fn should_accept_moves_off_the_tape() {
+        // every engine traps on them as it runs
+        let text = format!("right {MEM_SIZE}\nleft {}", Operand::MAX);
+        let ops = parse_ir(&text).unwrap();
+        assert_eq!(print_ir(&ops), format!("{text}\n"));
+    }
+
+    #[test]
+    fn should_parse_hand_written_ir() {
+        let text = "
+            # move cell 0 to cell 1
+            inc 3
+            loop { dec right inc left }   # operands default to 1
+            in out 2
+        ";
+        assert_eq!(parse_ir(text).unwrap(), generate_ops("+++[->+<],.."));
+    }
+
+    #[test]
+    fn should_report_invalid_ir() {
+        let cases = [
+            ("inc 1\nmul 2", "unknown op `mul` [LINE:2]"),
+            ("loop dec", "`loop` should be followed by `{` [LINE:1]"),
+            ("inc\n}", "`}` does not close any loop [LINE:2]"),
+            ("dec 0", "operand should be positive [LINE:1]"),
+            ("loop {\nloop {\n}", "1 `loop`s left open"),
+        ];
+        for (text, message) in cases {
+            let e = parse_ir(text).unwrap_err();
+            assert_eq!(e.to_string(), format!("invalid IR: {message}"));
+        }
+    }
+}
@@ -1,4 +1,11 @@
-use std::fmt;
+use crate::interpreter::{read_cell, write_cell, RuntimeError};
+use crate::ir::*;
+use crate::op::*;
+use crate::{Memory, MEM_SIZE};
+use memmap2::Mmap;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::{fmt, mem};
 
 #[derive(Debug)]
 pub struct JitCompileError {
@@ -6,8 +13,6 @@ pub struct JitCompileError {
 }
 
 impl JitCompileError {
-    // When target_arch and target_os are not matched, this associated function is never used
-    #[allow(dead_code)]
     fn with_ip(ip: usize, message: &str) -> Self {
         JitCompileError {
             message: format!("{message} [IP:{ip}]"),
@@ -16,7 +21,7 @@ impl JitCompileError {
 
     fn new(message: &str) -> Self {
         JitCompileError {
-            message: format!("{message}"),
+            message: message.to_string(),
         }
     }
 }
@@ -37,21 +42,977 @@ impl From<std::io::Error> for JitCompileError {
 
 impl From<JitCompileError> for std::io::Error {
     fn from(value: JitCompileError) -> Self {
-        value.into()
+        std::io::Error::other(value.to_string())
     }
 }
 
-#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+// Everything a target has to encode; validation, loop bookkeeping and error reporting are shared
+// by all targets through `compile`.
+pub trait Backend {
+    // where a loop head was emitted, so that its forward branch can be patched at the loop tail
+    type Label;
+
+    fn prologue(&mut self);
+    fn epilogue(&mut self);
+    fn add_to_cell(&mut self, amount: Operand);
+    // in cells, less than `MEM_SIZE` either way; `trap` is the status to return with when the
+    // pointer leaves the memory
+    fn move_pointer(&mut self, amount: Operand, trap: Option<u64>);
+    // returns with `status` wherever the pointer is, for a move that always leaves the memory
+    fn trap(&mut self, status: u64);
+    // `io_error` is the status to return with when a callback fails, and `not_ascii` when the cell
+    // cannot be written as a byte
+    fn input(&mut self, io_error: u64);
+    fn output(&mut self, not_ascii: u64, io_error: u64);
+    fn loop_head(&mut self) -> Self::Label;
+    fn loop_tail(&mut self, head: Self::Label) -> Result<(), &'static str>;
+    // bytes emitted so far
+    fn code_len(&self) -> usize;
+    fn finish(self) -> Result<Vec<u8>, &'static str>;
+}
+
+// Generated code is called as `JitEntry` with the tape, a run context and the cell index to start
+// the data pointer at, and returns a status, which is 0, or `ip << 8 | trap` when it stops at a
+// trap, with the cell index of the data pointer. The tape is the interpreter's `Memory`, so either
+// can take over from the other with nothing but the data pointer.
+type JitEntry<R, W> =
+    extern "C" fn(tape: *mut Operand, context: *mut RunContext<R, W>, dp: u64) -> JitExit;
+
+#[repr(C)]
+struct JitExit {
+    status: u64,
+    dp: u64,
+}
+
+pub(crate) const OUTPUT_BUFFER_SIZE: usize = 4096;
+
+// Generated code only touches the fields before `input`, whose offsets do not depend on `R` and
+// `W`. `.` appends to `buffer` and calls `flush` when it is full; `,` calls `read`, which flushes
+// first. A callback returns nonzero when it fails, and leaves the reason in `error`.
+#[repr(C)]
+struct RunContext<R, W> {
+    read: extern "C" fn(context: *mut RunContext<R, W>, cell: *mut Operand) -> u32, // +0
+    flush: extern "C" fn(context: *mut RunContext<R, W>) -> u32,                    // +8
+    buffer: *mut u8,                                                                // +16
+    len: usize,                                                                     // +24
+    input: R,
+    output: W,
+    error: Option<String>,
+}
+
+impl<R: Read, W: Write> RunContext<R, W> {
+    fn flush_output(&mut self) -> Result<(), String> {
+        // Safety: generated code has written `len` bytes to `buffer`
+        let bytes = unsafe { std::slice::from_raw_parts(self.buffer, self.len) };
+        self.len = 0;
+        self.output
+            .write_all(bytes)
+            .and_then(|_| self.output.flush())
+            .map_err(|e| format!("cannot write to stdout ({e})"))
+    }
+
+    fn fail(&mut self, message: String) -> u32 {
+        self.error = Some(message);
+        1
+    }
+}
+
+extern "C" fn read_callback<R: Read, W: Write>(
+    context: *mut RunContext<R, W>,
+    cell: *mut Operand,
+) -> u32 {
+    // Safety: `context` is what `JitProgram::run` passed to generated code, and `cell` is within
+    // the tape
+    let context = unsafe { &mut *context };
+    match context
+        .flush_output()
+        .and_then(|_| read_cell(&mut context.input))
+    {
+        Ok(value) => {
+            unsafe { *cell = value };
+            0
+        }
+        Err(message) => context.fail(message),
+    }
+}
+
+extern "C" fn flush_callback<R: Read, W: Write>(context: *mut RunContext<R, W>) -> u32 {
+    // Safety: same as `read_callback`
+    let context = unsafe { &mut *context };
+    match context.flush_output() {
+        Ok(()) => 0,
+        Err(message) => context.fail(message),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Trap {
+    NegativeDataPointer = 1,
+    DataPointerOverflow = 2,
+    Io = 3,
+    Output = 4, // the cell is not ASCII, which the host explains
+}
+
+impl Trap {
+    pub(crate) fn status(self, ip: usize) -> u64 {
+        (ip as u64) << 8 | self as u64
+    }
+}
+
+// `io_error` is what the I/O callback left when it failed
+fn check_status(status: u64, io_error: Option<String>) -> Result<(), RuntimeError> {
+    let ip = (status >> 8) as usize;
+    let message = match status & 0xff {
+        0 => return Ok(()),
+        s if s == Trap::NegativeDataPointer as u64 => "data pointer is negative",
+        s if s == Trap::DataPointerOverflow as u64 => "data pointer exceeded memory size",
+        s if s == Trap::Io as u64 || s == Trap::Output as u64 => {
+            let message = io_error.unwrap_or_else(|| "unknown I/O error".to_string());
+            return Err(RuntimeError::with_ip(ip, &message));
+        }
+        _ => "unknown trap",
+    };
+    Err(RuntimeError::with_ip(ip, message))
+}
+
+// `balanced[ip]` is true when the loop starting at `ip` always ends where it started, so the data
+// pointer is the same on every iteration and after the loop
+fn balanced_loops(ops: &[Op]) -> Vec<bool> {
+    let mut balanced = vec![false; ops.len()];
+    let mut loops: Vec<(usize, Option<Operand>)> = Vec::new(); // `[` and the movement so far
+    for (idx, op) in ops.iter().enumerate() {
+        match (op.kind, loops.last_mut()) {
+            // a loop that moves too far to tell is not balanced
+            (OpKind::Left, Some((_, net))) => *net = net.and_then(|n| n.checked_sub(op.operand)),
+            (OpKind::Right, Some((_, net))) => *net = net.and_then(|n| n.checked_add(op.operand)),
+            (OpKind::Jeq0Forward, _) => loops.push((idx, Some(0))),
+            (OpKind::Jne0Backward, _) => {
+                let Some((head, net)) = loops.pop() else {
+                    continue;
+                };
+                balanced[head] = net == Some(0);
+                if let (false, Some((_, parent))) = (balanced[head], loops.last_mut()) {
+                    *parent = None;
+                }
+            }
+            _ => {}
+        }
+    }
+    balanced
+}
+
+// Machine code, and where the code of each op starts in it. `op_offsets[ops.len()]` is where the
+// epilogue starts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MachineCode {
+    pub bytes: Vec<u8>,
+    pub op_offsets: Vec<usize>,
+}
+
+// Code for `ops[range]` alone, which starts with the data pointer at `dp`, or anywhere when it is
+// `None`. Traps report ips in `ops`, and `op_offsets` has an entry for each op in `range`.
+pub fn compile<B: Backend>(
+    mut backend: B,
+    ops: &[Op],
+    range: Range<usize>,
+    mut dp: Option<usize>, // statically known data pointer, which needs no boundary check
+) -> Result<MachineCode, JitCompileError> {
+    let mut backpatches = Vec::new();
+    let mut op_offsets = Vec::with_capacity(range.len() + 1);
+    let balanced = balanced_loops(ops);
+
+    backend.prologue();
+    for (idx, op) in ops.iter().enumerate().take(range.end).skip(range.start) {
+        op_offsets.push(backend.code_len());
+        let Op { kind, operand } = *op;
+        match kind {
+            OpKind::Inc => backend.add_to_cell(operand),
+            OpKind::Dec => backend.add_to_cell(operand.wrapping_neg()),
+            OpKind::Left | OpKind::Right => {
+                let (amount, trap) = match kind {
+                    OpKind::Left => (operand.wrapping_neg(), Trap::NegativeDataPointer),
+                    _ => (operand, Trap::DataPointerOverflow),
+                };
+                // no pointer on the tape can move that far, and backends need not encode it
+                if operand as usize >= MEM_SIZE {
+                    backend.trap(trap.status(idx));
+                    continue;
+                }
+                let next = dp
+                    .and_then(|dp: usize| dp.checked_add_signed(amount as isize))
+                    .filter(|&dp| dp < MEM_SIZE);
+                let trap = next.is_none().then(|| trap.status(idx));
+                backend.move_pointer(amount, trap);
+                dp = next;
+            }
+            OpKind::Input => {
+                for _ in 0..operand {
+                    backend.input(Trap::Io.status(idx));
+                }
+            }
+            OpKind::Output => {
+                for _ in 0..operand {
+                    backend.output(Trap::Output.status(idx), Trap::Io.status(idx));
+                }
+            }
+            OpKind::Jeq0Forward => {
+                if !balanced[idx] {
+                    dp = None;
+                }
+                backpatches.push((idx, backend.loop_head()));
+            }
+            OpKind::Jne0Backward => {
+                let Some((_, head)) = backpatches.pop() else {
+                    return Err(JitCompileError::with_ip(
+                        idx,
+                        &format!(
+                            "invalid program: `[` and `]` should match (`]` exceeds) [IDX:{idx}]"
+                        ),
+                    ));
+                };
+                backend
+                    .loop_tail(head)
+                    .map_err(|message| JitCompileError::with_ip(idx, message))?;
+            }
+        }
+    }
+
+    // the first `[` that is left open
+    if let Some(&(idx, _)) = backpatches.first() {
+        return Err(JitCompileError::with_ip(
+            idx,
+            "invalid program: `[` and `]` should match",
+        ));
+    }
+    op_offsets.push(backend.code_len());
+    backend.epilogue();
+
+    let bytes = backend.finish().map_err(JitCompileError::new)?;
+    Ok(MachineCode { bytes, op_offsets })
+}
+
 pub mod aarch64;
-#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
-pub use aarch64::jit_compile;
-
-#[cfg(not(any(all(target_arch = "aarch64", target_os = "linux"))))]
-pub fn jit_compile(
-    _input: &str,
-    _memory: &mut crate::Memory,
-) -> Result<memmap2::Mmap, JitCompileError> {
-    Err(JitCompileError::new(
-        "JIT compiler is not supported on this architecture with OS",
-    ))
+mod aot;
+mod debuginfo;
+mod elf;
+mod gdb;
+mod listing;
+pub mod x86_64;
+
+pub use aot::{build_executable, build_object};
+pub use listing::print_asm;
+
+// Code can be emitted for any target on any host, but only runs on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    X86_64,
+    Aarch64,
+}
+
+impl Target {
+    // the target whose code runs here, if the JIT supports this host
+    pub fn host() -> Option<Target> {
+        if cfg!(all(target_arch = "x86_64", target_os = "linux")) {
+            Some(Target::X86_64)
+        } else if cfg!(all(target_arch = "aarch64", target_os = "linux")) {
+            Some(Target::Aarch64)
+        } else {
+            None
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Target::X86_64 => "x86_64",
+            Target::Aarch64 => "aarch64",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Target> {
+        [Target::X86_64, Target::Aarch64]
+            .into_iter()
+            .find(|target| target.name() == name)
+    }
+}
+
+// machine code for `target`, which is called as `JitEntry`
+pub fn emit_code(ops: &[Op], target: Target) -> Result<MachineCode, JitCompileError> {
+    emit_range(ops, 0..ops.len(), Some(0), target)
+}
+
+// machine code for the loop that starts at `head` alone, which runs from any data pointer and
+// returns once the loop exits
+pub(crate) fn emit_loop(
+    ops: &[Op],
+    head: usize,
+    target: Target,
+) -> Result<MachineCode, JitCompileError> {
+    let tail = ops[head].operand as usize - 1;
+    emit_range(ops, head..tail + 1, None, target)
+}
+
+fn emit_range(
+    ops: &[Op],
+    range: Range<usize>,
+    dp: Option<usize>,
+    target: Target,
+) -> Result<MachineCode, JitCompileError> {
+    match target {
+        Target::X86_64 => compile(x86_64::X86_64::default(), ops, range, dp),
+        Target::Aarch64 => compile(aarch64::Aarch64::default(), ops, range, dp),
+    }
+}
+
+// Compiled code, which borrows a tape only while it runs
+pub struct JitProgram {
+    // dropped before the code is unmapped
+    _gdb: Option<gdb::Registration>,
+    code: Mmap,
+    end: usize, // the ip after the last op
+    // the data pointer that the whole program is compiled to start at, where moves that stay on
+    // the tape from there are not checked, or `None` for a loop, which checks every move
+    start: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RunStats {
+    pub dp: usize,
+}
+
+impl JitProgram {
+    pub fn run<R, W>(
+        &mut self,
+        tape: &mut Memory,
+        input: R,
+        output: W,
+    ) -> Result<RunStats, RuntimeError>
+    where
+        R: Read,
+        W: Write,
+    {
+        self.run_from(tape, 0, input, output)
+    }
+
+    // runs from the data pointer `dp`, which is 0 unless the code is from `jit_compile_loop`
+    pub(crate) fn run_from<R, W>(
+        &mut self,
+        tape: &mut Memory,
+        dp: usize,
+        input: R,
+        output: W,
+    ) -> Result<RunStats, RuntimeError>
+    where
+        R: Read,
+        W: Write,
+    {
+        // Safety: the code is generated for `JitEntry` by `compile`, and it touches nothing but
+        // `tape`, within its bounds, and `context` during the call. That holds for any ops, as
+        // `compile` traps on every move of `MEM_SIZE` or more, and checks every other move that
+        // it cannot prove to stay on the tape from `start`, which `dp` is checked against.
+        assert!(dp < MEM_SIZE, "the data pointer starts on the tape");
+        if let Some(start) = self.start {
+            assert_eq!(dp, start, "the program starts where it is compiled to");
+        }
+        let entry = unsafe { mem::transmute::<*const u8, JitEntry<R, W>>(self.code.as_ptr()) };
+        run_code(self.end, tape, dp, input, output, |tape, context, dp| {
+            entry(tape, context, dp)
+        })
+    }
+}
+
+// Sets up a run context for generated code, which `enter` calls natively or, in tests, emulates,
+// and turns how it exits into the interpreter's result
+fn run_code<R, W, F>(
+    end: usize,
+    tape: &mut Memory,
+    dp: usize,
+    input: R,
+    output: W,
+    enter: F,
+) -> Result<RunStats, RuntimeError>
+where
+    R: Read,
+    W: Write,
+    F: FnOnce(*mut Operand, *mut RunContext<R, W>, u64) -> JitExit,
+{
+    let mut buffer = vec![0; OUTPUT_BUFFER_SIZE];
+    let mut context = RunContext {
+        read: read_callback::<R, W>,
+        flush: flush_callback::<R, W>,
+        buffer: buffer.as_mut_ptr(),
+        len: 0,
+        input,
+        output,
+        error: None,
+    };
+    let exit = enter(tape.as_mut_ptr(), &mut context, dp as u64);
+    let dp = exit.dp as usize;
+
+    // what was written before a trap still goes out, as with the interpreter
+    let flushed = context.flush_output();
+    let mut error = context.error.take();
+    if exit.status & 0xff == Trap::Output as u64 {
+        error = write_cell(io::sink(), tape[dp]).err();
+    }
+    check_status(exit.status, error)?;
+    flushed.map_err(|message| RuntimeError::with_ip(end, &message))?;
+    Ok(RunStats { dp })
+}
+
+pub fn jit_compile(input: &str) -> Result<JitProgram, JitCompileError> {
+    jit_compile_ops(&generate_ops(input))
+}
+
+// What else `jit_compile_ops_with` does with the code, for profilers and debuggers
+#[derive(Debug, Clone, Default)]
+pub struct JitOptions {
+    // append a symbol for each part of the code to `/tmp/perf-<pid>.map`, which `perf` reads
+    pub perf_map: bool,
+    // register the code with gdb's JIT interface
+    pub gdb: bool,
+    // the file the ops come from, and, for brainf***, the position of each op in it, which gdb
+    // maps the code back to
+    pub source_path: Option<String>,
+    pub positions: Option<Vec<SourcePos>>,
+}
+
+fn host_target() -> Result<Target, JitCompileError> {
+    Target::host().ok_or_else(|| {
+        JitCompileError::new("JIT compiler is not supported on this architecture with OS")
+    })
+}
+
+fn load(code: &MachineCode) -> Result<Mmap, JitCompileError> {
+    let mut mmap = memmap2::MmapMut::map_anon(code.bytes.len())?;
+    mmap.copy_from_slice(&code.bytes);
+    Ok(mmap.make_exec()?)
+}
+
+// The loop that starts at `head`, for `JitProgram::run_from`, which returns after the loop
+pub(crate) fn jit_compile_loop(ops: &[Op], head: usize) -> Result<JitProgram, JitCompileError> {
+    let code = emit_loop(ops, head, host_target()?)?;
+    Ok(JitProgram {
+        _gdb: None,
+        code: load(&code)?,
+        end: ops[head].operand as usize,
+        start: None,
+    })
+}
+
+pub fn jit_compile_ops(ops: &[Op]) -> Result<JitProgram, JitCompileError> {
+    jit_compile_ops_with(ops, &JitOptions::default())
+}
+
+pub fn jit_compile_ops_with(
+    ops: &[Op],
+    options: &JitOptions,
+) -> Result<JitProgram, JitCompileError> {
+    let target = host_target()?;
+    let code = emit_code(ops, target)?;
+    let mmap = load(&code)?;
+
+    let base = mmap.as_ptr() as usize;
+    let symbols = debuginfo::code_symbols(ops, &code, options.positions.as_deref());
+    if options.perf_map {
+        let path = format!("/tmp/perf-{}.map", std::process::id());
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(debuginfo::perf_map(base, &symbols).as_bytes())?;
+    }
+    let gdb = options.gdb.then(|| {
+        let dir = std::env::current_dir().unwrap_or_default();
+        let dir = dir.to_string_lossy();
+        let source = match (&options.source_path, &options.positions) {
+            (Some(path), Some(positions)) => Some(debuginfo::Source {
+                path,
+                dir: &dir,
+                positions,
+            }),
+            _ => None,
+        };
+        gdb::register(debuginfo::debug_object(
+            target, base, &code, &symbols, source,
+        ))
+    });
+
+    Ok(JitProgram {
+        _gdb: gdb,
+        code: mmap,
+        end: ops.len(),
+        start: Some(0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // records what the driver asks for, so the shared part can be tested on any host
+    #[derive(Default)]
+    struct Recorder {
+        calls: Vec<String>,
+    }
+
+    impl Backend for Recorder {
+        type Label = usize;
+
+        fn prologue(&mut self) {
+            self.calls.push("prologue".to_string());
+        }
+        fn epilogue(&mut self) {
+            self.calls.push("epilogue".to_string());
+        }
+        fn add_to_cell(&mut self, amount: Operand) {
+            self.calls.push(format!("add {amount}"));
+        }
+        fn move_pointer(&mut self, amount: Operand, trap: Option<u64>) {
+            match trap {
+                Some(status) => self.calls.push(format!("move {amount} trap {status:#x}")),
+                None => self.calls.push(format!("move {amount}")),
+            }
+        }
+        fn trap(&mut self, status: u64) {
+            self.calls.push(format!("trap {status:#x}"));
+        }
+        fn input(&mut self, _trap: u64) {
+            self.calls.push("input".to_string());
+        }
+        fn output(&mut self, _not_ascii: u64, _io_error: u64) {
+            self.calls.push("output".to_string());
+        }
+        fn loop_head(&mut self) -> usize {
+            self.calls.push("head".to_string());
+            self.calls.len() - 1
+        }
+        fn loop_tail(&mut self, head: usize) -> Result<(), &'static str> {
+            if self.calls.len() - head > 4 {
+                return Err("loop body is too large");
+            }
+            self.calls.push(format!("tail {head}"));
+            Ok(())
+        }
+        fn code_len(&self) -> usize {
+            self.calls.len()
+        }
+        fn finish(self) -> Result<Vec<u8>, &'static str> {
+            Ok(self.calls.join("; ").into_bytes())
+        }
+    }
+
+    fn record(ops: &[Op]) -> Result<String, JitCompileError> {
+        let code = compile(Recorder::default(), ops, 0..ops.len(), Some(0))?;
+        Ok(String::from_utf8(code.bytes).unwrap())
+    }
+
+    #[test]
+    fn should_drive_backend_through_ops() {
+        let ops = generate_ops("++[->><<<]>,..");
+        let expected = "prologue; add 2; head; add -1; move 2 trap 0x302; move -3 trap 0x401; \
+                        tail 2; move 1 trap 0x602; input; output; output; epilogue";
+        assert_eq!(record(&ops).unwrap(), expected);
+    }
+
+    #[test]
+    fn should_check_only_unknown_pointer_moves() {
+        // balanced loops keep the pointer known, unbalanced ones lose it
+        let ops = generate_ops(">[>-<]<<[>]>");
+        let expected = "prologue; move 1; head; move 1; add -1; move -1; tail 2; \
+                        move -2 trap 0x601; head; move 1 trap 0x802; tail 8; \
+                        move 1 trap 0xa02; epilogue";
+        assert_eq!(record(&ops).unwrap(), expected);
+
+        let ops = generate_ops(&">".repeat(MEM_SIZE - 1));
+        let expected = format!("prologue; move {}; epilogue", MEM_SIZE - 1);
+        assert_eq!(record(&ops).unwrap(), expected);
+    }
+
+    #[test]
+    fn should_trap_on_moves_beyond_the_memory() {
+        let ops = generate_ops(&format!(
+            "+{}-{}",
+            ">".repeat(MEM_SIZE),
+            "<".repeat(MEM_SIZE)
+        ));
+        let expected = "prologue; add 1; trap 0x102; add -1; trap 0x301; epilogue";
+        assert_eq!(record(&ops).unwrap(), expected);
+
+        // too far to add up in a loop
+        let op = |kind, operand| Op { kind, operand };
+        let ops = [
+            op(OpKind::Jeq0Forward, 4),
+            op(OpKind::Right, Operand::MAX),
+            op(OpKind::Right, Operand::MAX),
+            op(OpKind::Jne0Backward, 1),
+        ];
+        let expected = "prologue; head; trap 0x102; trap 0x202; tail 1; epilogue";
+        assert_eq!(record(&ops).unwrap(), expected);
+    }
+
+    #[test]
+    fn should_decode_trap_status() {
+        assert!(check_status(0, None).is_ok());
+        let e = check_status(Trap::NegativeDataPointer.status(3), None).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            RuntimeError::with_ip(3, "data pointer is negative").to_string()
+        );
+        let e = check_status(Trap::DataPointerOverflow.status(5), None).unwrap_err();
+        assert!(e.to_string().contains("data pointer exceeded memory size"));
+        let e = check_status(Trap::Io.status(7), Some("oops".to_string())).unwrap_err();
+        assert_eq!(e.to_string(), RuntimeError::with_ip(7, "oops").to_string());
+    }
+
+    #[test]
+    fn should_report_errors_with_ip() {
+        let e = record(&generate_ops("[+>+>+>]")).unwrap_err();
+        assert_eq!(e.message, "loop body is too large [IP:7]");
+
+        let ops = vec![Op {
+            kind: OpKind::Jne0Backward,
+            operand: 1,
+        }];
+        let e = record(&ops).unwrap_err();
+        assert!(e.message.contains("(`]` exceeds) [IDX:0]"));
+
+        let mut builder = OpsBuilder::default();
+        builder.feed("+[[").unwrap();
+        let e = record(builder.ops()).unwrap_err();
+        assert_eq!(
+            e.message,
+            "invalid program: `[` and `]` should match [IP:1]"
+        );
+    }
+
+    #[test]
+    fn should_emit_code_for_every_target() {
+        let ops = generate_ops("+[->+<].");
+        let x86_64 = emit_code(&ops, Target::X86_64).unwrap().bytes;
+        assert_eq!(x86_64[..4], [0x55, 0x48, 0x89, 0xe5]); // push rbp; mov rbp, rsp
+        let aarch64 = emit_code(&ops, Target::Aarch64).unwrap().bytes;
+        assert_eq!(aarch64[..4], 0xa9bd7bfdu32.to_le_bytes()); // stp x29, x30, [sp, #-48]!
+        assert!(aarch64.len().is_multiple_of(4));
+
+        if Target::host().is_none() {
+            assert!(jit_compile_ops(&ops).is_err());
+        }
+    }
+
+    #[test]
+    fn should_describe_code_to_perf_and_gdb() {
+        if Target::host().is_none() {
+            return;
+        }
+        // the map of this process, which is removed when the test ends, even if it fails
+        struct PerfMap(String);
+        impl Drop for PerfMap {
+            fn drop(&mut self) {
+                let _ = std::fs::remove_file(&self.0);
+            }
+        }
+        let path = PerfMap(format!("/tmp/perf-{}.map", std::process::id()));
+
+        let input = "+\n[-]";
+        let options = JitOptions {
+            perf_map: true,
+            gdb: true,
+            source_path: Some("a.bf".to_string()),
+            positions: Some(source_map(input)),
+        };
+        let mut program = jit_compile_ops_with(&generate_ops(input), &options).unwrap();
+        let base = program.code.as_ptr() as usize;
+        let map = std::fs::read_to_string(&path.0).unwrap();
+        assert!(map.contains(&format!("{base:x} ")));
+        assert!(map.lines().any(|line| line.ends_with(" bf_loop@2:1")));
+
+        // registered code still runs
+        let mut tape = [0; MEM_SIZE];
+        program.run(&mut tape, io::empty(), io::sink()).unwrap();
+    }
+
+    mod programs {
+        use super::*;
+        use crate::jitc::aarch64::emulator::EmulatedProgram;
+        use crate::testing::{interpret, programs};
+        use crate::{Interpreter, Memory};
+        use std::cell::RefCell;
+        use std::io::{self, Cursor};
+        use std::panic::{self, AssertUnwindSafe};
+        use std::rc::Rc;
+
+        // native code where the host has a backend, and aarch64 code in the emulator on any host
+        enum Engine {
+            Native(JitProgram),
+            Emulated(EmulatedProgram),
+        }
+
+        impl Engine {
+            fn run<R: Read, W: Write>(
+                &mut self,
+                tape: &mut Memory,
+                input: R,
+                output: W,
+            ) -> Result<RunStats, RuntimeError> {
+                match self {
+                    Engine::Native(program) => program.run(tape, input, output),
+                    Engine::Emulated(program) => program.run(tape, input, output),
+                }
+            }
+
+            fn run_from<R: Read, W: Write>(
+                &mut self,
+                tape: &mut Memory,
+                dp: usize,
+                input: R,
+                output: W,
+            ) -> Result<RunStats, RuntimeError> {
+                match self {
+                    Engine::Native(program) => program.run_from(tape, dp, input, output),
+                    Engine::Emulated(program) => program.run_from(tape, dp, input, output),
+                }
+            }
+        }
+
+        fn engines(program: &str) -> Vec<(&'static str, Engine)> {
+            engines_for_ops(&generate_ops(program))
+        }
+
+        fn engines_for_ops(ops: &[Op]) -> Vec<(&'static str, Engine)> {
+            let mut engines = vec![(
+                "aarch64 emulator",
+                Engine::Emulated(EmulatedProgram::compile(ops).unwrap()),
+            )];
+            if Target::host().is_some() {
+                engines.push(("native", Engine::Native(jit_compile_ops(ops).unwrap())));
+            }
+            engines
+        }
+
+        // the same program and input through the interpreter and every engine
+        fn assert_same_as_interpreter(program: &str, input: &[u8]) {
+            assert_ops_same_as_interpreter(&generate_ops(program), program, input);
+        }
+
+        // for ops that no brainf*** source makes, where `program` names them in messages
+        fn assert_ops_same_as_interpreter(ops: &[Op], program: &str, input: &[u8]) {
+            let expected = interpret(ops, input);
+
+            for (name, mut engine) in engines_for_ops(ops) {
+                let mut memory: Memory = [0; MEM_SIZE];
+                let mut output = Vec::new();
+                let result = engine.run(&mut memory, Cursor::new(input), &mut output);
+
+                let outcome = result.as_ref().map(|_| ()).map_err(|e| e.to_string());
+                assert_eq!(outcome, expected.result, "{name}: {program}");
+                if let Ok(stats) = result {
+                    assert_eq!(stats.dp, expected.dp, "{name}: {program}");
+                }
+                assert_eq!(output, expected.output, "{name}: {program}");
+                assert!(memory == *expected.memory, "{name}: {program}");
+            }
+        }
+
+        #[test]
+        fn should_run_basic_operations() {
+            assert_same_as_interpreter("+++>--->>+<<<+", &[]);
+            for (program, input) in programs() {
+                assert_same_as_interpreter(&program, input);
+            }
+        }
+
+        #[test]
+        fn should_run_loops() {
+            assert_same_as_interpreter("++[->+<]", &[]);
+            assert_same_as_interpreter("[+++]++++[>++++[>+++<-]<-]>>[-<+>]", &[]);
+        }
+
+        #[test]
+        fn should_run_long_pointer_moves() {
+            let program = format!("{}+++{}-", ">".repeat(1000), "<".repeat(999));
+            assert_same_as_interpreter(&program, &[]);
+        }
+
+        #[test]
+        fn should_trap_like_the_interpreter() {
+            let too_far = ">".repeat(MEM_SIZE);
+            for program in ["+<", "+[>+]", "+[<+]", "<", &too_far] {
+                assert_same_as_interpreter(program, &[]);
+            }
+        }
+
+        #[test]
+        fn should_stay_on_the_tape_with_any_operand() {
+            // the interpreter cannot even take negative moves, so these only have to trap
+            for kind in [OpKind::Right, OpKind::Left] {
+                for operand in [-1, -(MEM_SIZE as Operand), Operand::MIN] {
+                    let ops = [
+                        Op {
+                            kind: OpKind::Right,
+                            operand: 1,
+                        },
+                        Op { kind, operand },
+                        Op {
+                            kind: OpKind::Inc,
+                            operand: 1,
+                        },
+                    ];
+                    for (name, mut engine) in engines_for_ops(&ops) {
+                        let mut memory: Memory = [0; MEM_SIZE];
+                        let result = engine.run(&mut memory, io::empty(), io::sink());
+                        assert!(result.is_err(), "{name}: {kind:?} {operand}");
+                        assert!(memory.iter().all(|&cell| cell == 0), "{name}");
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn should_trap_on_moves_beyond_the_memory_like_the_interpreter() {
+            for kind in [OpKind::Right, OpKind::Left] {
+                for operand in [MEM_SIZE as Operand, 1 << 29, 1 << 30, Operand::MAX] {
+                    let ops = [
+                        Op { kind, operand },
+                        Op {
+                            kind: OpKind::Inc,
+                            operand: 1,
+                        },
+                        Op {
+                            kind: OpKind::Output,
+                            operand: 1,
+                        },
+                    ];
+                    let program = format!("{kind:?} {operand}");
+                    assert_ops_same_as_interpreter(&ops, &program, &[]);
+                }
+            }
+        }
+
+        #[test]
+        fn should_do_io_like_the_interpreter() {
+            assert_same_as_interpreter(",.,,.>,.", b"abc");
+            assert_same_as_interpreter(include_str!("../example/hello.bf"), &[]);
+            assert_same_as_interpreter(include_str!("../example/bsort.bf"), b"4213");
+            // invalid output, and EOF leaves 0 in the cell
+            assert_same_as_interpreter("-.", &[]);
+            assert_same_as_interpreter(&format!("{}.", "+".repeat(200)), &[]);
+            assert_same_as_interpreter("+,.", &[]);
+            // more than the output buffer holds
+            let program = format!("{}[>{}<-]>.", "+".repeat(100), ".".repeat(100));
+            assert_same_as_interpreter(&program, &[]);
+        }
+
+        #[test]
+        fn should_report_io_errors_like_the_interpreter() {
+            struct Broken;
+            impl io::Read for Broken {
+                fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                    Err(io::Error::other("broken"))
+                }
+            }
+            impl io::Write for Broken {
+                fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                    Err(io::Error::other("broken"))
+                }
+                fn flush(&mut self) -> io::Result<()> {
+                    Ok(())
+                }
+            }
+
+            let mut memory: Memory = [0; MEM_SIZE];
+            let expected = Interpreter::new(generate_ops("+>,"), &mut memory)
+                .run(Broken, Broken)
+                .unwrap_err();
+            for (name, mut engine) in engines("+>,") {
+                let e = engine.run(&mut memory, Broken, Broken).unwrap_err();
+                assert_eq!(e.to_string(), expected.to_string(), "{name}");
+            }
+
+            // buffered output fails when it is flushed at exit
+            let expected = RuntimeError::with_ip(5, "cannot write to stdout (broken)");
+            for (name, mut engine) in engines("+>+.+") {
+                let e = engine.run(&mut memory, Broken, Broken).unwrap_err();
+                assert_eq!(e.to_string(), expected.to_string(), "{name}");
+            }
+        }
+
+        #[test]
+        fn should_flush_output_before_input() {
+            // checks what has been written by the time it is read from
+            struct Prompted(Rc<RefCell<Vec<u8>>>);
+            impl io::Read for Prompted {
+                fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                    assert_eq!(&self.0.borrow()[..], b"A");
+                    buf[0] = b'z';
+                    Ok(1)
+                }
+            }
+            struct Shared(Rc<RefCell<Vec<u8>>>);
+            impl io::Write for Shared {
+                fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                    self.0.borrow_mut().write(buf)
+                }
+                fn flush(&mut self) -> io::Result<()> {
+                    Ok(())
+                }
+            }
+
+            let program = format!("{}.,.", "+".repeat(65));
+            for (name, mut engine) in engines(&program) {
+                let output = Rc::new(RefCell::new(Vec::new()));
+                let mut memory: Memory = [0; MEM_SIZE];
+                engine
+                    .run(
+                        &mut memory,
+                        Prompted(output.clone()),
+                        Shared(output.clone()),
+                    )
+                    .unwrap();
+                assert_eq!(&output.borrow()[..], b"Az", "{name}");
+            }
+        }
+
+        #[test]
+        fn should_run_a_loop_alone_from_any_dp() {
+            let ops = generate_ops(">[<+>-]");
+            let mut loops = vec![(
+                "aarch64 emulator",
+                Engine::Emulated(EmulatedProgram::compile_loop(&ops, 1).unwrap()),
+            )];
+            if Target::host().is_some() {
+                loops.push(("native", Engine::Native(jit_compile_loop(&ops, 1).unwrap())));
+            }
+            for (name, mut engine) in loops {
+                let mut memory: Memory = [0; MEM_SIZE];
+                memory[10] = 3;
+                let stats = engine.run_from(&mut memory, 10, io::empty(), io::sink());
+                assert_eq!(stats.unwrap().dp, 10, "{name}");
+                assert_eq!(memory[9..11], [3, 0], "{name}");
+
+                // no cell is known to be in bounds, and traps report ips in the whole program
+                memory[0] = 1;
+                let error = engine.run_from(&mut memory, 0, io::empty(), io::sink());
+                assert_eq!(
+                    error.unwrap_err().to_string(),
+                    "RUNTIME ERROR: data pointer is negative [IP:2]",
+                    "{name}"
+                );
+            }
+        }
+
+        #[test]
+        fn should_run_a_whole_program_only_from_the_start() {
+            if Target::host().is_none() {
+                return;
+            }
+            // `>+` is compiled without a check, as it stays on the tape from 0
+            let mut program = jit_compile_ops(&generate_ops(">+")).unwrap();
+            let mut memory: Memory = [0; MEM_SIZE];
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                program.run_from(&mut memory, MEM_SIZE - 1, io::empty(), io::sink())
+            }));
+            assert!(result.is_err());
+            assert_eq!(memory, [0; MEM_SIZE]);
+        }
+    }
 }
@@ -1,146 +1,587 @@
 mod codegen;
+pub mod disasm;
+#[cfg(test)]
+pub mod emulator;
+pub(crate) mod runtime;
 
 use std::mem::size_of;
 
-use crate::ir::*;
-use crate::jitc::JitCompileError;
+use crate::jitc::{Backend, OUTPUT_BUFFER_SIZE};
 use crate::op::*;
-use crate::{BackPatchingStack, Memory};
-use memmap2::{Mmap, MmapMut};
+use crate::MEM_SIZE;
+use codegen::*;
 
 const AARCH64_INST_SIZE: usize = 4;
 
-pub fn jit_compile(input: &str, memory: &mut Memory) -> Result<Mmap, JitCompileError> {
-    const SZ: usize = AARCH64_INST_SIZE;
-    let ops = generate_ops(input);
-    let mut raw_code = Vec::new();
-    let mut backpatches = BackPatchingStack::new();
-
-    /* A dedicated register for data pointer: x19, which is callee-saved */
-    // movz x19, #operand[..16], lsl #0
-    // movk x19, #operand[16..32], lsl #16
-    // movk x19, #operand[32..48], lsl #32
-    // movk x19, #operand[48..64], lsl #48
-    raw_code.extend_from_slice(&codegen::mov_x19_u64operand(memory.as_mut_ptr() as u64));
-
-    for (idx, op) in ops.into_iter().enumerate() {
-        let Op { kind, operand } = op;
-        match kind {
-            OpKind::Inc => {
-                // mov w8, #operand[..16]
-                // movk w8, #operand[16..], lsl #16
-                raw_code.extend_from_slice(&codegen::mov_x8_i32operand(operand)); // now operand is in x8
-
-                // ldr w9, [x19]
-                raw_code.extend_from_slice(&codegen::ldr_w9_addrx19());
-                // manually add nop because of load-use data hazard
-                // this can be resolved through hardware, but it's uncertain whether older CPUs exist that cannot handle this problem.
-                raw_code.extend_from_slice(&codegen::nop());
-                // add w9, w9, w8
-                raw_code.extend_from_slice(&codegen::add_w9_w9_w8());
-                // str w9, [x19]
-                raw_code.extend_from_slice(&codegen::str_w9_addrx19());
-            }
-            OpKind::Dec => {
-                // mov w8, #operand[..16]
-                // movk w8, #operand[16..], lsl #16
-                raw_code.extend_from_slice(&codegen::mov_x8_i32operand(operand)); // now operand is in x8
-
-                // ldr w9, [x19]
-                raw_code.extend_from_slice(&codegen::ldr_w9_addrx19());
-                // manually add nop because of load-use data hazard
-                // this can be resolved through hardware, but it's uncertain whether older CPUs exist that cannot handle this problem.
-                raw_code.extend_from_slice(&codegen::nop());
-                // sub w9, w9, w8
-                raw_code.extend_from_slice(&codegen::sub_w9_w9_w8());
-                // str w9, [x19]
-                raw_code.extend_from_slice(&codegen::str_w9_addrx19());
-            }
-            OpKind::Left => {
-                // mov x8, #operand[..16]
-                // movk x8, #operand[16..], lsl #16
-                // FIX: check memory boundary
-                raw_code.extend_from_slice(&codegen::mov_x8_i32operand(
-                    operand * size_of::<Operand>() as i32,
-                )); // now operand is in x8
-
-                // sub x19, x19, x8
-                raw_code.extend_from_slice(&codegen::sub_x19_x19_x8());
-            }
-            OpKind::Right => {
-                // mov x8, #operand[..16]
-                // movk x8, #operand[16..], lsl #16
-                // FIX: check memory boundary
-                raw_code.extend_from_slice(&codegen::mov_x8_i32operand(
-                    operand * size_of::<Operand>() as i32,
-                )); // now operand is in x8
-
-                // add x19, x19, x8
-                raw_code.extend_from_slice(&codegen::add_x19_x19_x8());
-            }
-            OpKind::Input => {
-                for _ in 0..operand {
-                    raw_code.extend_from_slice(&codegen::syscall_read());
-                }
+const LOAD_CELL: Inst = Inst::LdrW {
+    rt: W9,
+    rn: X19,
+    offset: 0,
+};
+const STORE_CELL: Inst = Inst::StrW {
+    rt: W9,
+    rn: X19,
+    offset: 0,
+};
+
+// what w9 holds: nothing, the current cell, or the current cell that is not stored yet
+#[derive(Default, Clone, Copy, PartialEq)]
+enum Cell {
+    #[default]
+    Unknown,
+    Clean,
+    Dirty,
+}
+
+#[derive(Default)]
+pub struct Aarch64 {
+    asm: Assembler,
+    traps: Vec<(Label, u64)>, // trap stub, and the status it returns
+    cell: Cell,
+}
+
+impl Aarch64 {
+    // ldr w9, [x19]
+    fn load_cell(&mut self) {
+        if self.cell == Cell::Unknown {
+            self.asm.emit(LOAD_CELL);
+            self.cell = Cell::Clean;
+        }
+    }
+
+    // str w9, [x19]
+    // before the pointer moves, memory is handed to the host, or control flow joins
+    fn store_cell(&mut self) {
+        if self.cell == Cell::Dirty {
+            self.asm.emit(STORE_CELL);
+            self.cell = Cell::Clean;
+        }
+    }
+
+    // b.cond and cbz only reach +-1MiB, so they skip over this unconditional branch to the trap
+    // stub, which is emitted in the epilogue
+    fn branch_to_trap(&mut self, status: u64) {
+        let stub = self.asm.new_label();
+        self.traps.push((stub, status));
+        self.asm.emit_branch(Inst::B { offset: 0 }, stub);
+    }
+
+    // cbz w0, ok
+    // b trap
+    // ok:
+    fn check_callback(&mut self, io_error: u64) {
+        let ok = self.asm.new_label();
+        self.asm.emit_branch(Inst::Cbz { rt: W0, offset: 0 }, ok);
+        self.branch_to_trap(io_error);
+        self.asm.bind(ok);
+    }
+
+    // mov x8, #operand[..16]
+    // movk x8, #operand[16..], lsl #16
+    fn mov_x8(&mut self, operand: u32) {
+        self.asm.mov_imm(X8, operand as u64);
+    }
+}
+
+impl Backend for Aarch64 {
+    type Label = (Label, Label); // the test at the tail and the body
+
+    fn prologue(&mut self) {
+        /* AAPCS64: the tape comes in x0, the run context in x1, and the cell to start at in x2 */
+        /* A dedicated register for data pointer: x19, and the run context: x20, which are callee-saved */
+        // stp x29, x30, [sp, #-48]!
+        // mov x29, sp
+        // stp x19, x20, [sp, #16]
+        // stp x21, x22, [sp, #32]
+        // add x19, x0, x2, lsl #2
+        // mov x20, x1
+        let (rn, index) = (SP, Index::Offset);
+        self.asm.emit(Inst::Stp {
+            rt: X29,
+            rt2: X30,
+            rn,
+            offset: -48,
+            index: Index::Pre,
+        });
+        self.asm.emit(Inst::AddImmX {
+            rd: X29,
+            rn: SP,
+            imm12: 0,
+            lsl12: false,
+        });
+        self.asm.emit(Inst::Stp {
+            rt: X19,
+            rt2: X20,
+            rn,
+            offset: 16,
+            index,
+        });
+        self.asm.emit(Inst::Stp {
+            rt: X21,
+            rt2: X22,
+            rn,
+            offset: 32,
+            index,
+        });
+        self.asm.emit(Inst::AddLslX {
+            rd: X19,
+            rn: X0,
+            rm: X2,
+            shift: 2,
+        });
+        self.asm.emit(Inst::Mov { rd: X20, rm: X1 });
+
+        /* The memory boundary: [x21, x22), which are callee-saved so that they survive I/O calls */
+        // mov x21, x0
+        // add x22, x0, #(MEM_SIZE * 4 >> 12), lsl #12
+        let bytes = MEM_SIZE * size_of::<Operand>();
+        let (imm12, lsl12) = split_immd12(bytes as u32).expect("the memory size is 4KiB-aligned");
+        self.asm.emit(Inst::Mov { rd: X21, rm: X0 });
+        self.asm.emit(Inst::AddImmX {
+            rd: X22,
+            rn: X0,
+            imm12,
+            lsl12,
+        });
+    }
+
+    fn epilogue(&mut self) {
+        self.store_cell();
+
+        /* The status is returned in x0 and the cell index of the data pointer in x1 */
+        // mov x0, #0
+        // exit:
+        // sub x1, x19, x21
+        // lsr x1, x1, #2
+        // ldp x21, x22, [sp, #32]
+        // ldp x19, x20, [sp, #16]
+        // ldp x29, x30, [sp], #48
+        // ret
+        self.asm.emit(Inst::Movz {
+            rd: X0,
+            imm16: 0,
+            lsl: 0,
+        });
+        let exit = self.asm.new_label();
+        self.asm.bind(exit);
+        self.asm.emit(Inst::SubX {
+            rd: X1,
+            rn: X19,
+            rm: X21,
+        });
+        self.asm.emit(Inst::LsrX {
+            rd: X1,
+            rn: X1,
+            shift: 2,
+        });
+        let (rn, index) = (SP, Index::Offset);
+        self.asm.emit(Inst::Ldp {
+            rt: X21,
+            rt2: X22,
+            rn,
+            offset: 32,
+            index,
+        });
+        self.asm.emit(Inst::Ldp {
+            rt: X19,
+            rt2: X20,
+            rn,
+            offset: 16,
+            index,
+        });
+        self.asm.emit(Inst::Ldp {
+            rt: X29,
+            rt2: X30,
+            rn,
+            offset: 48,
+            index: Index::Post,
+        });
+        self.asm.emit(Inst::Ret);
+
+        // trap stubs, one per bounds check and I/O call:
+        // mov x0, #status
+        // b exit
+        for (stub, status) in std::mem::take(&mut self.traps) {
+            self.asm.bind(stub);
+            self.asm.mov_imm(X0, status);
+            self.asm.emit_branch(Inst::B { offset: 0 }, exit);
+        }
+    }
+
+    fn add_to_cell(&mut self, amount: Operand) {
+        self.load_cell();
+
+        if let Some((imm12, lsl12)) = split_immd12(amount.unsigned_abs()) {
+            if amount >= 0 {
+                // add w9, w9, #operand
+                self.asm.emit(Inst::AddImmW {
+                    rd: W9,
+                    rn: W9,
+                    imm12,
+                    lsl12,
+                });
+            } else {
+                // sub w9, w9, #operand
+                self.asm.emit(Inst::SubImmW {
+                    rd: W9,
+                    rn: W9,
+                    imm12,
+                    lsl12,
+                });
             }
-            OpKind::Output => {
-                // TODO: use buffer for optimization
-                for _ in 0..operand {
-                    raw_code.extend_from_slice(&codegen::syscall_write());
+            self.cell = Cell::Dirty; // stored lazily
+            return;
+        }
+
+        self.mov_x8(amount.unsigned_abs()); // now operand is in x8
+
+        if amount >= 0 {
+            // add w9, w9, w8
+            self.asm.emit(Inst::AddW {
+                rd: W9,
+                rn: W9,
+                rm: W8,
+            });
+        } else {
+            // sub w9, w9, w8
+            self.asm.emit(Inst::SubW {
+                rd: W9,
+                rn: W9,
+                rm: W8,
+            });
+        }
+        self.cell = Cell::Dirty; // stored lazily
+    }
+
+    fn move_pointer(&mut self, amount: Operand, trap: Option<u64>) {
+        self.store_cell();
+        self.cell = Cell::Unknown;
+
+        let bytes = amount
+            .unsigned_abs()
+            .checked_mul(size_of::<Operand>() as u32)
+            .expect("moves are less than the memory size");
+        match (split_immd12(bytes), amount >= 0) {
+            // add x19, x19, #operand
+            (Some((imm12, lsl12)), true) => self.asm.emit(Inst::AddImmX {
+                rd: X19,
+                rn: X19,
+                imm12,
+                lsl12,
+            }),
+            // sub x19, x19, #operand
+            (Some((imm12, lsl12)), false) => self.asm.emit(Inst::SubImmX {
+                rd: X19,
+                rn: X19,
+                imm12,
+                lsl12,
+            }),
+            (None, positive) => {
+                self.mov_x8(bytes); // now operand is in x8
+
+                if positive {
+                    // add x19, x19, x8
+                    self.asm.emit(Inst::AddX {
+                        rd: X19,
+                        rn: X19,
+                        rm: X8,
+                    });
+                } else {
+                    // sub x19, x19, x8
+                    self.asm.emit(Inst::SubX {
+                        rd: X19,
+                        rn: X19,
+                        rm: X8,
+                    });
                 }
             }
-            OpKind::Jeq0Forward => {
-                backpatches.push(raw_code.len() as i32);
+        }
 
-                let placeholder: codegen::CondNearBranch = [0; SZ * 3];
-                raw_code.extend_from_slice(&placeholder);
-            }
-            OpKind::Jne0Backward => {
-                let Some(matching_byte_addr) = backpatches.pop() else {
-                    return Err(JitCompileError::with_ip(
-                        idx,
-                        &format!(
-                            "invalid program: `[` and `]` should match (`]` exceeds) [IDX:{idx}]"
-                        ),
-                    ));
-                };
-
-                // TODO: extend jump boundary (currently +-2^20 bytes = +-2^18 instructions)
-                let curr_byte_addr = raw_code.len() as i32;
-                let addr = matching_byte_addr as usize;
-                let matching_inst =
-                    &mut raw_code[addr..addr + size_of::<codegen::CondNearBranch>()];
-
-                let base_amount = curr_byte_addr - matching_byte_addr;
-
-                // TODO: check jump boundary
-                let jez_amount = (base_amount >> 2) + 1; // equivalent to `base_amount / SZ + 1`, but faster
-                let jnz_amount = -((base_amount >> 2) + 1);
-                matching_inst.copy_from_slice(&codegen::cbz_x9_addrx19_immd19(jez_amount));
-                raw_code.extend_from_slice(&codegen::cbnz_x9_addrx19_immd19(jnz_amount));
-            }
+        let Some(status) = trap else {
+            return;
+        };
+        let ok = self.asm.new_label();
+        if amount >= 0 {
+            // cmp x19, x22
+            // b.lo ok
+            self.asm.emit(Inst::CmpX { rn: X19, rm: X22 });
+            self.asm.emit_branch(
+                Inst::BCond {
+                    cond: Cond::Lo,
+                    offset: 0,
+                },
+                ok,
+            );
+        } else {
+            // cmp x19, x21
+            // b.hs ok
+            self.asm.emit(Inst::CmpX { rn: X19, rm: X21 });
+            self.asm.emit_branch(
+                Inst::BCond {
+                    cond: Cond::Hs,
+                    offset: 0,
+                },
+                ok,
+            );
         }
+        // b trap
+        // ok:
+        self.branch_to_trap(status);
+        self.asm.bind(ok);
     }
 
-    if !backpatches.is_empty() {
-        let length = backpatches.len();
-        for backpatch in backpatches {
-            eprintln!(
-                "{}",
-                JitCompileError::with_ip(
-                    backpatch as usize,
-                    "invalid program: `[` and `]` should match"
-                )
-            );
+    fn trap(&mut self, status: u64) {
+        // str w9, [x19]
+        // b trap
+        self.store_cell();
+        self.branch_to_trap(status);
+    }
+
+    fn input(&mut self, io_error: u64) {
+        self.store_cell();
+        self.cell = Cell::Unknown; // written by the host
+
+        // mov x0, x20
+        // mov x1, x19
+        // ldr x8, [x20]    /* read, which flushes the output buffer first */
+        // blr x8
+        self.asm.emit(Inst::Mov { rd: X0, rm: X20 });
+        self.asm.emit(Inst::Mov { rd: X1, rm: X19 });
+        self.asm.emit(Inst::LdrX {
+            rt: X8,
+            rn: X20,
+            offset: 0,
+        });
+        self.asm.emit(Inst::Blr { rn: X8 });
+        self.check_callback(io_error);
+    }
+
+    fn output(&mut self, not_ascii: u64, io_error: u64) {
+        // the host reads the cell when it traps, and w9 does not survive the flush callback
+        self.load_cell();
+        self.store_cell();
+        self.cell = Cell::Unknown;
+
+        // cmp w9, #127
+        // b.ls ascii
+        // b trap
+        // ascii:
+        let ascii = self.asm.new_label();
+        self.asm.emit(Inst::CmpImmW { rn: W9, imm12: 127 });
+        self.asm.emit_branch(
+            Inst::BCond {
+                cond: Cond::Ls,
+                offset: 0,
+            },
+            ascii,
+        );
+        self.branch_to_trap(not_ascii);
+        self.asm.bind(ascii);
+
+        // ldp x10, x11, [x20, #16]    /* the output buffer and its length */
+        // strb w9, [x10, x11]
+        // add x11, x11, #1
+        // str x11, [x20, #24]
+        // cmp x11, #OUTPUT_BUFFER_SIZE
+        // b.lo done
+        let (imm12, lsl12) =
+            split_immd12(OUTPUT_BUFFER_SIZE as u32).expect("the buffer size is an immediate");
+        let index = Index::Offset;
+        self.asm.emit(Inst::Ldp {
+            rt: X10,
+            rt2: X11,
+            rn: X20,
+            offset: 16,
+            index,
+        });
+        self.asm.emit(Inst::Strb {
+            rt: W9,
+            rn: X10,
+            rm: X11,
+        });
+        self.asm.emit(Inst::AddImmX {
+            rd: X11,
+            rn: X11,
+            imm12: 1,
+            lsl12: false,
+        });
+        self.asm.emit(Inst::StrX {
+            rt: X11,
+            rn: X20,
+            offset: 24,
+        });
+        self.asm.emit(Inst::CmpImmX {
+            rn: X11,
+            imm12,
+            lsl12,
+        });
+        let done = self.asm.new_label();
+        self.asm.emit_branch(
+            Inst::BCond {
+                cond: Cond::Lo,
+                offset: 0,
+            },
+            done,
+        );
+
+        // mov x0, x20
+        // ldr x8, [x20, #8]    /* flush */
+        // blr x8
+        // cbz w0, done
+        // b trap
+        // done:
+        self.asm.emit(Inst::Mov { rd: X0, rm: X20 });
+        self.asm.emit(Inst::LdrX {
+            rt: X8,
+            rn: X20,
+            offset: 8,
+        });
+        self.asm.emit(Inst::Blr { rn: X8 });
+        self.check_callback(io_error);
+        self.asm.bind(done);
+    }
+
+    // Loops are rotated, so that each iteration takes a single test at the tail:
+    //
+    //       b test
+    //   body:
+    //       ...
+    //   test:
+    //       ldr w9, [x19]
+    //       cbnz w9, body
+    //
+    // The body and what follows the loop are only reached from the test, with the cell in w9.
+    fn loop_head(&mut self) -> (Label, Label) {
+        self.store_cell();
+
+        // b test
+        // body:
+        let (test, body) = (self.asm.new_label(), self.asm.new_label());
+        self.asm.emit_branch(Inst::B { offset: 0 }, test);
+        self.asm.bind(body);
+        self.cell = Cell::Clean;
+        (test, body)
+    }
+
+    fn loop_tail(&mut self, (test, body): (Label, Label)) -> Result<(), &'static str> {
+        self.store_cell();
+
+        // test:
+        // ldr w9, [x19]
+        self.asm.bind(test);
+        self.asm.emit(LOAD_CELL);
+        let to_body = self
+            .asm
+            .offset_to(body)
+            .expect("the body is bound at the head");
+        if fits_in(to_body, 19) {
+            // cbnz w9, body    /* cbnz reaches +-2^18 instructions */
+            self.asm.emit_branch(Inst::Cbnz { rt: W9, offset: 0 }, body);
+        } else {
+            // cbz w9, done     /* b reaches +-2^25 instructions */
+            // b body
+            // done:
+            let done = self.asm.new_label();
+            self.asm.emit_branch(Inst::Cbz { rt: W9, offset: 0 }, done);
+            self.asm.emit_branch(Inst::B { offset: 0 }, body);
+            self.asm.bind(done);
         }
-        return Err(JitCompileError::new(&format!("({length} `[`s left)",)));
+        self.cell = Cell::Clean;
+        Ok(())
     }
-    // ret
-    raw_code.extend_from_slice(&codegen::ret());
 
-    let mut mmap = MmapMut::map_anon(raw_code.len())?;
-    mmap.copy_from_slice(&raw_code);
-    let mmap = mmap.make_exec()?;
+    fn code_len(&self) -> usize {
+        self.asm.len()
+    }
 
-    Ok(mmap)
+    fn finish(self) -> Result<Vec<u8>, &'static str> {
+        self.asm.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inst(code: &[u8], byte_addr: usize) -> u32 {
+        u32::from_le_bytes(
+            code[byte_addr..byte_addr + AARCH64_INST_SIZE]
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    // the loop head and test, and the whole program with `body` Inc ops in the loop
+    fn compile_loop(body: usize) -> (usize, usize, Vec<u8>) {
+        let mut backend = Aarch64::default();
+        backend.prologue();
+        let head = backend.asm.len();
+        let labels = backend.loop_head();
+        for _ in 0..body {
+            backend.add_to_cell(1);
+            backend.add_to_cell(-1);
+        }
+        let test = backend.asm.len() + AARCH64_INST_SIZE; // after storing the cell
+        backend.loop_tail(labels).unwrap();
+        backend.epilogue();
+        (head, test, backend.finish().unwrap())
+    }
+
+    // target of the branch at `byte_addr`, which is `b`, `cbz` or `cbnz`
+    fn branch_target(code: &[u8], byte_addr: usize) -> usize {
+        let inst = inst(code, byte_addr);
+        let amount = if inst >> 26 == 0b000101 {
+            (inst << 6) as i32 >> 6
+        } else {
+            (inst << 8) as i32 >> 13
+        };
+        (byte_addr as isize + amount as isize * AARCH64_INST_SIZE as isize) as usize
+    }
+
+    #[test]
+    fn should_keep_the_cell_in_a_register() {
+        let mut backend = Aarch64::default();
+        for amount in [3, -1, 2] {
+            backend.add_to_cell(amount);
+        }
+        backend.move_pointer(1, None);
+        let code = backend.finish().unwrap();
+
+        let loads = (0..code.len()).step_by(AARCH64_INST_SIZE).filter(|&addr| {
+            code[addr..addr + AARCH64_INST_SIZE] == LOAD_CELL.encode().to_le_bytes()
+        });
+        let stores = (0..code.len()).step_by(AARCH64_INST_SIZE).filter(|&addr| {
+            code[addr..addr + AARCH64_INST_SIZE] == STORE_CELL.encode().to_le_bytes()
+        });
+        assert_eq!(loads.count(), 1);
+        assert_eq!(stores.count(), 1);
+    }
+
+    #[test]
+    fn should_use_near_branches_for_small_loops() {
+        let (head, test, code) = compile_loop(10);
+
+        assert_eq!(inst(&code, head) >> 26, 0b000101); // b
+        assert_eq!(branch_target(&code, head), test);
+        assert_eq!(
+            code[test..test + AARCH64_INST_SIZE],
+            LOAD_CELL.encode().to_le_bytes()
+        );
+        assert_eq!(inst(&code, test + 4) >> 24, 0x35); // cbnz
+        assert_eq!(branch_target(&code, test + 4), head + 4);
+    }
+
+    #[test]
+    fn should_use_far_branches_for_huge_loops() {
+        // every pair of Inc and Dec takes 2 instructions, so this is beyond the +-1MiB of cbnz
+        let body = (1 << 20) / (2 * AARCH64_INST_SIZE) + 1;
+        let (head, test, code) = compile_loop(body);
+
+        assert_eq!(branch_target(&code, head), test);
+        // cbz over `b`, which jumps back to the body
+        assert_eq!(inst(&code, test + 4) >> 24, 0x34);
+        assert_eq!(branch_target(&code, test + 4), test + 12);
+        assert_eq!(inst(&code, test + 8) >> 26, 0b000101);
+        assert_eq!(branch_target(&code, test + 8), head + 4);
+    }
 }
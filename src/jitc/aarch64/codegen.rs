@@ -1,175 +1,1105 @@
 use super::AARCH64_INST_SIZE;
 
-pub fn nop() -> [u8; AARCH64_INST_SIZE] {
-    [0x1f, 0x20, 0x03, 0xd5]
+// General-purpose registers, as 64-bit `X` or 32-bit `W` views. Register 31 is the stack pointer
+// or the zero register, depending on the instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct X(pub u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct W(pub u8);
+
+pub const X0: X = X(0);
+pub const X1: X = X(1);
+pub const X2: X = X(2);
+pub const X8: X = X(8);
+pub const X9: X = X(9);
+pub const X10: X = X(10);
+pub const X11: X = X(11);
+pub const X19: X = X(19);
+pub const X20: X = X(20);
+pub const X21: X = X(21);
+pub const X22: X = X(22);
+pub const X29: X = X(29);
+pub const X30: X = X(30);
+pub const SP: X = X(31);
+pub const XZR: X = X(31);
+pub const W0: W = W(0);
+pub const W2: W = W(2);
+pub const W8: W = W(8);
+pub const W9: W = W(9);
+pub const W10: W = W(10);
+pub const W11: W = W(11);
+pub const W19: W = W(19);
+pub const WZR: W = W(31);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond {
+    Eq = 0b0000,
+    Ne = 0b0001,
+    Hs = 0b0010,
+    Lo = 0b0011,
+    Hi = 0b1000,
+    Ls = 0b1001,
 }
 
-pub fn ret() -> [u8; AARCH64_INST_SIZE] {
-    [0xc0, 0x03, 0x5f, 0xd6]
+// addressing modes of `stp` and `ldp`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Index {
+    Offset, // [rn, #offset]
+    Pre,    // [rn, #offset]!
+    Post,   // [rn], #offset
 }
 
-pub fn sub_x19_x19_x8() -> [u8; AARCH64_INST_SIZE] {
-    [0x73, 0x02, 0x08, 0xcb]
+// The subset of A64 that the backend emits. Memory offsets are in bytes as in the assembly
+// syntax, and branch offsets are in instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Inst {
+    Ret,
+    Movz {
+        rd: X,
+        imm16: u16,
+        lsl: u8,
+    },
+    Movk {
+        rd: X,
+        imm16: u16,
+        lsl: u8,
+    },
+    Mov {
+        rd: X,
+        rm: X,
+    }, // orr rd, xzr, rm
+    AddImmW {
+        rd: W,
+        rn: W,
+        imm12: u16,
+        lsl12: bool,
+    },
+    SubImmW {
+        rd: W,
+        rn: W,
+        imm12: u16,
+        lsl12: bool,
+    },
+    AddImmX {
+        rd: X,
+        rn: X,
+        imm12: u16,
+        lsl12: bool,
+    },
+    SubImmX {
+        rd: X,
+        rn: X,
+        imm12: u16,
+        lsl12: bool,
+    },
+    CmpImmW {
+        rn: W,
+        imm12: u16,
+    },
+    CmpImmX {
+        rn: X,
+        imm12: u16,
+        lsl12: bool,
+    },
+    AddW {
+        rd: W,
+        rn: W,
+        rm: W,
+    },
+    SubW {
+        rd: W,
+        rn: W,
+        rm: W,
+    },
+    AddX {
+        rd: X,
+        rn: X,
+        rm: X,
+    },
+    // add rd, rn, rm, lsl #shift
+    AddLslX {
+        rd: X,
+        rn: X,
+        rm: X,
+        shift: u8,
+    },
+    SubX {
+        rd: X,
+        rn: X,
+        rm: X,
+    },
+    CmpX {
+        rn: X,
+        rm: X,
+    },
+    LsrX {
+        rd: X,
+        rn: X,
+        shift: u8,
+    },
+    UxtbW {
+        rd: W,
+        rn: W,
+    },
+    LdrW {
+        rt: W,
+        rn: X,
+        offset: u16,
+    },
+    StrW {
+        rt: W,
+        rn: X,
+        offset: u16,
+    },
+    LdrX {
+        rt: X,
+        rn: X,
+        offset: u16,
+    },
+    StrX {
+        rt: X,
+        rn: X,
+        offset: u16,
+    },
+    Strb {
+        rt: W,
+        rn: X,
+        rm: X,
+    }, // strb rt, [rn, rm]
+    Stp {
+        rt: X,
+        rt2: X,
+        rn: X,
+        offset: i16,
+        index: Index,
+    },
+    Ldp {
+        rt: X,
+        rt2: X,
+        rn: X,
+        offset: i16,
+        index: Index,
+    },
+    Blr {
+        rn: X,
+    },
+    Svc {
+        imm16: u16,
+    },
+    B {
+        offset: i32,
+    },
+    Bl {
+        offset: i32,
+    },
+    BCond {
+        cond: Cond,
+        offset: i32,
+    },
+    Cbz {
+        rt: W,
+        offset: i32,
+    },
+    Cbnz {
+        rt: W,
+        offset: i32,
+    },
 }
 
-pub fn add_x19_x19_x8() -> [u8; AARCH64_INST_SIZE] {
-    [0x73, 0x02, 0x08, 0x8b]
+fn reg(r: u8) -> u32 {
+    assert!(r < 32);
+    r as u32
 }
 
-pub fn sub_w9_w9_w8() -> [u8; AARCH64_INST_SIZE] {
-    [0x29, 0x01, 0x08, 0x4b]
+fn add_sub_imm(base: u32, rd: u8, rn: u8, imm12: u16, lsl12: bool) -> u32 {
+    assert!(imm12 < 1 << 12);
+
+    // N-filled bits are a placeholder for imm12, and S for the shift
+    // sf0100010SNN NNNNNNNN NNnnnnnd dddd
+    base | ((lsl12 as u32) << 22) | ((imm12 as u32) << 10) | (reg(rn) << 5) | reg(rd)
 }
 
-pub fn str_w9_addrx19() -> [u8; AARCH64_INST_SIZE] {
-    [0x69, 0x02, 0x00, 0xb9]
+fn three_regs(base: u32, rd: u8, rn: u8, rm: u8) -> u32 {
+    base | (reg(rm) << 16) | (reg(rn) << 5) | reg(rd)
 }
 
-pub fn add_w9_w9_w8() -> [u8; AARCH64_INST_SIZE] {
-    [0x29, 0x01, 0x08, 0x0b]
+fn move_wide(base: u32, rd: u8, imm16: u16, lsl: u8) -> u32 {
+    assert!(lsl.is_multiple_of(16) && lsl < 64);
+    base | (((lsl / 16) as u32) << 21) | ((imm16 as u32) << 5) | reg(rd)
 }
 
-pub fn ldr_w9_addrx19() -> [u8; AARCH64_INST_SIZE] {
-    [0x69, 0x02, 0x40, 0xb9]
+fn load_store(base: u32, rt: u8, rn: u8, offset: u16, scale: u16) -> u32 {
+    assert!(offset.is_multiple_of(scale) && offset / scale < 1 << 12);
+    base | (((offset / scale) as u32) << 10) | (reg(rn) << 5) | reg(rt)
 }
 
-fn movk_xn_immd16(xn: u8, immd16: u16, lsl: u8) -> [u8; AARCH64_INST_SIZE] {
-    assert!(xn < 32);
-    assert!(lsl == 0 || lsl == 16 || lsl == 32 || lsl == 48);
-    let lsl = match lsl {
-        0 => 0,
-        16 => 1,
-        32 => 2,
-        48 => 3,
-        _ => panic!("invalid lsl field in movk"),
+fn pair(base: u32, rt: u8, rt2: u8, rn: u8, offset: i16, index: Index) -> u32 {
+    assert!(offset % 8 == 0 && (-64..64).contains(&(offset / 8)));
+    let index = match index {
+        Index::Post => 0b01,
+        Index::Offset => 0b10,
+        Index::Pre => 0b11,
     };
-    // N-filled bits are a placeholder for immd16
-    // 11110010 100NNNNN NNNNNNNN NNN01000
-    let base = 0xf2800000; // big endian version of `movk xn, #0x0000, lsl #0`
-    let instruction = base | xn as u32 | ((immd16 as u32) << 5) | ((lsl as u32) << 16 + 5);
-    instruction.to_le_bytes() // reverse byte order to ensure that it's little endian (because it's aarch64!)
+    let imm7 = ((offset / 8) as u32) & 0x7f;
+    base | (index << 23) | (imm7 << 15) | (reg(rt2) << 10) | (reg(rn) << 5) | reg(rt)
 }
 
-fn mov_xn_immd16(xn: u8, immd16: u16) -> [u8; AARCH64_INST_SIZE] {
-    assert!(xn < 32);
-
-    // N-filled bits are a placeholder for immd16
-    // 11010010 100NNNNN NNNNNNNN NNN01000
-    let base = 0xd2800000; // big endian version of `mov xn, #0x0000`
-    let instruction = base | xn as u32 | ((immd16 as u32) << 5);
-    instruction.to_le_bytes() // reverse byte order to ensure that it's little endian (because it's aarch64!)
+fn imm19(offset: i32) -> u32 {
+    ((offset as u32) & 0x0007ffff) << 5
 }
 
-pub fn mov_x8_i32operand(operand: i32) -> [u8; AARCH64_INST_SIZE * 2] {
-    const SZ: usize = AARCH64_INST_SIZE;
+impl Inst {
+    // the width of the offset, if this is a branch
+    pub fn offset_bits(&self) -> Option<u32> {
+        match self {
+            Inst::B { .. } | Inst::Bl { .. } => Some(26),
+            Inst::BCond { .. } | Inst::Cbz { .. } | Inst::Cbnz { .. } => Some(19),
+            _ => None,
+        }
+    }
 
-    let immd0 = (operand & 0x0000ffff) as u16;
-    let immd1 = ((operand >> 16) & 0xffff) as u16;
+    pub fn with_offset(self, offset: i32) -> Inst {
+        match self {
+            Inst::B { .. } => Inst::B { offset },
+            Inst::Bl { .. } => Inst::Bl { offset },
+            Inst::BCond { cond, .. } => Inst::BCond { cond, offset },
+            Inst::Cbz { rt, .. } => Inst::Cbz { rt, offset },
+            Inst::Cbnz { rt, .. } => Inst::Cbnz { rt, offset },
+            _ => panic!("{self:?} is not a branch"),
+        }
+    }
 
-    let mov_inst: [u8; AARCH64_INST_SIZE] = mov_xn_immd16(8, immd0);
-    let movk_inst: [u8; AARCH64_INST_SIZE] = movk_xn_immd16(8, immd1, 16);
+    // the instruction word as the manual writes it, which is stored little-endian
+    pub fn encode(&self) -> u32 {
+        match *self {
+            Inst::Ret => 0xd65f03c0,
+            Inst::Movz { rd, imm16, lsl } => move_wide(0xd2800000, rd.0, imm16, lsl),
+            Inst::Movk { rd, imm16, lsl } => move_wide(0xf2800000, rd.0, imm16, lsl),
+            Inst::Mov { rd, rm } => three_regs(0xaa0003e0, rd.0, 0, rm.0),
+            Inst::AddImmW {
+                rd,
+                rn,
+                imm12,
+                lsl12,
+            } => add_sub_imm(0x11000000, rd.0, rn.0, imm12, lsl12),
+            Inst::SubImmW {
+                rd,
+                rn,
+                imm12,
+                lsl12,
+            } => add_sub_imm(0x51000000, rd.0, rn.0, imm12, lsl12),
+            Inst::AddImmX {
+                rd,
+                rn,
+                imm12,
+                lsl12,
+            } => add_sub_imm(0x91000000, rd.0, rn.0, imm12, lsl12),
+            Inst::SubImmX {
+                rd,
+                rn,
+                imm12,
+                lsl12,
+            } => add_sub_imm(0xd1000000, rd.0, rn.0, imm12, lsl12),
+            Inst::CmpImmW { rn, imm12 } => add_sub_imm(0x71000000, 31, rn.0, imm12, false),
+            Inst::CmpImmX { rn, imm12, lsl12 } => add_sub_imm(0xf1000000, 31, rn.0, imm12, lsl12),
+            Inst::AddW { rd, rn, rm } => three_regs(0x0b000000, rd.0, rn.0, rm.0),
+            Inst::SubW { rd, rn, rm } => three_regs(0x4b000000, rd.0, rn.0, rm.0),
+            Inst::AddX { rd, rn, rm } => three_regs(0x8b000000, rd.0, rn.0, rm.0),
+            Inst::AddLslX { rd, rn, rm, shift } => {
+                assert!(shift < 64);
+                three_regs(0x8b000000 | (shift as u32) << 10, rd.0, rn.0, rm.0)
+            }
+            Inst::SubX { rd, rn, rm } => three_regs(0xcb000000, rd.0, rn.0, rm.0),
+            Inst::CmpX { rn, rm } => three_regs(0xeb000000, 31, rn.0, rm.0),
+            Inst::LsrX { rd, rn, shift } => {
+                // ubfm rd, rn, #shift, #63
+                assert!(shift < 64);
+                0xd340fc00 | ((shift as u32) << 16) | (reg(rn.0) << 5) | reg(rd.0)
+            }
+            // ubfm rd, rn, #0, #7
+            Inst::UxtbW { rd, rn } => 0x53001c00 | (reg(rn.0) << 5) | reg(rd.0),
+            Inst::LdrW { rt, rn, offset } => load_store(0xb9400000, rt.0, rn.0, offset, 4),
+            Inst::StrW { rt, rn, offset } => load_store(0xb9000000, rt.0, rn.0, offset, 4),
+            Inst::LdrX { rt, rn, offset } => load_store(0xf9400000, rt.0, rn.0, offset, 8),
+            Inst::StrX { rt, rn, offset } => load_store(0xf9000000, rt.0, rn.0, offset, 8),
+            Inst::Strb { rt, rn, rm } => three_regs(0x38206800, rt.0, rn.0, rm.0),
+            Inst::Stp {
+                rt,
+                rt2,
+                rn,
+                offset,
+                index,
+            } => pair(0xa8000000, rt.0, rt2.0, rn.0, offset, index),
+            Inst::Ldp {
+                rt,
+                rt2,
+                rn,
+                offset,
+                index,
+            } => pair(0xa8400000, rt.0, rt2.0, rn.0, offset, index),
+            Inst::Blr { rn } => 0xd63f0000 | (reg(rn.0) << 5),
+            Inst::Svc { imm16 } => 0xd4000001 | ((imm16 as u32) << 5),
+            Inst::B { offset } => 0x14000000 | ((offset as u32) & 0x03ffffff),
+            Inst::Bl { offset } => 0x94000000 | ((offset as u32) & 0x03ffffff),
+            Inst::BCond { cond, offset } => 0x54000000 | imm19(offset) | cond as u32,
+            Inst::Cbz { rt, offset } => 0x34000000 | imm19(offset) | reg(rt.0),
+            Inst::Cbnz { rt, offset } => 0x35000000 | imm19(offset) | reg(rt.0),
+        }
+    }
+}
 
-    let mut result: [u8; SZ * 2] = [0; SZ * 2];
-    result[..SZ].copy_from_slice(&mov_inst);
-    result[SZ..SZ * 2].copy_from_slice(&movk_inst);
-    result
+fn sign_extend(value: u32, bits: u32) -> i32 {
+    ((value << (32 - bits)) as i32) >> (32 - bits)
 }
 
-pub fn mov_x19_u64operand(operand: u64) -> [u8; AARCH64_INST_SIZE * 4] {
-    const SZ: usize = AARCH64_INST_SIZE;
+impl Inst {
+    // the inverse of `encode`, for the emulator and the disassembler
+    pub fn decode(word: u32) -> Option<Inst> {
+        let rd = (word & 31) as u8;
+        let rn = ((word >> 5) & 31) as u8;
+        let rm = ((word >> 16) & 31) as u8;
+        let imm12 = ((word >> 10) & 0xfff) as u16;
+        let lsl12 = (word >> 22) & 1 == 1;
+        let imm16 = (word >> 5) as u16;
+        let lsl = ((word >> 21) & 3) as u8 * 16;
+        let imm19 = sign_extend((word >> 5) & 0x7ffff, 19);
 
-    let immd0 = (operand & 0xffff) as u16;
-    let immd1 = ((operand >> 16) & 0xffff) as u16;
-    let immd2 = ((operand >> 32) & 0xffff) as u16;
-    let immd3 = ((operand >> 48) & 0xffff) as u16;
+        let inst = match word {
+            0xd65f03c0 => Inst::Ret,
+            _ if word & 0xff800000 == 0xd2800000 => Inst::Movz {
+                rd: X(rd),
+                imm16,
+                lsl,
+            },
+            _ if word & 0xff800000 == 0xf2800000 => Inst::Movk {
+                rd: X(rd),
+                imm16,
+                lsl,
+            },
+            _ if word & 0xffe0ffe0 == 0xaa0003e0 => Inst::Mov {
+                rd: X(rd),
+                rm: X(rm),
+            },
+            _ if word & 0xff800000 == 0x11000000 => Inst::AddImmW {
+                rd: W(rd),
+                rn: W(rn),
+                imm12,
+                lsl12,
+            },
+            _ if word & 0xff800000 == 0x51000000 => Inst::SubImmW {
+                rd: W(rd),
+                rn: W(rn),
+                imm12,
+                lsl12,
+            },
+            _ if word & 0xff800000 == 0x91000000 => Inst::AddImmX {
+                rd: X(rd),
+                rn: X(rn),
+                imm12,
+                lsl12,
+            },
+            _ if word & 0xff800000 == 0xd1000000 => Inst::SubImmX {
+                rd: X(rd),
+                rn: X(rn),
+                imm12,
+                lsl12,
+            },
+            _ if word & 0xffc0001f == 0x7100001f => Inst::CmpImmW { rn: W(rn), imm12 },
+            _ if word & 0xff80001f == 0xf100001f => Inst::CmpImmX {
+                rn: X(rn),
+                imm12,
+                lsl12,
+            },
+            _ if word & 0xffe0fc00 == 0x0b000000 => Inst::AddW {
+                rd: W(rd),
+                rn: W(rn),
+                rm: W(rm),
+            },
+            _ if word & 0xffe0fc00 == 0x4b000000 => Inst::SubW {
+                rd: W(rd),
+                rn: W(rn),
+                rm: W(rm),
+            },
+            _ if word & 0xffe0fc00 == 0x8b000000 => Inst::AddX {
+                rd: X(rd),
+                rn: X(rn),
+                rm: X(rm),
+            },
+            _ if word & 0xffe00000 == 0x8b000000 => Inst::AddLslX {
+                rd: X(rd),
+                rn: X(rn),
+                rm: X(rm),
+                shift: ((word >> 10) & 63) as u8,
+            },
+            _ if word & 0xffe0fc00 == 0xcb000000 => Inst::SubX {
+                rd: X(rd),
+                rn: X(rn),
+                rm: X(rm),
+            },
+            _ if word & 0xffe0fc1f == 0xeb00001f => Inst::CmpX {
+                rn: X(rn),
+                rm: X(rm),
+            },
+            _ if word & 0xffc0fc00 == 0xd340fc00 => {
+                let shift = ((word >> 16) & 63) as u8;
+                Inst::LsrX {
+                    rd: X(rd),
+                    rn: X(rn),
+                    shift,
+                }
+            }
+            _ if word & 0xfffffc00 == 0x53001c00 => Inst::UxtbW {
+                rd: W(rd),
+                rn: W(rn),
+            },
+            _ if word & 0xffc00000 == 0xb9400000 => Inst::LdrW {
+                rt: W(rd),
+                rn: X(rn),
+                offset: imm12 * 4,
+            },
+            _ if word & 0xffc00000 == 0xb9000000 => Inst::StrW {
+                rt: W(rd),
+                rn: X(rn),
+                offset: imm12 * 4,
+            },
+            _ if word & 0xffc00000 == 0xf9400000 => Inst::LdrX {
+                rt: X(rd),
+                rn: X(rn),
+                offset: imm12 * 8,
+            },
+            _ if word & 0xffc00000 == 0xf9000000 => Inst::StrX {
+                rt: X(rd),
+                rn: X(rn),
+                offset: imm12 * 8,
+            },
+            _ if word & 0xffe0fc00 == 0x38206800 => Inst::Strb {
+                rt: W(rd),
+                rn: X(rn),
+                rm: X(rm),
+            },
+            _ if word & 0xfe000000 == 0xa8000000 => {
+                let index = match (word >> 23) & 3 {
+                    0b01 => Index::Post,
+                    0b10 => Index::Offset,
+                    0b11 => Index::Pre,
+                    _ => return None,
+                };
+                let (rt, rt2, rn) = (X(rd), X(((word >> 10) & 31) as u8), X(rn));
+                let offset = sign_extend((word >> 15) & 0x7f, 7) as i16 * 8;
+                if (word >> 22) & 1 == 1 {
+                    Inst::Ldp {
+                        rt,
+                        rt2,
+                        rn,
+                        offset,
+                        index,
+                    }
+                } else {
+                    Inst::Stp {
+                        rt,
+                        rt2,
+                        rn,
+                        offset,
+                        index,
+                    }
+                }
+            }
+            _ if word & 0xfffffc1f == 0xd63f0000 => Inst::Blr { rn: X(rn) },
+            _ if word & 0xffe0001f == 0xd4000001 => Inst::Svc { imm16 },
+            _ if word & 0xfc000000 == 0x14000000 => Inst::B {
+                offset: sign_extend(word & 0x03ffffff, 26),
+            },
+            _ if word & 0xfc000000 == 0x94000000 => Inst::Bl {
+                offset: sign_extend(word & 0x03ffffff, 26),
+            },
+            _ if word & 0xff000010 == 0x54000000 => {
+                let cond = match word & 15 {
+                    0b0000 => Cond::Eq,
+                    0b0001 => Cond::Ne,
+                    0b0010 => Cond::Hs,
+                    0b0011 => Cond::Lo,
+                    0b1000 => Cond::Hi,
+                    0b1001 => Cond::Ls,
+                    _ => return None,
+                };
+                Inst::BCond {
+                    cond,
+                    offset: imm19,
+                }
+            }
+            _ if word & 0xff000000 == 0x34000000 => Inst::Cbz {
+                rt: W(rd),
+                offset: imm19,
+            },
+            _ if word & 0xff000000 == 0x35000000 => Inst::Cbnz {
+                rt: W(rd),
+                offset: imm19,
+            },
+            _ => return None,
+        };
+        Some(inst)
+    }
+}
 
-    let mov_inst0 = mov_xn_immd16(19, immd0);
-    let movk_inst1 = movk_xn_immd16(19, immd1, 16);
-    let movk_inst2 = movk_xn_immd16(19, immd2, 32);
-    let movk_inst3 = movk_xn_immd16(19, immd3, 48);
+// `value` as an unsigned 12-bit immediate, which may be shifted left by 12
+pub fn split_immd12(value: u32) -> Option<(u16, bool)> {
+    if value < 1 << 12 {
+        Some((value as u16, false))
+    } else if value.is_multiple_of(1 << 12) && value >> 12 < 1 << 12 {
+        Some(((value >> 12) as u16, true))
+    } else {
+        None
+    }
+}
 
-    let mut result = [0; SZ * 4];
-    result[..SZ].copy_from_slice(&mov_inst0);
-    result[SZ..SZ * 2].copy_from_slice(&movk_inst1);
-    result[SZ * 2..SZ * 3].copy_from_slice(&movk_inst2);
-    result[SZ * 3..SZ * 4].copy_from_slice(&movk_inst3);
-    result
+// whether `amount` is a signed immediate of `bits` bits
+pub fn fits_in(amount: i32, bits: u32) -> bool {
+    let limit = 1 << (bits - 1);
+    (-limit..limit).contains(&amount)
 }
 
-pub fn syscall_write() -> [u8; AARCH64_INST_SIZE * 5] {
-    const SZ: usize = AARCH64_INST_SIZE;
-    let mut result = [0; SZ * 5];
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Label(usize);
 
-    // mov x0, #1
-    // mov x1, x19
-    // mov x2, #1    /* this is possible because aarch64 uses little-endian (though I'm not 100% sure) */
-    // mov x8, #64
-    // svc #0
-    result[..SZ].copy_from_slice(&[0x20, 0x00, 0x80, 0xd2]);
-    result[SZ..SZ * 2].copy_from_slice(&[0xe1, 0x03, 0x13, 0xaa]);
-    result[SZ * 2..SZ * 3].copy_from_slice(&[0x22, 0x00, 0x80, 0xd2]);
-    result[SZ * 3..SZ * 4].copy_from_slice(&[0x08, 0x08, 0x80, 0xd2]);
-    result[SZ * 4..SZ * 5].copy_from_slice(&[0x01, 0x00, 0x00, 0xd4]);
-    result
+// Branches may refer to labels that are bound later, and their offsets are fixed up in `finish`.
+#[derive(Default)]
+pub struct Assembler {
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>, // byte address of each label, once bound
+    fixups: Vec<(usize, Inst, Label)>, // byte address of a branch, and where it goes
 }
 
-pub fn syscall_read() -> [u8; AARCH64_INST_SIZE * 5] {
-    const SZ: usize = AARCH64_INST_SIZE;
-    let mut result = [0; SZ * 5];
+impl Assembler {
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
 
-    // mov x0, #0
-    // mov x1, x19
-    // mov x2, #1
-    // mov x8, #63
-    // svc #0
-    result[..SZ].copy_from_slice(&[0x00, 0x00, 0x80, 0xd2]);
-    result[SZ..SZ * 2].copy_from_slice(&[0xe1, 0x03, 0x13, 0xaa]);
-    result[SZ * 2..SZ * 3].copy_from_slice(&[0x22, 0x00, 0x80, 0xd2]);
-    result[SZ * 3..SZ * 4].copy_from_slice(&[0xe8, 0x07, 0x80, 0xd2]);
-    result[SZ * 4..SZ * 5].copy_from_slice(&[0x01, 0x00, 0x00, 0xd4]);
-    result
-}
+    pub fn emit(&mut self, inst: Inst) {
+        self.code.extend_from_slice(&inst.encode().to_le_bytes()); // little-endian in memory
+    }
 
-fn cbz_xn_immd19(xn: u8, immd19: i32) -> [u8; AARCH64_INST_SIZE] {
-    assert!(xn < 32);
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
 
-    let base = 0xb4000000u32; // big-endian version of `cbz xn, #immd19`
-    let instruction = base | xn as u32 | ((immd19 as u32) << 5); // immd19 is always positive
-    instruction.to_le_bytes()
-}
+    pub fn bind(&mut self, label: Label) {
+        assert!(self.labels[label.0].is_none(), "{label:?} is bound twice");
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    // in instructions, from the instruction emitted next to a bound `label`
+    pub fn offset_to(&self, label: Label) -> Option<i32> {
+        let target = self.labels[label.0]? as isize;
+        Some(((target - self.code.len() as isize) / AARCH64_INST_SIZE as isize) as i32)
+    }
+
+    // `inst` is a branch, whose offset is ignored and fixed up later
+    pub fn emit_branch(&mut self, inst: Inst, label: Label) {
+        self.fixups.push((self.code.len(), inst, label));
+        self.emit(inst.with_offset(0));
+    }
 
-fn cbnz_xn_immd19(xn: u8, immd19: i32) -> [u8; AARCH64_INST_SIZE] {
-    assert!(xn < 32);
+    // movz xd, #value[..16]
+    // movk xd, #value[16..32], lsl #16    /* and so on, for each halfword that is not zero */
+    pub fn mov_imm(&mut self, rd: X, value: u64) {
+        self.emit(Inst::Movz {
+            rd,
+            imm16: value as u16,
+            lsl: 0,
+        });
+        for lsl in [16, 32, 48] {
+            let imm16 = (value >> lsl) as u16;
+            if imm16 != 0 {
+                self.emit(Inst::Movk { rd, imm16, lsl });
+            }
+        }
+    }
 
-    let base = 0xb5000000u32; // big-endian version of `cbnz xn, #immd19`
-    let sign_shrunk = (immd19 & 0x0007ffff) as u32; // immd19 is always negative
-    let instruction = base | xn as u32 | (sign_shrunk << 5);
-    instruction.to_le_bytes()
+    pub fn finish(mut self) -> Result<Vec<u8>, &'static str> {
+        for (byte_addr, inst, label) in std::mem::take(&mut self.fixups) {
+            let target = self.labels[label.0].expect("branch to a label that is never bound");
+            let amount = (target as isize - byte_addr as isize) / AARCH64_INST_SIZE as isize;
+            let bits = inst
+                .offset_bits()
+                .expect("fixup of an instruction that is not a branch");
+            let amount = i32::try_from(amount)
+                .ok()
+                .filter(|&amount| fits_in(amount, bits))
+                .ok_or("branch is out of range")?;
+            self.code[byte_addr..byte_addr + AARCH64_INST_SIZE]
+                .copy_from_slice(&inst.with_offset(amount).encode().to_le_bytes());
+        }
+        Ok(self.code)
+    }
 }
 
-pub type CondNearBranch = [u8; AARCH64_INST_SIZE * 3];
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add_w9(imm12: u16, lsl12: bool) -> Inst {
+        Inst::AddImmW {
+            rd: W9,
+            rn: W9,
+            imm12,
+            lsl12,
+        }
+    }
+
+    #[test]
+    fn should_encode_add_sub_immediates() {
+        let sub_w9 = Inst::SubImmW {
+            rd: W9,
+            rn: W9,
+            imm12: 1,
+            lsl12: false,
+        };
+        let add_x19 = Inst::AddImmX {
+            rd: X19,
+            rn: X19,
+            imm12: 4,
+            lsl12: false,
+        };
+        let sub_x19 = Inst::SubImmX {
+            rd: X19,
+            rn: X19,
+            imm12: 4,
+            lsl12: false,
+        };
+        let sub_x19_lsl12 = Inst::SubImmX {
+            rd: X19,
+            rn: X19,
+            imm12: 0x40,
+            lsl12: true,
+        };
+        let add_x22 = Inst::AddImmX {
+            rd: X22,
+            rn: X0,
+            imm12: 0x40,
+            lsl12: true,
+        };
+        let mov_x29 = Inst::AddImmX {
+            rd: X29,
+            rn: SP,
+            imm12: 0,
+            lsl12: false,
+        };
+        assert_eq!(add_w9(1, false).encode(), 0x11000529); // add w9, w9, #1
+        assert_eq!(sub_w9.encode(), 0x51000529); // sub w9, w9, #1
+        assert_eq!(add_w9(0xfff, false).encode(), 0x113ffd29); // add w9, w9, #4095
+        assert_eq!(add_w9(1, true).encode(), 0x11400529); // add w9, w9, #1, lsl #12
+        assert_eq!(add_x19.encode(), 0x91001273); // add x19, x19, #4
+        assert_eq!(sub_x19.encode(), 0xd1001273); // sub x19, x19, #4
+        assert_eq!(sub_x19_lsl12.encode(), 0xd1410273); // sub x19, x19, #0x40000
+        assert_eq!(add_x22.encode(), 0x91410016); // add x22, x0, #0x40, lsl #12
+        assert_eq!(mov_x29.encode(), 0x910003fd); // mov x29, sp
+        assert_eq!(Inst::CmpImmW { rn: W9, imm12: 127 }.encode(), 0x7101fd3f); // cmp w9, #127
+        let cmp_x11 = Inst::CmpImmX {
+            rn: X11,
+            imm12: 1,
+            lsl12: true,
+        };
+        assert_eq!(cmp_x11.encode(), 0xf140057f); // cmp x11, #4096
+    }
+
+    #[test]
+    fn should_split_immediates() {
+        assert_eq!(split_immd12(0), Some((0, false)));
+        assert_eq!(split_immd12(4095), Some((4095, false)));
+        assert_eq!(split_immd12(4096), Some((1, true)));
+        assert_eq!(split_immd12(4097), None);
+        assert_eq!(split_immd12(0xfff000), Some((0xfff, true)));
+        assert_eq!(split_immd12(0x1000000), None);
+    }
+
+    #[test]
+    fn should_encode_register_forms() {
+        assert_eq!(
+            Inst::AddW {
+                rd: W9,
+                rn: W9,
+                rm: W8
+            }
+            .encode(),
+            0x0b080129
+        ); // add w9, w9, w8
+        assert_eq!(
+            Inst::SubW {
+                rd: W9,
+                rn: W9,
+                rm: W8
+            }
+            .encode(),
+            0x4b080129
+        ); // sub w9, w9, w8
+        assert_eq!(Inst::UxtbW { rd: W9, rn: W19 }.encode(), 0x53001e69); // uxtb w9, w19
+        assert_eq!(
+            Inst::AddX {
+                rd: X19,
+                rn: X19,
+                rm: X8
+            }
+            .encode(),
+            0x8b080273
+        ); // add x19, x19, x8
+        assert_eq!(
+            Inst::SubX {
+                rd: X19,
+                rn: X19,
+                rm: X8
+            }
+            .encode(),
+            0xcb080273
+        ); // sub x19, x19, x8
+        assert_eq!(
+            Inst::SubX {
+                rd: X1,
+                rn: X19,
+                rm: X21
+            }
+            .encode(),
+            0xcb150261
+        ); // sub x1, x19, x21
+        assert_eq!(Inst::CmpX { rn: X19, rm: X21 }.encode(), 0xeb15027f); // cmp x19, x21
+        assert_eq!(
+            Inst::LsrX {
+                rd: X1,
+                rn: X1,
+                shift: 2
+            }
+            .encode(),
+            0xd342fc21
+        ); // lsr x1, x1, #2
+        assert_eq!(
+            Inst::AddLslX {
+                rd: X19,
+                rn: X0,
+                rm: X2,
+                shift: 2
+            }
+            .encode(),
+            0x8b020813
+        ); // add x19, x0, x2, lsl #2
+        assert_eq!(Inst::Mov { rd: X0, rm: X20 }.encode(), 0xaa1403e0); // mov x0, x20
+        assert_eq!(Inst::Mov { rd: X19, rm: X0 }.encode(), 0xaa0003f3); // mov x19, x0
+        assert_eq!(Inst::Blr { rn: X8 }.encode(), 0xd63f0100); // blr x8
+    }
+
+    #[test]
+    fn should_encode_loads_and_stores() {
+        let frame = |rt, rt2, offset, index| Inst::Stp {
+            rt,
+            rt2,
+            rn: SP,
+            offset,
+            index,
+        };
+        assert_eq!(frame(X29, X30, -48, Index::Pre).encode(), 0xa9bd7bfd); // stp x29, x30, [sp, #-48]!
+        assert_eq!(frame(X19, X20, 16, Index::Offset).encode(), 0xa90153f3); // stp x19, x20, [sp, #16]
+        let ldp_x29 = Inst::Ldp {
+            rt: X29,
+            rt2: X30,
+            rn: SP,
+            offset: 48,
+            index: Index::Post,
+        };
+        assert_eq!(ldp_x29.encode(), 0xa8c37bfd); // ldp x29, x30, [sp], #48
+        let ldp_x10 = Inst::Ldp {
+            rt: X10,
+            rt2: X11,
+            rn: X20,
+            offset: 16,
+            index: Index::Offset,
+        };
+        assert_eq!(ldp_x10.encode(), 0xa9412e8a); // ldp x10, x11, [x20, #16]
+
+        assert_eq!(
+            Inst::LdrW {
+                rt: W9,
+                rn: X19,
+                offset: 0
+            }
+            .encode(),
+            0xb9400269
+        ); // ldr w9, [x19]
+        assert_eq!(
+            Inst::StrW {
+                rt: W9,
+                rn: X19,
+                offset: 0
+            }
+            .encode(),
+            0xb9000269
+        ); // str w9, [x19]
+        assert_eq!(
+            Inst::LdrX {
+                rt: X8,
+                rn: X20,
+                offset: 8
+            }
+            .encode(),
+            0xf9400688
+        ); // ldr x8, [x20, #8]
+        assert_eq!(
+            Inst::StrX {
+                rt: X11,
+                rn: X20,
+                offset: 24
+            }
+            .encode(),
+            0xf9000e8b
+        ); // str x11, [x20, #24]
+        assert_eq!(
+            Inst::Strb {
+                rt: W9,
+                rn: X10,
+                rm: X11
+            }
+            .encode(),
+            0x382b6949
+        ); // strb w9, [x10, x11]
+    }
+
+    #[test]
+    fn should_encode_branches() {
+        assert_eq!(Inst::Cbz { rt: W0, offset: 2 }.encode(), 0x34000040); // cbz w0, #2
+        assert_eq!(Inst::Cbnz { rt: W9, offset: -1 }.encode(), 0x35ffffe9); // cbnz w9, #-1
+        assert_eq!(
+            Inst::BCond {
+                cond: Cond::Ls,
+                offset: 2
+            }
+            .encode(),
+            0x54000049
+        ); // b.ls #2
+        assert_eq!(Inst::B { offset: -2 }.encode(), 0x17fffffe); // b #-2
+        assert_eq!(Inst::Bl { offset: -2 }.encode(), 0x97fffffe); // bl #-2
+        assert_eq!(
+            Inst::BCond {
+                cond: Cond::Hi,
+                offset: 2
+            }
+            .encode(),
+            0x54000048
+        ); // b.hi #2
+        assert_eq!(Inst::Svc { imm16: 0 }.encode(), 0xd4000001); // svc #0
+    }
+
+    #[test]
+    fn should_decode_what_it_encodes() {
+        let index = Index::Pre;
+        let insts = [
+            Inst::Ret,
+            Inst::Movz {
+                rd: X0,
+                imm16: 0xfedc,
+                lsl: 32,
+            },
+            Inst::Movk {
+                rd: X8,
+                imm16: 1,
+                lsl: 16,
+            },
+            Inst::Mov { rd: X19, rm: X0 },
+            add_w9(0xfff, true),
+            Inst::SubImmW {
+                rd: W9,
+                rn: W9,
+                imm12: 3,
+                lsl12: false,
+            },
+            Inst::AddImmX {
+                rd: X29,
+                rn: SP,
+                imm12: 0,
+                lsl12: false,
+            },
+            Inst::SubImmX {
+                rd: X19,
+                rn: X19,
+                imm12: 0x40,
+                lsl12: true,
+            },
+            Inst::CmpImmW { rn: W9, imm12: 127 },
+            Inst::CmpImmX {
+                rn: X11,
+                imm12: 1,
+                lsl12: true,
+            },
+            Inst::AddW {
+                rd: W9,
+                rn: W9,
+                rm: W8,
+            },
+            Inst::SubW {
+                rd: W9,
+                rn: W9,
+                rm: W8,
+            },
+            Inst::AddX {
+                rd: X19,
+                rn: X19,
+                rm: X8,
+            },
+            Inst::SubX {
+                rd: X1,
+                rn: X19,
+                rm: X21,
+            },
+            Inst::CmpX { rn: X19, rm: X22 },
+            Inst::LsrX {
+                rd: X1,
+                rn: X1,
+                shift: 2,
+            },
+            Inst::AddLslX {
+                rd: X19,
+                rn: X0,
+                rm: X2,
+                shift: 2,
+            },
+            Inst::UxtbW { rd: W9, rn: W19 },
+            Inst::LdrW {
+                rt: W9,
+                rn: X19,
+                offset: 4,
+            },
+            Inst::StrW {
+                rt: W9,
+                rn: X19,
+                offset: 0,
+            },
+            Inst::LdrX {
+                rt: X8,
+                rn: X20,
+                offset: 8,
+            },
+            Inst::StrX {
+                rt: X11,
+                rn: X20,
+                offset: 24,
+            },
+            Inst::Strb {
+                rt: W9,
+                rn: X10,
+                rm: X11,
+            },
+            Inst::Stp {
+                rt: X29,
+                rt2: X30,
+                rn: SP,
+                offset: -48,
+                index,
+            },
+            Inst::Ldp {
+                rt: X29,
+                rt2: X30,
+                rn: SP,
+                offset: 48,
+                index: Index::Post,
+            },
+            Inst::Ldp {
+                rt: X10,
+                rt2: X11,
+                rn: X20,
+                offset: 16,
+                index: Index::Offset,
+            },
+            Inst::Blr { rn: X8 },
+            Inst::Svc { imm16: 0 },
+            Inst::B { offset: -(1 << 25) },
+            Inst::Bl { offset: 5 },
+            Inst::BCond {
+                cond: Cond::Hs,
+                offset: 2,
+            },
+            Inst::BCond {
+                cond: Cond::Ne,
+                offset: -1,
+            },
+            Inst::Cbz {
+                rt: W0,
+                offset: (1 << 18) - 1,
+            },
+            Inst::Cbnz { rt: W9, offset: -3 },
+        ];
+        for inst in insts {
+            assert_eq!(Inst::decode(inst.encode()), Some(inst));
+        }
+        assert_eq!(Inst::decode(0xd4000002), None); // hvc #0
+        assert_eq!(Inst::decode(0xd503201f), None); // nop
+    }
+
+    #[test]
+    fn should_fix_up_forward_and_backward_branches() {
+        let mut asm = Assembler::default();
+        let (back, forward) = (asm.new_label(), asm.new_label());
+        asm.bind(back);
+        asm.emit(Inst::Ret);
+        asm.emit_branch(Inst::Cbz { rt: W9, offset: 0 }, forward);
+        assert_eq!(asm.offset_to(back), Some(-2));
+        assert_eq!(asm.offset_to(forward), None);
+        asm.emit_branch(Inst::B { offset: 0 }, back);
+        asm.bind(forward);
+        asm.emit(Inst::Ret);
+
+        let code = asm.finish().unwrap();
+        let words: Vec<u32> = code
+            .chunks(AARCH64_INST_SIZE)
+            .map(|inst| u32::from_le_bytes(inst.try_into().unwrap()))
+            .collect();
+        let expected = [
+            Inst::Ret,
+            Inst::Cbz { rt: W9, offset: 2 },
+            Inst::B { offset: -2 },
+            Inst::Ret,
+        ];
+        assert_eq!(words, expected.map(|inst| inst.encode()));
+    }
+
+    #[test]
+    fn should_reject_branches_out_of_range() {
+        let mut asm = Assembler::default();
+        let far = asm.new_label();
+        asm.emit_branch(Inst::Cbnz { rt: W9, offset: 0 }, far);
+        for _ in 0..1 << 18 {
+            asm.emit(Inst::Ret);
+        }
+        asm.bind(far);
+        assert_eq!(asm.finish(), Err("branch is out of range"));
+    }
 
-pub fn cbz_x9_addrx19_immd19(operand: i32) -> CondNearBranch {
-    const SZ: usize = AARCH64_INST_SIZE;
-    let mut result = [0; SZ * 3];
+    #[test]
+    fn should_check_branch_ranges() {
+        assert!(fits_in((1 << 18) - 1, 19));
+        assert!(fits_in(-(1 << 18), 19));
+        assert!(!fits_in(1 << 18, 19));
+        assert!(fits_in(1 << 18, 26));
+        assert!(!fits_in(1 << 25, 26));
+    }
 
-    // ldr x9, [x19]
-    // nop
-    // cbz x9, #immd19
-    result[..SZ].copy_from_slice(&ldr_w9_addrx19());
-    result[SZ..SZ * 2].copy_from_slice(&nop());
-    result[SZ * 2..SZ * 3].copy_from_slice(&cbz_xn_immd19(9, operand));
-    result
-}
-
-pub fn cbnz_x9_addrx19_immd19(operand: i32) -> CondNearBranch {
-    const SZ: usize = AARCH64_INST_SIZE;
-    let mut result = [0; SZ * 3];
-
-    // ldr x9, [x19]
-    // nop
-    // cbnz x9, #immd19
-    result[..SZ].copy_from_slice(&ldr_w9_addrx19());
-    result[SZ..SZ * 2].copy_from_slice(&nop());
-    result[SZ * 2..SZ * 3].copy_from_slice(&cbnz_xn_immd19(9, operand));
-    result
+    #[test]
+    fn should_skip_zero_halfwords() {
+        let mut asm = Assembler::default();
+        asm.mov_imm(X0, 0x0001_0000_0000_0203);
+        let expected = [
+            Inst::Movz {
+                rd: X0,
+                imm16: 0x203,
+                lsl: 0,
+            },
+            Inst::Movk {
+                rd: X0,
+                imm16: 1,
+                lsl: 48,
+            },
+        ];
+        let expected: Vec<u8> = expected
+            .iter()
+            .flat_map(|inst| inst.encode().to_le_bytes())
+            .collect();
+        assert_eq!(asm.finish().unwrap(), expected);
+    }
 }
@@ -0,0 +1,310 @@
+use super::codegen::*;
+use super::AARCH64_INST_SIZE;
+
+// register 31 reads as the stack pointer in address and add/sub immediate operands, and as the
+// zero register elsewhere
+fn x(r: X) -> String {
+    if r.0 == 31 {
+        "xzr".to_string()
+    } else {
+        format!("x{}", r.0)
+    }
+}
+
+fn x_or_sp(r: X) -> String {
+    if r.0 == 31 {
+        "sp".to_string()
+    } else {
+        format!("x{}", r.0)
+    }
+}
+
+fn w(r: W) -> String {
+    if r.0 == 31 {
+        "wzr".to_string()
+    } else {
+        format!("w{}", r.0)
+    }
+}
+
+fn w_or_wsp(r: W) -> String {
+    if r.0 == 31 {
+        "wsp".to_string()
+    } else {
+        format!("w{}", r.0)
+    }
+}
+
+fn imm12(imm12: u16, lsl12: bool) -> String {
+    if lsl12 {
+        format!("#{imm12:#x}, lsl #12")
+    } else {
+        format!("#{imm12}")
+    }
+}
+
+fn addr(rn: X, offset: u16) -> String {
+    if offset == 0 {
+        format!("[{}]", x_or_sp(rn))
+    } else {
+        format!("[{}, #{offset}]", x_or_sp(rn))
+    }
+}
+
+fn cond(cond: Cond) -> &'static str {
+    match cond {
+        Cond::Eq => "eq",
+        Cond::Ne => "ne",
+        Cond::Hs => "hs",
+        Cond::Lo => "lo",
+        Cond::Hi => "hi",
+        Cond::Ls => "ls",
+    }
+}
+
+// `inst` at byte address `pc`, in the assembly syntax, with branch targets as byte addresses
+pub fn format(inst: Inst, pc: usize) -> String {
+    let target = |offset: i32| {
+        let target = pc as isize + offset as isize * AARCH64_INST_SIZE as isize;
+        format!("{target:#x}")
+    };
+    let lsl = |lsl: u8| match lsl {
+        0 => String::new(),
+        lsl => format!(", lsl #{lsl}"),
+    };
+
+    match inst {
+        Inst::Ret => "ret".to_string(),
+        Inst::Movz {
+            rd,
+            imm16,
+            lsl: shift,
+        } => format!("movz {}, #{imm16:#x}{}", x(rd), lsl(shift)),
+        Inst::Movk {
+            rd,
+            imm16,
+            lsl: shift,
+        } => format!("movk {}, #{imm16:#x}{}", x(rd), lsl(shift)),
+        Inst::Mov { rd, rm } => format!("mov {}, {}", x(rd), x(rm)),
+        Inst::AddImmW {
+            rd,
+            rn,
+            imm12: imm,
+            lsl12,
+        } => {
+            format!(
+                "add {}, {}, {}",
+                w_or_wsp(rd),
+                w_or_wsp(rn),
+                imm12(imm, lsl12)
+            )
+        }
+        Inst::SubImmW {
+            rd,
+            rn,
+            imm12: imm,
+            lsl12,
+        } => {
+            format!(
+                "sub {}, {}, {}",
+                w_or_wsp(rd),
+                w_or_wsp(rn),
+                imm12(imm, lsl12)
+            )
+        }
+        Inst::AddImmX {
+            rd,
+            rn,
+            imm12: 0,
+            lsl12: _,
+        } if rd.0 == 31 || rn.0 == 31 => {
+            format!("mov {}, {}", x_or_sp(rd), x_or_sp(rn))
+        }
+        Inst::AddImmX {
+            rd,
+            rn,
+            imm12: imm,
+            lsl12,
+        } => {
+            format!(
+                "add {}, {}, {}",
+                x_or_sp(rd),
+                x_or_sp(rn),
+                imm12(imm, lsl12)
+            )
+        }
+        Inst::SubImmX {
+            rd,
+            rn,
+            imm12: imm,
+            lsl12,
+        } => {
+            format!(
+                "sub {}, {}, {}",
+                x_or_sp(rd),
+                x_or_sp(rn),
+                imm12(imm, lsl12)
+            )
+        }
+        Inst::CmpImmW { rn, imm12: imm } => format!("cmp {}, #{imm}", w_or_wsp(rn)),
+        Inst::CmpImmX {
+            rn,
+            imm12: imm,
+            lsl12,
+        } => {
+            format!("cmp {}, {}", x_or_sp(rn), imm12(imm, lsl12))
+        }
+        Inst::AddW { rd, rn, rm } => format!("add {}, {}, {}", w(rd), w(rn), w(rm)),
+        Inst::SubW { rd, rn, rm } => format!("sub {}, {}, {}", w(rd), w(rn), w(rm)),
+        Inst::AddX { rd, rn, rm } => format!("add {}, {}, {}", x(rd), x(rn), x(rm)),
+        Inst::AddLslX { rd, rn, rm, shift } => {
+            format!("add {}, {}, {}, lsl #{shift}", x(rd), x(rn), x(rm))
+        }
+        Inst::SubX { rd, rn, rm } => format!("sub {}, {}, {}", x(rd), x(rn), x(rm)),
+        Inst::CmpX { rn, rm } => format!("cmp {}, {}", x(rn), x(rm)),
+        Inst::LsrX { rd, rn, shift } => format!("lsr {}, {}, #{shift}", x(rd), x(rn)),
+        Inst::UxtbW { rd, rn } => format!("uxtb {}, {}", w(rd), w(rn)),
+        Inst::LdrW { rt, rn, offset } => format!("ldr {}, {}", w(rt), addr(rn, offset)),
+        Inst::StrW { rt, rn, offset } => format!("str {}, {}", w(rt), addr(rn, offset)),
+        Inst::LdrX { rt, rn, offset } => format!("ldr {}, {}", x(rt), addr(rn, offset)),
+        Inst::StrX { rt, rn, offset } => format!("str {}, {}", x(rt), addr(rn, offset)),
+        Inst::Strb { rt, rn, rm } => format!("strb {}, [{}, {}]", w(rt), x_or_sp(rn), x(rm)),
+        Inst::Stp {
+            rt,
+            rt2,
+            rn,
+            offset,
+            index,
+        }
+        | Inst::Ldp {
+            rt,
+            rt2,
+            rn,
+            offset,
+            index,
+        } => {
+            let mnemonic = if let Inst::Stp { .. } = inst {
+                "stp"
+            } else {
+                "ldp"
+            };
+            let rn = x_or_sp(rn);
+            let addr = match index {
+                Index::Offset => format!("[{rn}, #{offset}]"),
+                Index::Pre => format!("[{rn}, #{offset}]!"),
+                Index::Post => format!("[{rn}], #{offset}"),
+            };
+            format!("{mnemonic} {}, {}, {addr}", x(rt), x(rt2))
+        }
+        Inst::Blr { rn } => format!("blr {}", x(rn)),
+        Inst::Svc { imm16 } => format!("svc #{imm16:#x}"),
+        Inst::B { offset } => format!("b {}", target(offset)),
+        Inst::Bl { offset } => format!("bl {}", target(offset)),
+        Inst::BCond { cond: c, offset } => format!("b.{} {}", cond(c), target(offset)),
+        Inst::Cbz { rt, offset } => format!("cbz {}, {}", w(rt), target(offset)),
+        Inst::Cbnz { rt, offset } => format!("cbnz {}, {}", w(rt), target(offset)),
+    }
+}
+
+// Each instruction: its byte address, length and text. Words that the encoder never produces
+// show up as `.word`.
+pub fn disassemble(code: &[u8]) -> Vec<(usize, usize, String)> {
+    code.chunks(AARCH64_INST_SIZE)
+        .enumerate()
+        .map(|(idx, bytes)| {
+            let pc = idx * AARCH64_INST_SIZE;
+            let text = match <[u8; AARCH64_INST_SIZE]>::try_from(bytes) {
+                Ok(word) => {
+                    let word = u32::from_le_bytes(word);
+                    match Inst::decode(word) {
+                        Some(inst) => format(inst, pc),
+                        None => format!(".word {word:#010x}"),
+                    }
+                }
+                Err(_) => format!(".byte {bytes:02x?}"),
+            };
+            (pc, bytes.len(), text)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate_ops;
+    use crate::jitc::{emit_code, Target};
+
+    #[test]
+    fn should_format_like_the_assembly_syntax() {
+        let frame = Inst::Stp {
+            rt: X29,
+            rt2: X30,
+            rn: SP,
+            offset: -48,
+            index: Index::Pre,
+        };
+        assert_eq!(format(frame, 0), "stp x29, x30, [sp, #-48]!");
+        let mov = Inst::AddImmX {
+            rd: X29,
+            rn: SP,
+            imm12: 0,
+            lsl12: false,
+        };
+        assert_eq!(format(mov, 0), "mov x29, sp");
+        let add = Inst::AddImmX {
+            rd: X22,
+            rn: X0,
+            imm12: 0x40,
+            lsl12: true,
+        };
+        assert_eq!(format(add, 0), "add x22, x0, #0x40, lsl #12");
+        assert_eq!(
+            format(
+                Inst::LdrX {
+                    rt: X8,
+                    rn: X20,
+                    offset: 8
+                },
+                0
+            ),
+            "ldr x8, [x20, #8]"
+        );
+        assert_eq!(
+            format(
+                Inst::LdrW {
+                    rt: W9,
+                    rn: X19,
+                    offset: 0
+                },
+                0
+            ),
+            "ldr w9, [x19]"
+        );
+        let movk = Inst::Movk {
+            rd: X0,
+            imm16: 1,
+            lsl: 16,
+        };
+        assert_eq!(format(movk, 0), "movk x0, #0x1, lsl #16");
+        assert_eq!(
+            format(Inst::Cbnz { rt: W9, offset: -2 }, 0x20),
+            "cbnz w9, 0x18"
+        );
+        let b_lo = Inst::BCond {
+            cond: Cond::Lo,
+            offset: 2,
+        };
+        assert_eq!(format(b_lo, 0x20), "b.lo 0x28");
+    }
+
+    #[test]
+    fn should_decode_everything_the_backend_emits() {
+        let program = format!("+[->{}+<]<-[,.]{}", ">".repeat(5000), "+".repeat(70000));
+        let code = emit_code(&generate_ops(&program), Target::Aarch64).unwrap();
+        let listing = disassemble(&code.bytes);
+        assert_eq!(listing.len() * AARCH64_INST_SIZE, code.bytes.len());
+        for (pc, _, text) in listing {
+            assert!(!text.starts_with('.'), "{text} at {pc:#x}");
+        }
+    }
+}
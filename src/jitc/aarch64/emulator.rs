@@ -0,0 +1,540 @@
+// An interpreter for the A64 subset that the backend emits, so that its output runs on any host.
+// Registers hold real host addresses, and every access is checked against the tape, the run
+// context, the output buffer and the emulated stack. `blr` to a callback in the run context calls
+// it natively, and then clobbers the caller-saved registers as a real call might.
+
+use std::io::{Read, Write};
+use std::mem::size_of;
+use std::ops::Range;
+use std::ptr;
+
+use super::codegen::*;
+use super::AARCH64_INST_SIZE;
+use crate::interpreter::RuntimeError;
+use crate::jitc::OUTPUT_BUFFER_SIZE;
+use crate::jitc::{
+    emit_code, emit_loop, run_code, JitCompileError, JitExit, RunContext, RunStats, Target,
+};
+use crate::op::*;
+use crate::testing::Process;
+use crate::{Memory, MEM_SIZE};
+
+// the return address of the entry, which ends the emulation
+const HOST: u64 = 0xffff_0000_0000_0000;
+
+// what registers hold when their value should not matter
+const POISON: u64 = 0xdead_beef_dead_beef;
+
+const STACK_SIZE: usize = 512;
+
+pub struct EmulatedProgram {
+    code: Vec<u8>,
+    end: usize, // the ip after the last op
+}
+
+impl EmulatedProgram {
+    pub fn compile(ops: &[Op]) -> Result<EmulatedProgram, JitCompileError> {
+        Ok(EmulatedProgram {
+            code: emit_code(ops, Target::Aarch64)?.bytes,
+            end: ops.len(),
+        })
+    }
+
+    // the loop that starts at `head`, as `jit_compile_loop` compiles it
+    pub fn compile_loop(ops: &[Op], head: usize) -> Result<EmulatedProgram, JitCompileError> {
+        Ok(EmulatedProgram {
+            code: emit_loop(ops, head, Target::Aarch64)?.bytes,
+            end: ops[head].operand as usize,
+        })
+    }
+
+    pub fn run<R: Read, W: Write>(
+        &mut self,
+        tape: &mut Memory,
+        input: R,
+        output: W,
+    ) -> Result<RunStats, RuntimeError> {
+        self.run_from(tape, 0, input, output)
+    }
+
+    pub fn run_from<R: Read, W: Write>(
+        &mut self,
+        tape: &mut Memory,
+        dp: usize,
+        input: R,
+        output: W,
+    ) -> Result<RunStats, RuntimeError> {
+        run_code(self.end, tape, dp, input, output, |tape, context, dp| {
+            emulate(&self.code, tape, context, dp)
+        })
+    }
+}
+
+struct Cpu {
+    x: [u64; 31],
+    sp: u64,
+    pc: usize,      // byte address in the code
+    code_base: u64, // where the code is in memory
+    carry: bool,
+    zero: bool,
+    regions: Vec<(u64, usize)>, // what the code may access: start and length in bytes
+}
+
+impl Cpu {
+    // register 31 is the zero register, or the stack pointer where the instruction says so
+    fn reg(&self, r: u8) -> u64 {
+        self.x.get(r as usize).copied().unwrap_or(0)
+    }
+
+    fn reg_or_sp(&self, r: u8) -> u64 {
+        if r == 31 {
+            self.sp
+        } else {
+            self.x[r as usize]
+        }
+    }
+
+    fn set(&mut self, r: u8, value: u64) {
+        if let Some(x) = self.x.get_mut(r as usize) {
+            *x = value;
+        }
+    }
+
+    fn set_or_sp(&mut self, r: u8, value: u64) {
+        if r == 31 {
+            self.sp = value;
+        } else {
+            self.x[r as usize] = value;
+        }
+    }
+
+    fn compare(&mut self, a: u64, b: u64) {
+        self.carry = a >= b; // no borrow
+        self.zero = a == b;
+    }
+
+    fn check(&self, addr: u64, size: usize) -> *mut u8 {
+        let inside = self.regions.iter().any(|&(start, len)| {
+            addr >= start && addr.wrapping_add(size as u64) <= start + len as u64
+        });
+        assert!(
+            inside,
+            "access to {addr:#x} out of bounds at pc {:#x}",
+            self.pc
+        );
+        addr as *mut u8
+    }
+
+    fn load(&self, addr: u64, size: usize) -> u64 {
+        let mut bytes = [0; 8];
+        // Safety: `check` makes sure that the bytes are within one of the regions
+        unsafe { ptr::copy_nonoverlapping(self.check(addr, size), bytes.as_mut_ptr(), size) };
+        u64::from_le_bytes(bytes)
+    }
+
+    fn store(&self, addr: u64, size: usize, value: u64) {
+        // Safety: same as `load`
+        unsafe {
+            ptr::copy_nonoverlapping(value.to_le_bytes().as_ptr(), self.check(addr, size), size)
+        };
+    }
+
+    // byte address in `code` of `addr`, if it is in there
+    fn code_addr(&self, addr: u64, code: &[u8]) -> Option<usize> {
+        let offset = addr.wrapping_sub(self.code_base) as usize;
+        (offset < code.len()).then_some(offset)
+    }
+
+    // runs one instruction, and stops at what the caller handles: a return or call to outside
+    // `code`, or a system call
+    fn step(&mut self, code: &[u8]) -> Option<Event> {
+        let pc = self.pc;
+        let word = code
+            .get(pc..pc + AARCH64_INST_SIZE)
+            .unwrap_or_else(|| panic!("pc {pc:#x} is outside the code"));
+        let word = u32::from_le_bytes(word.try_into().unwrap());
+        let inst = Inst::decode(word)
+            .unwrap_or_else(|| panic!("unsupported instruction {word:#010x} at pc {pc:#x}"));
+        let branch =
+            |offset: i32| (pc as isize + offset as isize * AARCH64_INST_SIZE as isize) as usize;
+        let shifted = |imm12: u16, lsl12: bool| (imm12 as u64) << if lsl12 { 12 } else { 0 };
+        self.pc += AARCH64_INST_SIZE;
+
+        match inst {
+            Inst::Ret => match self.code_addr(self.reg(30), code) {
+                Some(addr) => self.pc = addr,
+                None => return Some(Event::Return),
+            },
+            Inst::Movz { rd, imm16, lsl } => self.set(rd.0, (imm16 as u64) << lsl),
+            Inst::Movk { rd, imm16, lsl } => {
+                let value = self.reg(rd.0) & !(0xffff << lsl) | (imm16 as u64) << lsl;
+                self.set(rd.0, value);
+            }
+            Inst::Mov { rd, rm } => self.set(rd.0, self.reg(rm.0)),
+            Inst::AddImmW {
+                rd,
+                rn,
+                imm12,
+                lsl12,
+            } => {
+                let value =
+                    (self.reg_or_sp(rn.0) as u32).wrapping_add(shifted(imm12, lsl12) as u32);
+                self.set_or_sp(rd.0, value as u64);
+            }
+            Inst::SubImmW {
+                rd,
+                rn,
+                imm12,
+                lsl12,
+            } => {
+                let value =
+                    (self.reg_or_sp(rn.0) as u32).wrapping_sub(shifted(imm12, lsl12) as u32);
+                self.set_or_sp(rd.0, value as u64);
+            }
+            Inst::AddImmX {
+                rd,
+                rn,
+                imm12,
+                lsl12,
+            } => {
+                let value = self.reg_or_sp(rn.0).wrapping_add(shifted(imm12, lsl12));
+                self.set_or_sp(rd.0, value);
+            }
+            Inst::SubImmX {
+                rd,
+                rn,
+                imm12,
+                lsl12,
+            } => {
+                let value = self.reg_or_sp(rn.0).wrapping_sub(shifted(imm12, lsl12));
+                self.set_or_sp(rd.0, value);
+            }
+            Inst::CmpImmW { rn, imm12 } => {
+                self.compare(self.reg_or_sp(rn.0) as u32 as u64, imm12 as u64)
+            }
+            Inst::CmpImmX { rn, imm12, lsl12 } => {
+                self.compare(self.reg_or_sp(rn.0), shifted(imm12, lsl12))
+            }
+            Inst::AddW { rd, rn, rm } => {
+                let value = (self.reg(rn.0) as u32).wrapping_add(self.reg(rm.0) as u32);
+                self.set(rd.0, value as u64);
+            }
+            Inst::SubW { rd, rn, rm } => {
+                let value = (self.reg(rn.0) as u32).wrapping_sub(self.reg(rm.0) as u32);
+                self.set(rd.0, value as u64);
+            }
+            Inst::AddX { rd, rn, rm } => {
+                self.set(rd.0, self.reg(rn.0).wrapping_add(self.reg(rm.0)))
+            }
+            Inst::AddLslX { rd, rn, rm, shift } => {
+                self.set(rd.0, self.reg(rn.0).wrapping_add(self.reg(rm.0) << shift))
+            }
+            Inst::SubX { rd, rn, rm } => {
+                self.set(rd.0, self.reg(rn.0).wrapping_sub(self.reg(rm.0)))
+            }
+            Inst::CmpX { rn, rm } => self.compare(self.reg(rn.0), self.reg(rm.0)),
+            Inst::LsrX { rd, rn, shift } => self.set(rd.0, self.reg(rn.0) >> shift),
+            Inst::UxtbW { rd, rn } => self.set(rd.0, self.reg(rn.0) & 0xff),
+            Inst::LdrW { rt, rn, offset } => {
+                let value = self.load(self.reg_or_sp(rn.0) + offset as u64, 4);
+                self.set(rt.0, value);
+            }
+            Inst::StrW { rt, rn, offset } => {
+                self.store(self.reg_or_sp(rn.0) + offset as u64, 4, self.reg(rt.0))
+            }
+            Inst::LdrX { rt, rn, offset } => {
+                let value = self.load(self.reg_or_sp(rn.0) + offset as u64, 8);
+                self.set(rt.0, value);
+            }
+            Inst::StrX { rt, rn, offset } => {
+                self.store(self.reg_or_sp(rn.0) + offset as u64, 8, self.reg(rt.0))
+            }
+            Inst::Strb { rt, rn, rm } => self.store(
+                self.reg_or_sp(rn.0).wrapping_add(self.reg(rm.0)),
+                1,
+                self.reg(rt.0),
+            ),
+            Inst::Stp {
+                rt,
+                rt2,
+                rn,
+                offset,
+                index,
+            }
+            | Inst::Ldp {
+                rt,
+                rt2,
+                rn,
+                offset,
+                index,
+            } => {
+                let base = self.reg_or_sp(rn.0);
+                let moved = base.wrapping_add(offset as i64 as u64);
+                let addr = if index == Index::Post { base } else { moved };
+                if let Inst::Stp { .. } = inst {
+                    self.store(addr, 8, self.reg(rt.0));
+                    self.store(addr + 8, 8, self.reg(rt2.0));
+                } else {
+                    let (first, second) = (self.load(addr, 8), self.load(addr + 8, 8));
+                    self.set(rt.0, first);
+                    self.set(rt2.0, second);
+                }
+                if index != Index::Offset {
+                    self.set_or_sp(rn.0, moved);
+                }
+            }
+            Inst::Blr { rn } => {
+                let target = self.reg(rn.0);
+                match self.code_addr(target, code) {
+                    Some(addr) => {
+                        self.x[30] = self.code_base + self.pc as u64;
+                        self.pc = addr;
+                    }
+                    None => return Some(Event::Call(target)),
+                }
+            }
+            Inst::Svc { .. } => return Some(Event::Svc),
+            Inst::B { offset } => self.pc = branch(offset),
+            Inst::Bl { offset } => {
+                self.x[30] = self.code_base + self.pc as u64;
+                self.pc = branch(offset);
+            }
+            Inst::BCond { cond, offset } => {
+                let taken = match cond {
+                    Cond::Eq => self.zero,
+                    Cond::Ne => !self.zero,
+                    Cond::Hs => self.carry,
+                    Cond::Lo => !self.carry,
+                    Cond::Hi => self.carry && !self.zero,
+                    Cond::Ls => !self.carry || self.zero,
+                };
+                if taken {
+                    self.pc = branch(offset);
+                }
+            }
+            Inst::Cbz { rt, offset } if self.reg(rt.0) as u32 == 0 => self.pc = branch(offset),
+            Inst::Cbnz { rt, offset } if self.reg(rt.0) as u32 != 0 => self.pc = branch(offset),
+            Inst::Cbz { .. } | Inst::Cbnz { .. } => {}
+        }
+        None
+    }
+}
+
+// what `Cpu::step` leaves to its caller
+enum Event {
+    Return,
+    Call(u64), // to the address
+    Svc,
+}
+
+// runs `code` as `JitEntry`, and checks that it follows AAPCS64 on the way out
+fn emulate<R: Read, W: Write>(
+    code: &[u8],
+    tape: *mut Operand,
+    context: *mut RunContext<R, W>,
+    dp: u64,
+) -> JitExit {
+    let mut stack = vec![0u128; STACK_SIZE / size_of::<u128>()]; // 16-byte aligned
+
+    // Safety: `context` is what `run_code` passes, which lives during the call
+    let (read, flush, buffer) = unsafe { ((*context).read, (*context).flush, (*context).buffer) };
+    let mut cpu = Cpu {
+        x: [POISON; 31],
+        sp: stack.as_mut_ptr() as u64 + STACK_SIZE as u64,
+        pc: 0,
+        code_base: code.as_ptr() as u64,
+        carry: false,
+        zero: false,
+        regions: vec![
+            (tape as u64, MEM_SIZE * size_of::<Operand>()),
+            (context as u64, size_of::<RunContext<R, W>>()),
+            (buffer as u64, OUTPUT_BUFFER_SIZE),
+            (stack.as_ptr() as u64, STACK_SIZE),
+        ],
+    };
+    cpu.x[0] = tape as u64;
+    cpu.x[1] = context as u64;
+    cpu.x[2] = dp;
+    cpu.x[30] = HOST;
+    let saved = (cpu.x, cpu.sp);
+
+    loop {
+        let pc = cpu.pc;
+        match cpu.step(code) {
+            None => {}
+            Some(Event::Return) => {
+                assert_eq!(cpu.reg(30), HOST, "ret to somewhere else at pc {pc:#x}");
+                break;
+            }
+            Some(Event::Call(target)) => {
+                assert_eq!(
+                    cpu.reg(0),
+                    context as u64,
+                    "callback without the run context at pc {pc:#x}"
+                );
+                let status = if target == read as usize as u64 {
+                    let cell = cpu.check(cpu.reg(1), size_of::<Operand>());
+                    let tape_end = tape as u64 + (MEM_SIZE * size_of::<Operand>()) as u64;
+                    assert!(
+                        (tape as u64..tape_end).contains(&(cell as u64)),
+                        "read into something other than the tape"
+                    );
+                    read(context, cell as *mut Operand)
+                } else if target == flush as usize as u64 {
+                    flush(context)
+                } else {
+                    panic!("blr to {target:#x}, which is not a callback, at pc {pc:#x}");
+                };
+                cpu.x[0] = status as u64;
+                cpu.x[1..18].fill(POISON);
+                cpu.x[30] = POISON;
+            }
+            Some(Event::Svc) => panic!("system call from JIT-compiled code at pc {pc:#x}"),
+        }
+    }
+
+    assert_eq!(
+        cpu.x[19..30],
+        saved.0[19..30],
+        "callee-saved registers are not restored"
+    );
+    assert_eq!(cpu.sp, saved.1, "the stack pointer is not restored");
+    JitExit {
+        status: cpu.x[0],
+        dp: cpu.x[1],
+    }
+}
+
+// Runs a static executable whose segments are loaded in `memory`, with its code at `text`, from
+// `entry` until it exits. It is served Linux system calls: `read` from `input`, and `write` to
+// stdout, which fails with EIO when `stdout_fails`, or stderr.
+pub fn run_process(
+    memory: &mut [u8],
+    text: Range<usize>,
+    entry: u64,
+    input: &[u8],
+    stdout_fails: bool,
+) -> Process {
+    const READ: u64 = 63;
+    const WRITE: u64 = 64;
+    const EXIT: u64 = 93;
+    const EIO: u64 = 5;
+
+    let mut stack = vec![0u128; STACK_SIZE / size_of::<u128>()];
+    let base = memory.as_mut_ptr();
+    // Safety: the code is only read, and everything else only through the regions
+    let code = unsafe { std::slice::from_raw_parts(base.add(text.start), text.len()) };
+    let code_base = code.as_ptr() as u64;
+    let mut cpu = Cpu {
+        x: [POISON; 31],
+        sp: stack.as_mut_ptr() as u64 + STACK_SIZE as u64,
+        pc: (entry - code_base) as usize,
+        code_base,
+        carry: false,
+        zero: false,
+        regions: vec![
+            (base as u64, memory.len()),
+            (stack.as_ptr() as u64, STACK_SIZE),
+        ],
+    };
+    let mut input = input;
+    let mut process = Process {
+        stdout: Vec::new(),
+        stderr: Vec::new(),
+        exit_code: 0,
+    };
+    loop {
+        let pc = cpu.pc;
+        match cpu.step(code) {
+            None => {}
+            Some(Event::Svc) => {
+                let (fd, buf, len) = (cpu.reg(0), cpu.reg(1), cpu.reg(2) as usize);
+                let result = match (cpu.reg(8), fd) {
+                    (READ, 0) => {
+                        let len = len.min(input.len());
+                        let buf = cpu.check(buf, len);
+                        // Safety: `check` makes sure that the bytes are within `memory`
+                        unsafe { ptr::copy_nonoverlapping(input.as_ptr(), buf, len) };
+                        input = &input[len..];
+                        len as u64
+                    }
+                    (WRITE, 1) if stdout_fails => EIO.wrapping_neg(),
+                    (WRITE, 1 | 2) => {
+                        // Safety: same as `read`
+                        let bytes = unsafe { std::slice::from_raw_parts(cpu.check(buf, len), len) };
+                        let out = if fd == 1 {
+                            &mut process.stdout
+                        } else {
+                            &mut process.stderr
+                        };
+                        out.extend_from_slice(bytes);
+                        len as u64
+                    }
+                    (EXIT, _) => {
+                        process.exit_code = fd as i32;
+                        return process;
+                    }
+                    (number, _) => panic!("unsupported system call {number} at pc {pc:#x}"),
+                };
+                cpu.x[0] = result;
+            }
+            Some(Event::Return) => panic!("ret from the entry point at pc {pc:#x}"),
+            Some(Event::Call(target)) => {
+                panic!("blr to {target:#x} outside the code at pc {pc:#x}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate_ops;
+
+    fn run(program: &str) -> (Result<RunStats, RuntimeError>, Box<Memory>) {
+        let mut memory = Box::new([0; MEM_SIZE]);
+        let result = EmulatedProgram::compile(&generate_ops(program))
+            .unwrap()
+            .run(&mut memory, &b""[..], Vec::new());
+        (result, memory)
+    }
+
+    #[test]
+    fn should_emulate_cell_and_pointer_arithmetic() {
+        let (result, memory) = run("+++>-----<-");
+        assert_eq!(result.unwrap().dp, 0);
+        assert_eq!(memory[..2], [2, -5]);
+
+        // beyond the immediates
+        let program = format!("{}>{}", "+".repeat(5000), ">".repeat(1025));
+        let (result, memory) = run(&program);
+        assert_eq!(result.unwrap().dp, 1026);
+        assert_eq!(memory[0], 5000);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn should_catch_accesses_outside_the_regions() {
+        let mut cpu_code = Vec::new();
+        // ldr w9, [x19] with x19 never set
+        cpu_code.extend_from_slice(
+            &Inst::LdrW {
+                rt: W9,
+                rn: X19,
+                offset: 0,
+            }
+            .encode()
+            .to_le_bytes(),
+        );
+        let mut memory: Memory = [0; MEM_SIZE];
+        run_code(
+            0,
+            &mut memory,
+            0,
+            &b""[..],
+            Vec::new(),
+            |tape, context, dp| emulate(&cpu_code, tape, context, dp),
+        )
+        .unwrap();
+    }
+}
@@ -0,0 +1,327 @@
+// The code of a static executable around the generated code: the entry point, which runs it and
+// exits with the message for its status, and the callbacks of its run context, which make Linux
+// system calls themselves. A relocatable object needs only the start of `bf_main`.
+
+use super::codegen::*;
+use crate::jitc::aot::{Addresses, Runtime, CONTEXT_FAILED, OTHER_CAUSE, TABLE_ENTRY_SIZE};
+use crate::jitc::Trap;
+
+const SYS_READ: u16 = 63;
+const SYS_WRITE: u16 = 64;
+const SYS_EXIT: u16 = 93;
+
+// movz xd, #value[..16]
+// movk xd, #value[16..32], lsl #16
+// movk xd, #value[32..48], lsl #32
+// movk xd, #value[48..], lsl #48
+// always four instructions, so that the size of the code does not depend on addresses
+fn mov_addr(asm: &mut Assembler, rd: X, value: u64) {
+    asm.emit(Inst::Movz {
+        rd,
+        imm16: value as u16,
+        lsl: 0,
+    });
+    for lsl in [16, 32, 48] {
+        let imm16 = (value >> lsl) as u16;
+        asm.emit(Inst::Movk { rd, imm16, lsl });
+    }
+}
+
+fn movz(asm: &mut Assembler, rd: X, imm16: u16) {
+    asm.emit(Inst::Movz { rd, imm16, lsl: 0 });
+}
+
+fn add_x(asm: &mut Assembler, rd: X, rn: X, imm12: u16) {
+    asm.emit(Inst::AddImmX {
+        rd,
+        rn,
+        imm12,
+        lsl12: false,
+    });
+}
+
+fn b_cond(asm: &mut Assembler, cond: Cond, label: Label) {
+    asm.emit_branch(Inst::BCond { cond, offset: 0 }, label);
+}
+
+// The generated code follows right after, as `bf_main`
+pub fn runtime(addresses: &Addresses) -> Runtime {
+    let mut asm = Assembler::default();
+    let (main, read, flush) = (asm.new_label(), asm.new_label(), asm.new_label());
+
+    /* _start: the stack is 16-byte aligned, and nothing else needs to be set up */
+    // mov x0, #tape
+    // mov x1, #context
+    // movz x2, #0
+    // bl main
+    // mov x19, x0      /* the status */
+    // mov x20, x1      /* and the data pointer */
+    // mov x21, #context
+    mov_addr(&mut asm, X0, addresses.tape);
+    mov_addr(&mut asm, X1, addresses.context);
+    movz(&mut asm, X2, 0);
+    asm.emit_branch(Inst::Bl { offset: 0 }, main);
+    asm.emit(Inst::Mov { rd: X19, rm: X0 });
+    asm.emit(Inst::Mov { rd: X20, rm: X1 });
+    mov_addr(&mut asm, X21, addresses.context);
+
+    /* I/O errors where writing failed, and cells that do not fit in a byte, look up other messages */
+    // uxtb w9, w19
+    // cmp w9, #IO
+    // b.ne not_io
+    // ldr x10, [x21, #FAILED]
+    // cbz w10, flush_rest
+    // add x19, x19, #OTHER_CAUSE
+    // b flush_rest
+    let (not_io, flush_rest) = (asm.new_label(), asm.new_label());
+    asm.emit(Inst::UxtbW { rd: W9, rn: W19 });
+    asm.emit(Inst::CmpImmW {
+        rn: W9,
+        imm12: Trap::Io as u16,
+    });
+    b_cond(&mut asm, Cond::Ne, not_io);
+    asm.emit(Inst::LdrX {
+        rt: X10,
+        rn: X21,
+        offset: CONTEXT_FAILED,
+    });
+    asm.emit_branch(Inst::Cbz { rt: W10, offset: 0 }, flush_rest);
+    add_x(&mut asm, X19, X19, OTHER_CAUSE as u16);
+    asm.emit_branch(Inst::B { offset: 0 }, flush_rest);
+
+    // not_io:
+    // cmp w9, #OUTPUT
+    // b.ne flush_rest
+    // mov x10, #tape
+    // add x10, x10, x20, lsl #2
+    // ldr w11, [x10]
+    // cmp w11, #255
+    // b.ls flush_rest
+    // add x19, x19, #OTHER_CAUSE
+    asm.bind(not_io);
+    asm.emit(Inst::CmpImmW {
+        rn: W9,
+        imm12: Trap::Output as u16,
+    });
+    b_cond(&mut asm, Cond::Ne, flush_rest);
+    mov_addr(&mut asm, X10, addresses.tape);
+    asm.emit(Inst::AddLslX {
+        rd: X10,
+        rn: X10,
+        rm: X20,
+        shift: 2,
+    });
+    asm.emit(Inst::LdrW {
+        rt: W11,
+        rn: X10,
+        offset: 0,
+    });
+    asm.emit(Inst::CmpImmW {
+        rn: W11,
+        imm12: 255,
+    });
+    b_cond(&mut asm, Cond::Ls, flush_rest);
+    add_x(&mut asm, X19, X19, OTHER_CAUSE as u16);
+
+    /* What was written before a trap still goes out */
+    // flush_rest:
+    // mov x0, x21
+    // bl flush
+    // cbnz w19, lookup     /* the trap is in the low byte */
+    // ldr x10, [x21, #FAILED]
+    // cbz w10, lookup
+    // mov x19, #end_key
+    let lookup = asm.new_label();
+    asm.bind(flush_rest);
+    asm.emit(Inst::Mov { rd: X0, rm: X21 });
+    asm.emit_branch(Inst::Bl { offset: 0 }, flush);
+    asm.emit_branch(Inst::Cbnz { rt: W19, offset: 0 }, lookup);
+    asm.emit(Inst::LdrX {
+        rt: X10,
+        rn: X21,
+        offset: CONTEXT_FAILED,
+    });
+    asm.emit_branch(Inst::Cbz { rt: W10, offset: 0 }, lookup);
+    mov_addr(&mut asm, X19, addresses.end_key);
+
+    /* The table has an entry for every status */
+    // lookup:
+    // mov x10, #table
+    // next:
+    // ldr x11, [x10]
+    // cmp x11, x19
+    // b.eq found
+    // add x10, x10, #TABLE_ENTRY_SIZE
+    // b next
+    let (next, found) = (asm.new_label(), asm.new_label());
+    asm.bind(lookup);
+    mov_addr(&mut asm, X10, addresses.table);
+    asm.bind(next);
+    asm.emit(Inst::LdrX {
+        rt: X11,
+        rn: X10,
+        offset: 0,
+    });
+    asm.emit(Inst::CmpX { rn: X11, rm: X19 });
+    b_cond(&mut asm, Cond::Eq, found);
+    add_x(&mut asm, X10, X10, TABLE_ENTRY_SIZE as u16);
+    asm.emit_branch(Inst::B { offset: 0 }, next);
+
+    // found:
+    // ldr x19, [x10, #24]   /* the exit code */
+    // ldr x1, [x10, #8]
+    // ldr x2, [x10, #16]
+    // movz x0, #2
+    // movz x8, #SYS_WRITE
+    // svc #0               /* the message, which is empty on success */
+    // mov x0, x19
+    // movz x8, #SYS_EXIT
+    // svc #0
+    asm.bind(found);
+    for (rt, offset) in [(X19, 24), (X1, 8), (X2, 16)] {
+        asm.emit(Inst::LdrX {
+            rt,
+            rn: X10,
+            offset,
+        });
+    }
+    movz(&mut asm, X0, 2);
+    movz(&mut asm, X8, SYS_WRITE);
+    asm.emit(Inst::Svc { imm16: 0 });
+    asm.emit(Inst::Mov { rd: X0, rm: X19 });
+    movz(&mut asm, X8, SYS_EXIT);
+    asm.emit(Inst::Svc { imm16: 0 });
+
+    /* flush(context): writes out the buffer, or sets FAILED and returns 1 */
+    // mov x9, x0
+    // ldr x1, [x9, #16]
+    // ldr x2, [x9, #24]
+    // str xzr, [x9, #24]
+    // again:
+    // cbz w2, done         /* the buffer is never longer than 4KiB */
+    // movz x0, #1
+    // movz x8, #SYS_WRITE
+    // svc #0
+    // cbz w0, failed
+    // cmp x0, x2
+    // b.hi failed          /* a negative error */
+    // add x1, x1, x0
+    // sub x2, x2, x0
+    // b again
+    // done:
+    // movz x0, #0
+    // ret
+    // failed:
+    // movz x10, #1
+    // str x10, [x9, #FAILED]
+    // movz x0, #1
+    // ret
+    let (again, done, failed) = (asm.new_label(), asm.new_label(), asm.new_label());
+    asm.bind(flush);
+    let flush_offset = asm.len();
+    asm.emit(Inst::Mov { rd: X9, rm: X0 });
+    for (rt, offset) in [(X1, 16), (X2, 24)] {
+        asm.emit(Inst::LdrX { rt, rn: X9, offset });
+    }
+    asm.emit(Inst::StrX {
+        rt: XZR,
+        rn: X9,
+        offset: 24,
+    });
+    asm.bind(again);
+    asm.emit_branch(Inst::Cbz { rt: W2, offset: 0 }, done);
+    movz(&mut asm, X0, 1);
+    movz(&mut asm, X8, SYS_WRITE);
+    asm.emit(Inst::Svc { imm16: 0 });
+    asm.emit_branch(Inst::Cbz { rt: W0, offset: 0 }, failed);
+    asm.emit(Inst::CmpX { rn: X0, rm: X2 });
+    b_cond(&mut asm, Cond::Hi, failed);
+    asm.emit(Inst::AddX {
+        rd: X1,
+        rn: X1,
+        rm: X0,
+    });
+    asm.emit(Inst::SubX {
+        rd: X2,
+        rn: X2,
+        rm: X0,
+    });
+    asm.emit_branch(Inst::B { offset: 0 }, again);
+    asm.bind(done);
+    movz(&mut asm, X0, 0);
+    asm.emit(Inst::Ret);
+    asm.bind(failed);
+    movz(&mut asm, X10, 1);
+    asm.emit(Inst::StrX {
+        rt: X10,
+        rn: X9,
+        offset: CONTEXT_FAILED,
+    });
+    movz(&mut asm, X0, 1);
+    asm.emit(Inst::Ret);
+
+    /* read(context, cell): flushes first, and reads a byte into the cell, which stays 0 at EOF */
+    // stp x1, x30, [sp, #-16]!
+    // bl flush
+    // ldp x1, x30, [sp], #16
+    // cbnz w0, return
+    // str wzr, [x1]
+    // movz x0, #0
+    // movz x2, #1
+    // movz x8, #SYS_READ
+    // svc #0
+    // lsr x0, x0, #63      /* 1 for an error, which is negative */
+    // return:
+    // ret
+    let ret = asm.new_label();
+    asm.bind(read);
+    let read_offset = asm.len();
+    asm.emit(Inst::Stp {
+        rt: X1,
+        rt2: X30,
+        rn: SP,
+        offset: -16,
+        index: Index::Pre,
+    });
+    asm.emit_branch(Inst::Bl { offset: 0 }, flush);
+    asm.emit(Inst::Ldp {
+        rt: X1,
+        rt2: X30,
+        rn: SP,
+        offset: 16,
+        index: Index::Post,
+    });
+    asm.emit_branch(Inst::Cbnz { rt: W0, offset: 0 }, ret);
+    asm.emit(Inst::StrW {
+        rt: WZR,
+        rn: X1,
+        offset: 0,
+    });
+    movz(&mut asm, X0, 0);
+    movz(&mut asm, X2, 1);
+    movz(&mut asm, X8, SYS_READ);
+    asm.emit(Inst::Svc { imm16: 0 });
+    asm.emit(Inst::LsrX {
+        rd: X0,
+        rn: X0,
+        shift: 63,
+    });
+    asm.bind(ret);
+    asm.emit(Inst::Ret);
+
+    asm.bind(main);
+    Runtime {
+        code: asm.finish().expect("the runtime is small"),
+        read: read_offset,
+        flush: flush_offset,
+    }
+}
+
+// `bf_main` in a relocatable object, which falls through into the generated code with the data
+// pointer at the first cell, as `_start` calls it
+pub fn object_entry() -> Vec<u8> {
+    // movz x2, #0
+    let mut asm = Assembler::default();
+    movz(&mut asm, X2, 0);
+    asm.finish().expect("the entry is small")
+}
@@ -0,0 +1,692 @@
+// Ahead-of-time compilation to a static Linux executable, which needs nothing but the kernel: the
+// generated code in `.text` after a small runtime of our own that makes system calls, the tape in
+// `.bss`, and the message for every status the code can return in `.rodata`. Or to a relocatable
+// object, which leaves the tape and I/O to the program it is linked into.
+
+use super::debuginfo::{add_debug_sections, code_symbols, Source};
+use super::elf::{self, Elf, Section, Segment, Symbol};
+use super::{
+    aarch64, compile, emit_code, x86_64, Backend, JitCompileError, Target, Trap, OUTPUT_BUFFER_SIZE,
+};
+use crate::interpreter::RuntimeError;
+use crate::ir::SourcePos;
+use crate::op::*;
+use crate::MEM_SIZE;
+use std::collections::BTreeSet;
+use std::mem::size_of;
+
+// where the executable is loaded, as usual for non-PIE executables
+const BASE: u64 = 0x400000;
+// the largest page size of either target, so that segments map on both
+const PAGE_SIZE: u64 = 0x10000;
+
+// The run context in `.data` has the fields of `RunContext` that generated code touches, and then
+// a flag that `flush` sets when a write fails, which tells the runtime a failed write from a
+// failed read
+pub(crate) const CONTEXT_FAILED: u16 = 32;
+const CONTEXT_SIZE: usize = 40;
+
+// Some statuses stand for one of two errors, which the runtime tells apart after the code returns:
+// an I/O error where writing failed, or a cell that does not even fit in a byte. It looks up the
+// message of the second with this bit set.
+pub(crate) const OTHER_CAUSE: u64 = 0x80;
+
+// Each entry in the message table is the status, the address and length of the message, and the
+// exit code
+pub(crate) const TABLE_ENTRY_SIZE: usize = 32;
+
+const ERROR_EXIT_CODE: u64 = 1;
+
+// What the runtime refers to
+#[derive(Default)]
+pub(crate) struct Addresses {
+    pub tape: u64,
+    pub context: u64,
+    pub table: u64,
+    // the status to look up when flushing fails after the code returns normally
+    pub end_key: u64,
+}
+
+// `read` and `flush` are where the callbacks start in `code`, which starts at the entry point
+pub(crate) struct Runtime {
+    pub code: Vec<u8>,
+    pub read: usize,
+    pub flush: usize,
+}
+
+fn runtime(target: Target, addresses: &Addresses) -> Runtime {
+    match target {
+        Target::X86_64 => x86_64::runtime::runtime(addresses),
+        Target::Aarch64 => aarch64::runtime::runtime(addresses),
+    }
+}
+
+fn object_entry(target: Target) -> Vec<u8> {
+    match target {
+        Target::X86_64 => x86_64::runtime::object_entry(),
+        Target::Aarch64 => aarch64::runtime::object_entry(),
+    }
+}
+
+fn machine(target: Target) -> u16 {
+    match target {
+        Target::X86_64 => elf::EM_X86_64,
+        Target::Aarch64 => elf::EM_AARCH64,
+    }
+}
+
+// passes everything to `backend`, and notes every status that the code can return
+struct RecordTraps<'a, B> {
+    backend: B,
+    statuses: &'a mut BTreeSet<u64>,
+}
+
+impl<B: Backend> Backend for RecordTraps<'_, B> {
+    type Label = B::Label;
+
+    fn prologue(&mut self) {
+        self.backend.prologue();
+    }
+    fn epilogue(&mut self) {
+        self.backend.epilogue();
+    }
+    fn add_to_cell(&mut self, amount: Operand) {
+        self.backend.add_to_cell(amount);
+    }
+    fn move_pointer(&mut self, amount: Operand, trap: Option<u64>) {
+        self.statuses.extend(trap);
+        self.backend.move_pointer(amount, trap);
+    }
+    fn trap(&mut self, status: u64) {
+        self.statuses.insert(status);
+        self.backend.trap(status);
+    }
+    fn input(&mut self, io_error: u64) {
+        self.statuses.insert(io_error);
+        self.backend.input(io_error);
+    }
+    fn output(&mut self, not_ascii: u64, io_error: u64) {
+        self.statuses.extend([not_ascii, io_error]);
+        self.backend.output(not_ascii, io_error);
+    }
+    fn loop_head(&mut self) -> Self::Label {
+        self.backend.loop_head()
+    }
+    fn loop_tail(&mut self, head: Self::Label) -> Result<(), &'static str> {
+        self.backend.loop_tail(head)
+    }
+    fn code_len(&self) -> usize {
+        self.backend.code_len()
+    }
+    fn finish(self) -> Result<Vec<u8>, &'static str> {
+        self.backend.finish()
+    }
+}
+
+// the generated code, and the statuses it can return
+fn compile_main(ops: &[Op], target: Target) -> Result<(Vec<u8>, BTreeSet<u64>), JitCompileError> {
+    let mut statuses = BTreeSet::new();
+    let range = 0..ops.len();
+    let code = match target {
+        Target::X86_64 => {
+            let backend = RecordTraps {
+                backend: x86_64::X86_64::default(),
+                statuses: &mut statuses,
+            };
+            compile(backend, ops, range, Some(0))?
+        }
+        Target::Aarch64 => {
+            let backend = RecordTraps {
+                backend: aarch64::Aarch64::default(),
+                statuses: &mut statuses,
+            };
+            compile(backend, ops, range, Some(0))?
+        }
+    };
+    Ok((code.bytes, statuses))
+}
+
+// Each status the runtime may look up, with what it prints to stderr and the exit code. The
+// messages are the interpreter's, without the reason that the OS gives for I/O errors.
+fn messages(ops: &[Op], statuses: &BTreeSet<u64>) -> Vec<(u64, String, u64)> {
+    let end = ops.len();
+    let error = |ip: usize, message: &str| format!("{}\n", RuntimeError::with_ip(ip, message));
+    let mut messages = vec![
+        (0, String::new(), 0),
+        (
+            Trap::Io.status(end) | OTHER_CAUSE,
+            error(end, "cannot write to stdout"),
+            ERROR_EXIT_CODE,
+        ),
+    ];
+    for &status in statuses {
+        let ip = (status >> 8) as usize;
+        let (message, other) = match status & 0xff {
+            s if s == Trap::NegativeDataPointer as u64 => ("data pointer is negative", None),
+            s if s == Trap::DataPointerOverflow as u64 => {
+                ("data pointer exceeded memory size", None)
+            }
+            s if s == Trap::Io as u64 => {
+                let message = match ops[ip].kind {
+                    OpKind::Input => "cannot read from stdin",
+                    _ => "cannot write to stdout",
+                };
+                (message, Some("cannot write to stdout"))
+            }
+            _ => (
+                "the value is not in the ASCII range",
+                Some("cannot reinterpret the byte into char"),
+            ),
+        };
+        messages.push((status, error(ip, message), ERROR_EXIT_CODE));
+        if let Some(other) = other {
+            messages.push((status | OTHER_CAUSE, error(ip, other), ERROR_EXIT_CODE));
+        }
+    }
+    messages
+}
+
+pub fn build_executable(ops: &[Op], target: Target) -> Result<Vec<u8>, JitCompileError> {
+    link(ops, target, BASE)
+}
+
+// The executable, loaded at `base`, which is a multiple of `PAGE_SIZE`. The first segment maps
+// the headers, `.text` and `.rodata`, and the second `.data` and `.bss` on the pages after them.
+fn link(ops: &[Op], target: Target, base: u64) -> Result<Vec<u8>, JitCompileError> {
+    let (main, statuses) = compile_main(ops, target)?;
+    let messages = messages(ops, &statuses);
+    // addresses only change immediates, and not the size of the code
+    let runtime_len = runtime(target, &Addresses::default()).code.len();
+
+    // in the order that `Elf::write` lays the sections out
+    let text_offset = elf::headers_len(2).next_multiple_of(16);
+    let rodata_offset = (text_offset + runtime_len + main.len()).next_multiple_of(8);
+    let table_len = messages.len() * TABLE_ENTRY_SIZE;
+    let rodata_len = table_len + messages.iter().map(|m| m.1.len()).sum::<usize>();
+    let data_offset = (rodata_offset + rodata_len).next_multiple_of(8);
+
+    let text = base + text_offset as u64;
+    let rodata = base + rodata_offset as u64;
+    let data = base + PAGE_SIZE + data_offset as u64;
+    let buffer = data + CONTEXT_SIZE as u64;
+    let tape = buffer + OUTPUT_BUFFER_SIZE as u64;
+    let bss_len = (OUTPUT_BUFFER_SIZE + MEM_SIZE * size_of::<Operand>()) as u64;
+
+    let runtime = runtime(
+        target,
+        &Addresses {
+            tape,
+            context: data,
+            table: rodata,
+            end_key: messages[1].0,
+        },
+    );
+    assert_eq!(runtime.code.len(), runtime_len);
+
+    let mut table = Vec::with_capacity(rodata_len);
+    let mut message_addr = rodata + table_len as u64;
+    for (status, message, exit_code) in &messages {
+        table.extend(status.to_le_bytes());
+        table.extend(message_addr.to_le_bytes());
+        table.extend((message.len() as u64).to_le_bytes());
+        table.extend(exit_code.to_le_bytes());
+        message_addr += message.len() as u64;
+    }
+    for (_, message, _) in &messages {
+        table.extend(message.as_bytes());
+    }
+
+    // read, flush, buffer, len, and the flag
+    let mut context = Vec::with_capacity(CONTEXT_SIZE);
+    for field in [
+        text + runtime.read as u64,
+        text + runtime.flush as u64,
+        buffer,
+        0,
+        0,
+    ] {
+        context.extend(field.to_le_bytes());
+    }
+
+    let mut elf = Elf::new(elf::ET_EXEC, machine(target));
+    let main_len = main.len() as u64;
+    let mut code = runtime.code;
+    code.extend(main);
+    let text_section = elf.add_section(
+        Section::progbits(".text", elf::SHF_ALLOC | elf::SHF_EXECINSTR, code)
+            .at(text)
+            .aligned(16),
+    );
+    elf.add_section(
+        Section::progbits(".rodata", elf::SHF_ALLOC, table)
+            .at(rodata)
+            .aligned(8),
+    );
+    let data_section = elf.add_section(
+        Section::progbits(".data", elf::SHF_ALLOC | elf::SHF_WRITE, context)
+            .at(data)
+            .aligned(8),
+    );
+    let bss_section = elf.add_section(Section::nobits(
+        ".bss",
+        elf::SHF_ALLOC | elf::SHF_WRITE,
+        buffer,
+        bss_len,
+    ));
+    elf.add_segment(Segment {
+        flags: elf::PF_R | elf::PF_X,
+        offset: 0,
+        vaddr: base,
+        filesz: (rodata_offset + rodata_len) as u64,
+        memsz: (rodata_offset + rodata_len) as u64,
+        align: PAGE_SIZE,
+    });
+    elf.add_segment(Segment {
+        flags: elf::PF_R | elf::PF_W,
+        offset: data_offset as u64,
+        vaddr: data,
+        filesz: CONTEXT_SIZE as u64,
+        memsz: CONTEXT_SIZE as u64 + bss_len,
+        align: PAGE_SIZE,
+    });
+    elf.set_entry(text);
+
+    let main_addr = text + runtime_len as u64;
+    let symbols = [
+        ("_start", elf::STT_FUNC, text_section, text, 0),
+        (
+            "bf_read",
+            elf::STT_FUNC,
+            text_section,
+            text + runtime.read as u64,
+            0,
+        ),
+        (
+            "bf_flush",
+            elf::STT_FUNC,
+            text_section,
+            text + runtime.flush as u64,
+            0,
+        ),
+        ("bf_main", elf::STT_FUNC, text_section, main_addr, main_len),
+        (
+            "bf_context",
+            elf::STT_OBJECT,
+            data_section,
+            data,
+            CONTEXT_SIZE as u64,
+        ),
+        (
+            "bf_tape",
+            elf::STT_OBJECT,
+            bss_section,
+            tape,
+            bss_len - OUTPUT_BUFFER_SIZE as u64,
+        ),
+    ];
+    for (name, kind, section, value, size) in symbols {
+        elf.add_symbol(Symbol {
+            name: name.to_string(),
+            kind,
+            global: true,
+            section,
+            value,
+            size,
+        });
+    }
+    Ok(elf.write())
+}
+
+// A relocatable object that exports the generated code as a C function:
+//
+//     bf_exit bf_main(int32_t *tape, bf_io *io);
+//
+// `bf_io` has the fields of `RunContext` that generated code touches, and `bf_exit` is `JitExit`.
+// The code refers to nothing outside itself, so only the debug sections, which come with
+// `source_path`, need relocations.
+pub fn build_object(
+    ops: &[Op],
+    target: Target,
+    source_path: Option<&str>,
+    positions: Option<&[SourcePos]>,
+) -> Result<Vec<u8>, JitCompileError> {
+    let code = emit_code(ops, target)?;
+    let mut text = object_entry(target);
+    let entry_len = text.len();
+    text.extend(&code.bytes);
+
+    let mut elf = Elf::new(elf::ET_REL, machine(target));
+    let text_len = text.len() as u64;
+    let text_section = elf.add_section(
+        Section::progbits(".text", elf::SHF_ALLOC | elf::SHF_EXECINSTR, text).aligned(16),
+    );
+    // which tells linkers that the stack need not be executable
+    elf.add_section(Section::progbits(".note.GNU-stack", 0, Vec::new()));
+    if let (Some(path), Some(positions)) = (source_path, positions) {
+        let dir = std::env::current_dir().unwrap_or_default();
+        let source = Source {
+            path,
+            dir: &dir.to_string_lossy(),
+            positions,
+        };
+        add_debug_sections(
+            &mut elf,
+            text_section,
+            &source,
+            entry_len as u64,
+            &code,
+            Some(target),
+        );
+    }
+    for symbol in code_symbols(ops, &code, positions) {
+        elf.add_symbol(Symbol {
+            name: symbol.name,
+            kind: elf::STT_FUNC,
+            global: false,
+            section: text_section,
+            value: (entry_len + symbol.start) as u64,
+            size: (symbol.end - symbol.start) as u64,
+        });
+    }
+    elf.add_symbol(Symbol {
+        name: "bf_main".to_string(),
+        kind: elf::STT_FUNC,
+        global: true,
+        section: text_section,
+        value: 0,
+        size: text_len,
+    });
+    Ok(elf.write())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate_ops;
+    use crate::jitc::aarch64::emulator::run_process;
+    use crate::jitc::elf::tests::{entry, relocations, sections, segments, symbols};
+    use crate::source_map;
+    use crate::testing::{interpret, programs, Process, HELLO};
+
+    // Maps the segments of an aarch64 executable linked for wherever they land in memory, and
+    // runs it in the emulator
+    fn emulate(ops: &[Op], input: &[u8], stdout_fails: bool) -> Process {
+        let file = link(ops, Target::Aarch64, BASE).unwrap();
+        let span = segments(&file)
+            .iter()
+            .map(|&(_, _, vaddr, _, memsz)| vaddr + memsz - BASE)
+            .max()
+            .unwrap() as usize;
+        let mut memory = vec![0u8; span + PAGE_SIZE as usize];
+        let base = (memory.as_ptr() as u64).next_multiple_of(PAGE_SIZE);
+        let file = link(ops, Target::Aarch64, base).unwrap();
+
+        let start = (base - memory.as_ptr() as u64) as usize;
+        let memory = &mut memory[start..start + span];
+        for (_, offset, vaddr, filesz, _) in segments(&file) {
+            let (offset, at) = (offset as usize, (vaddr - base) as usize);
+            memory[at..at + filesz as usize]
+                .copy_from_slice(&file[offset..offset + filesz as usize]);
+        }
+        let text = sections(&file)
+            .into_iter()
+            .find(|section| section.0 == ".text")
+            .unwrap();
+        let text_start = (text.2 - base) as usize;
+        run_process(
+            memory,
+            text_start..text_start + text.3.len(),
+            entry(&file),
+            input,
+            stdout_fails,
+        )
+    }
+
+    fn assert_same_as_interpreter(program: &str, input: &[u8]) {
+        let ops = generate_ops(program);
+        let expected = interpret(&ops, input).process();
+        assert_eq!(emulate(&ops, input, false), expected, "aarch64: {program}");
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        assert_eq!(
+            native::run(&ops, input, false),
+            expected,
+            "x86_64: {program}"
+        );
+    }
+
+    // a program that prints more than the output buffer holds
+    fn long_output() -> String {
+        format!(
+            "{}>{}[>{}[<<.>>-]<-]",
+            "+".repeat(65),
+            "+".repeat(100),
+            "+".repeat(50)
+        )
+    }
+
+    #[test]
+    fn should_run_like_the_interpreter() {
+        for (program, input) in programs() {
+            assert_same_as_interpreter(&program, input);
+        }
+        assert_same_as_interpreter(",>,>,.<.<.", b"ab");
+        assert_same_as_interpreter(&long_output(), b"");
+    }
+
+    #[test]
+    fn should_exit_with_the_message_of_a_trap() {
+        assert_same_as_interpreter(&">".repeat(MEM_SIZE), b"");
+        assert_same_as_interpreter("+.-.", b"");
+        assert_same_as_interpreter(&format!("{}.", "+".repeat(256)), b"");
+    }
+
+    #[test]
+    fn should_report_failed_writes() {
+        // when the buffer fills up at `.`
+        let ops = generate_ops(&long_output());
+        let ip = ops.iter().position(|op| op.kind == OpKind::Output).unwrap();
+        let expected = format!("{}\n", RuntimeError::with_ip(ip, "cannot write to stdout"));
+        let process = emulate(&ops, b"", true);
+        assert_eq!(String::from_utf8(process.stderr).unwrap(), expected);
+        assert_eq!(process.exit_code, 1);
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        assert_eq!(
+            String::from_utf8(native::run(&ops, b"", true).stderr).unwrap(),
+            expected
+        );
+
+        // at the end, and before reading
+        for (program, ip) in [("+.", 2), ("+.,", 2)] {
+            let ops = generate_ops(program);
+            let expected = format!("{}\n", RuntimeError::with_ip(ip, "cannot write to stdout"));
+            let process = emulate(&ops, b"", true);
+            assert_eq!(String::from_utf8(process.stderr).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn should_lay_out_a_loadable_executable() {
+        let ops = generate_ops("+[>+<-].");
+        for target in [Target::X86_64, Target::Aarch64] {
+            let file = build_executable(&ops, target).unwrap();
+            let segments = segments(&file);
+            assert_eq!(segments.len(), 2);
+            // every section is where its segment maps it
+            for (name, kind, addr, bytes) in sections(&file) {
+                if addr == 0 || kind != elf::SHT_PROGBITS {
+                    continue;
+                }
+                let &(_, offset, vaddr, _, _) = segments
+                    .iter()
+                    .find(|s| (s.2..s.2 + s.3).contains(&addr))
+                    .unwrap_or_else(|| panic!("{name} is not loaded"));
+                let offset = (offset + addr - vaddr) as usize;
+                assert_eq!(file[offset..offset + bytes.len()], bytes, "{name}");
+            }
+
+            let symbols = symbols(&file);
+            let symbol = |name: &str| symbols.iter().find(|s| s.0 == name).unwrap().clone();
+            assert_eq!(symbol("_start").2, entry(&file));
+            let tape = symbol("bf_tape");
+            assert_eq!(tape.3, (MEM_SIZE * size_of::<Operand>()) as u64);
+            let (flags, _, vaddr, _, memsz) = segments[1];
+            assert_eq!(flags, elf::PF_R | elf::PF_W);
+            assert_eq!(tape.2 + tape.3, vaddr + memsz);
+        }
+    }
+
+    #[test]
+    fn should_emit_only_known_aarch64_instructions() {
+        let file = build_executable(&generate_ops(&long_output()), Target::Aarch64).unwrap();
+        let text = sections(&file)
+            .into_iter()
+            .find(|s| s.0 == ".text")
+            .unwrap();
+        for (addr, _, text) in aarch64::disasm::disassemble(&text.3) {
+            assert!(!text.starts_with(".word"), "{text} at {addr:#x}");
+        }
+    }
+
+    #[test]
+    fn should_export_main_from_an_object() {
+        let input = "+\n[>+<-]";
+        let ops = generate_ops(input);
+        let positions = source_map(input);
+        for target in [Target::X86_64, Target::Aarch64] {
+            let entry_len = object_entry(target).len();
+            let file = build_object(&ops, target, Some("a.bf"), Some(&positions)).unwrap();
+            let text = sections(&file)
+                .into_iter()
+                .find(|s| s.0 == ".text")
+                .unwrap();
+            let code = emit_code(&ops, target).unwrap();
+            assert_eq!(text.3[entry_len..], code.bytes);
+            let symbols = symbols(&file);
+            assert_eq!(
+                symbols.last().unwrap(),
+                &("bf_main".to_string(), 1, 0, text.3.len() as u64)
+            );
+            let loop_symbol = symbols.iter().find(|s| s.0 == "bf_loop@2:1").unwrap();
+            assert_eq!(loop_symbol.2, (entry_len + code.op_offsets[1]) as u64);
+
+            // low_pc and the line program start after the entry, wherever `.text` ends up
+            let (info, line) = (
+                relocations(&file, ".debug_info"),
+                relocations(&file, ".debug_line"),
+            );
+            assert_eq!(info.len(), 3);
+            assert_eq!(info[2].2, 1);
+            assert_eq!(info[2].3, entry_len as i64);
+            assert_eq!(line.len(), 1);
+            assert_eq!(line[0].2, 1);
+        }
+
+        // without a source, there is nothing to relocate
+        let file = build_object(&ops, Target::X86_64, None, None).unwrap();
+        assert!(sections(&file).iter().all(|s| !s.0.starts_with(".rela")));
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    #[test]
+    fn should_link_an_object_into_a_c_program() {
+        for (program, input) in [
+            (HELLO, &b""[..]),
+            (",[.,]", b"echo"),
+            (&long_output(), b""),
+            ("+.<", b""),
+            ("+[>+]", b""),
+        ] {
+            let ops = generate_ops(program);
+            let Some((stdout, status)) = native::run_linked(&ops, input) else {
+                // no C compiler
+                return;
+            };
+            let expected = interpret(&ops, input).process();
+            assert_eq!(stdout, expected.stdout, "{program}");
+            match status {
+                0 => assert_eq!(expected.exit_code, 0, "{program}"),
+                _ => {
+                    let stderr = String::from_utf8(expected.stderr).unwrap();
+                    assert!(
+                        stderr.contains(&format!("[IP:{}]", status >> 8)),
+                        "{program}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    mod native {
+        use super::*;
+        use crate::testing::{run_command, TempDir};
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+        use std::process::Command;
+
+        // builds an executable in a temporary directory, and runs it with `input`, and stdout
+        // going to /dev/full when `stdout_fails`
+        pub fn run(ops: &[Op], input: &[u8], stdout_fails: bool) -> Process {
+            let dir = TempDir::new("aot");
+            let path = dir.path().join("a");
+            fs::write(&path, build_executable(ops, Target::X86_64).unwrap()).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            run_command(&mut Command::new(&path), input, stdout_fails)
+        }
+
+        // calls `bf_main` as the README shows, and prints the status to stderr
+        const HARNESS: &str = r#"
+            #include <stdint.h>
+            #include <stdio.h>
+
+            typedef struct bf_io {
+                int (*read)(struct bf_io *io, int32_t *cell);
+                int (*flush)(struct bf_io *io);
+                uint8_t *buffer;
+                size_t len;
+            } bf_io;
+            typedef struct { uint64_t status, dp; } bf_exit;
+            bf_exit bf_main(int32_t *tape, bf_io *io);
+
+            static int flush(bf_io *io) {
+                size_t written = fwrite(io->buffer, 1, io->len, stdout);
+                int failed = written != io->len;
+                io->len = 0;
+                return failed;
+            }
+            static int read_cell(bf_io *io, int32_t *cell) {
+                if (flush(io)) return 1;
+                int c = getchar();
+                *cell = c == EOF ? 0 : c;
+                return 0;
+            }
+            static int32_t tape[65536];
+            static uint8_t buffer[4096];
+            int main(void) {
+                bf_io io = { read_cell, flush, buffer, 0 };
+                bf_exit result = bf_main(tape, &io);
+                flush(&io);
+                fprintf(stderr, "%llu", (unsigned long long)result.status);
+                return 0;
+            }
+        "#;
+
+        // stdout and the status of `bf_main` in a C program, unless there is no C compiler
+        pub fn run_linked(ops: &[Op], input: &[u8]) -> Option<(Vec<u8>, u64)> {
+            let dir = TempDir::new("obj");
+            fs::write(dir.path().join("main.c"), HARNESS).unwrap();
+            let object = build_object(ops, Target::X86_64, None, None).unwrap();
+            fs::write(dir.path().join("bf.o"), object).unwrap();
+            let compiled = Command::new("cc")
+                .current_dir(dir.path())
+                .args(["main.c", "bf.o", "-o", "main"])
+                .status()
+                .ok()?;
+            assert!(compiled.success());
+
+            let process = run_command(&mut Command::new(dir.path().join("main")), input, false);
+            let status = String::from_utf8(process.stderr).unwrap().parse().unwrap();
+            Some((process.stdout, status))
+        }
+    }
+}
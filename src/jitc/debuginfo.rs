@@ -0,0 +1,498 @@
+// What profilers and debuggers need to know about generated code: names for its parts, and an
+// object file that maps it back to the source
+use super::elf::*;
+use super::{MachineCode, Target};
+use crate::ir::SourcePos;
+use crate::op::*;
+
+// A named range of generated code, in bytes from its start
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodeSymbol {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+// The prologue, each top-level loop, the straight-line code between them, and the epilogue with
+// the trap exits. Names tell where the code comes from: the position in the source when there is
+// one, and the ip otherwise.
+pub fn code_symbols(
+    ops: &[Op],
+    code: &MachineCode,
+    positions: Option<&[SourcePos]>,
+) -> Vec<CodeSymbol> {
+    let origin = |ip: usize| match positions.and_then(|p| p.get(ip)) {
+        Some(SourcePos { line, column }) => format!("{line}:{column}"),
+        None => format!("ip{ip}"),
+    };
+    let offsets = &code.op_offsets;
+    let mut symbols = vec![CodeSymbol {
+        name: "bf_prologue".to_string(),
+        start: 0,
+        end: offsets[0],
+    }];
+    let mut ip = 0;
+    while ip < ops.len() {
+        let first = ip;
+        let name = if ops[ip].kind == OpKind::Jeq0Forward {
+            let mut depth = 0;
+            while ip < ops.len() {
+                match ops[ip].kind {
+                    OpKind::Jeq0Forward => depth += 1,
+                    OpKind::Jne0Backward => depth -= 1,
+                    _ => (),
+                }
+                ip += 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            format!("bf_loop@{}", origin(first))
+        } else {
+            while ip < ops.len() && ops[ip].kind != OpKind::Jeq0Forward {
+                ip += 1;
+            }
+            format!("bf_ops@{}", origin(first))
+        };
+        symbols.push(CodeSymbol {
+            name,
+            start: offsets[first],
+            end: offsets[ip],
+        });
+    }
+    symbols.push(CodeSymbol {
+        name: "bf_epilogue".to_string(),
+        start: offsets[ops.len()],
+        end: code.bytes.len(),
+    });
+    // ops such as `,` with a zero count have no code
+    symbols.retain(|symbol| symbol.start < symbol.end);
+    symbols
+}
+
+// lines for `/tmp/perf-<pid>.map`, for code that is loaded at `base`
+pub fn perf_map(base: usize, symbols: &[CodeSymbol]) -> String {
+    symbols
+        .iter()
+        .map(|symbol| {
+            format!(
+                "{:x} {:x} {}\n",
+                base + symbol.start,
+                symbol.end - symbol.start,
+                symbol.name
+            )
+        })
+        .collect()
+}
+
+// The source file of some code, relative to `dir` unless it is absolute, and the position of each
+// op in it
+pub struct Source<'a> {
+    pub path: &'a str,
+    pub dir: &'a str,
+    pub positions: &'a [SourcePos],
+}
+
+// A relocatable object that describes code which is already loaded at `base`: `.text` takes no
+// room in the file, and has the symbols, and, with `source`, a DWARF line table that maps the
+// code of each op to its line and column.
+pub fn debug_object(
+    target: Target,
+    base: usize,
+    code: &MachineCode,
+    symbols: &[CodeSymbol],
+    source: Option<Source>,
+) -> Vec<u8> {
+    let machine = match target {
+        Target::X86_64 => EM_X86_64,
+        Target::Aarch64 => EM_AARCH64,
+    };
+    let mut elf = Elf::new(ET_REL, machine);
+    let len = code.bytes.len() as u64;
+    let text = elf.add_section(Section::nobits(
+        ".text",
+        SHF_ALLOC | SHF_EXECINSTR,
+        base as u64,
+        len,
+    ));
+    if let Some(source) = source {
+        add_debug_sections(&mut elf, text, &source, base as u64, code, None);
+    }
+    for symbol in symbols {
+        elf.add_symbol(Symbol {
+            name: symbol.name.clone(),
+            kind: STT_FUNC,
+            global: false,
+            section: text,
+            value: symbol.start as u64,
+            size: (symbol.end - symbol.start) as u64,
+        });
+    }
+    elf.write()
+}
+
+// The file symbol, and `.debug_abbrev`, `.debug_info` and `.debug_line` for the code in `text`,
+// which is at `base`. With `relocate`, where code in a relocatable file has no address yet, the
+// sections also get relocations for the target wherever they refer to another section.
+pub fn add_debug_sections(
+    elf: &mut Elf,
+    text: u16,
+    source: &Source,
+    base: u64,
+    code: &MachineCode,
+    relocate: Option<Target>,
+) {
+    elf.add_symbol(Symbol {
+        name: source.path.to_string(),
+        kind: STT_FILE,
+        global: false,
+        section: SHN_ABS,
+        value: 0,
+        size: 0,
+    });
+    let abbrev = elf.add_section(Section::progbits(".debug_abbrev", 0, debug_abbrev()));
+    let (info, info_references) = debug_info(source, base, code.bytes.len() as u64);
+    let (line, line_references) = debug_line(source, base, code);
+    let info_section = elf.add_section(Section::progbits(".debug_info", 0, info.clone()));
+    let line_section = elf.add_section(Section::progbits(".debug_line", 0, line.clone()));
+
+    let Some(target) = relocate else {
+        return;
+    };
+    let relocations = |data: &[u8], references: Vec<Reference>| {
+        (references.into_iter())
+            .map(|Reference { offset, to, size }| {
+                // what is there already is relative to the section
+                let mut addend = [0; 8];
+                addend[..size].copy_from_slice(&data[offset..offset + size]);
+                Relocation {
+                    offset: offset as u64,
+                    kind: match (target, size) {
+                        (Target::X86_64, 8) => R_X86_64_64,
+                        (Target::X86_64, _) => R_X86_64_32,
+                        (Target::Aarch64, 8) => R_AARCH64_ABS64,
+                        (Target::Aarch64, _) => R_AARCH64_ABS32,
+                    },
+                    section: match to {
+                        Refers::Text => text,
+                        Refers::Abbrev => abbrev,
+                        Refers::Line => line_section,
+                    },
+                    addend: i64::from_le_bytes(addend),
+                }
+            })
+            .collect()
+    };
+    elf.add_relocations(info_section, relocations(&info, info_references));
+    elf.add_relocations(line_section, relocations(&line, line_references));
+}
+
+enum Refers {
+    Text,
+    Abbrev,
+    Line,
+}
+
+// `size` bytes at `offset` in a DWARF section that hold an address in, or an offset in, another
+// section
+struct Reference {
+    offset: usize,
+    to: Refers,
+    size: usize,
+}
+
+const DW_TAG_COMPILE_UNIT: u64 = 0x11;
+const DW_CHILDREN_NO: u8 = 0;
+const DW_AT_NAME: u64 = 0x03;
+const DW_AT_STMT_LIST: u64 = 0x10;
+const DW_AT_LOW_PC: u64 = 0x11;
+const DW_AT_HIGH_PC: u64 = 0x12;
+const DW_AT_COMP_DIR: u64 = 0x1b;
+const DW_FORM_ADDR: u64 = 0x01;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_SEC_OFFSET: u64 = 0x17;
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_COLUMN: u8 = 5;
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+
+// the number of operands of each standard opcode, from DW_LNS_copy to DW_LNS_set_isa
+const STANDARD_OPCODE_LENGTHS: [u8; 12] = [0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+
+const DWARF_VERSION: u16 = 4;
+
+fn uleb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb128(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn string(out: &mut Vec<u8>, string: &str) {
+    out.extend(string.as_bytes());
+    out.push(0);
+}
+
+// `body` after its 32-bit length, as DWARF units are
+fn unit(body: Vec<u8>) -> Vec<u8> {
+    let mut unit = (body.len() as u32).to_le_bytes().to_vec();
+    unit.extend(body);
+    unit
+}
+
+// a single compile unit without children, which is all `debug_info` describes
+fn debug_abbrev() -> Vec<u8> {
+    let mut abbrev = Vec::new();
+    uleb128(&mut abbrev, 1);
+    uleb128(&mut abbrev, DW_TAG_COMPILE_UNIT);
+    abbrev.push(DW_CHILDREN_NO);
+    for (attribute, form) in [
+        (DW_AT_NAME, DW_FORM_STRING),
+        (DW_AT_COMP_DIR, DW_FORM_STRING),
+        (DW_AT_STMT_LIST, DW_FORM_SEC_OFFSET),
+        (DW_AT_LOW_PC, DW_FORM_ADDR),
+        (DW_AT_HIGH_PC, DW_FORM_DATA8),
+    ] {
+        uleb128(&mut abbrev, attribute);
+        uleb128(&mut abbrev, form);
+    }
+    abbrev.extend([0, 0, 0]);
+    abbrev
+}
+
+// with offsets in the unit, after its length
+fn debug_info(source: &Source, base: u64, len: u64) -> (Vec<u8>, Vec<Reference>) {
+    let mut references = Vec::new();
+    let mut refer = |info: &Vec<u8>, to, size| {
+        references.push(Reference {
+            offset: 4 + info.len(),
+            to,
+            size,
+        })
+    };
+    let mut info = DWARF_VERSION.to_le_bytes().to_vec();
+    refer(&info, Refers::Abbrev, 4);
+    info.extend(0u32.to_le_bytes()); // offset in .debug_abbrev
+    info.push(8); // address size
+    uleb128(&mut info, 1);
+    string(&mut info, source.path);
+    string(&mut info, source.dir);
+    refer(&info, Refers::Line, 4);
+    info.extend(0u32.to_le_bytes()); // offset in .debug_line
+    refer(&info, Refers::Text, 8);
+    info.extend(base.to_le_bytes());
+    info.extend(len.to_le_bytes()); // high_pc as data is the size
+    (unit(info), references)
+}
+
+// A row for each op with code of its own, up to the epilogue, which maps to no line
+fn debug_line(source: &Source, base: u64, code: &MachineCode) -> (Vec<u8>, Vec<Reference>) {
+    let offsets = &code.op_offsets;
+    let mut program = Vec::new();
+    let (mut address, mut line, mut column) = (None, 1, 0);
+    let mut set_address = None; // in `program`
+    for (ip, pos) in source.positions.iter().enumerate() {
+        if ip + 1 >= offsets.len() || offsets[ip] == offsets[ip + 1] {
+            continue;
+        }
+        let offset = offsets[ip] as u64;
+        match address {
+            None => {
+                program.extend([0, 9, DW_LNE_SET_ADDRESS]);
+                set_address = Some(program.len());
+                program.extend((base + offset).to_le_bytes());
+            }
+            Some(address) => {
+                program.push(DW_LNS_ADVANCE_PC);
+                uleb128(&mut program, offset - address);
+            }
+        }
+        if pos.line != line {
+            program.push(DW_LNS_ADVANCE_LINE);
+            sleb128(&mut program, pos.line as i64 - line as i64);
+        }
+        if pos.column != column {
+            program.push(DW_LNS_SET_COLUMN);
+            uleb128(&mut program, pos.column as u64);
+        }
+        program.push(DW_LNS_COPY);
+        (address, line, column) = (Some(offset), pos.line, pos.column);
+    }
+    if let Some(address) = address {
+        program.push(DW_LNS_ADVANCE_PC);
+        uleb128(&mut program, offsets[offsets.len() - 1] as u64 - address);
+        program.extend([0, 1, DW_LNE_END_SEQUENCE]);
+    }
+
+    let mut header = vec![
+        1,                                       // minimum instruction length
+        1,                                       // maximum operations per instruction
+        1,                                       // default is_stmt
+        -5i8 as u8,                              // line base
+        14,                                      // line range
+        STANDARD_OPCODE_LENGTHS.len() as u8 + 1, // opcode base
+    ];
+    header.extend(STANDARD_OPCODE_LENGTHS);
+    header.push(0); // no include directories but the compilation one
+    string(&mut header, source.path);
+    header.extend([0, 0, 0]); // directory, modification time and size
+    header.push(0);
+
+    let mut line = DWARF_VERSION.to_le_bytes().to_vec();
+    line.extend((header.len() as u32).to_le_bytes());
+    line.extend(header);
+    let references = set_address.map(|offset| Reference {
+        offset: 4 + line.len() + offset,
+        to: Refers::Text,
+        size: 8,
+    });
+    line.extend(program);
+    (unit(line), references.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::elf::tests::{sections, symbols};
+    use super::*;
+    use crate::generate_ops;
+    use crate::ir::source_map;
+    use crate::jitc::emit_code;
+
+    #[test]
+    fn should_name_top_level_loops() {
+        let input = "++\n[->[-]<]>.\n,[.,]";
+        let ops = generate_ops(input);
+        let code = emit_code(&ops, Target::Aarch64).unwrap();
+        let positions = source_map(input);
+        let symbols = code_symbols(&ops, &code, Some(&positions));
+
+        let names: Vec<&str> = symbols.iter().map(|s| &s.name[..]).collect();
+        assert_eq!(
+            names,
+            [
+                "bf_prologue",
+                "bf_ops@1:1",
+                "bf_loop@2:1",
+                "bf_ops@2:9",
+                "bf_loop@3:2",
+                "bf_epilogue"
+            ]
+        );
+        // the symbols cover the code without gaps
+        assert_eq!(symbols[0].start, 0);
+        for pair in symbols.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start);
+        }
+        assert_eq!(symbols[5].end, code.bytes.len());
+        assert_eq!(symbols[2].start, code.op_offsets[1]);
+
+        let names = code_symbols(&ops, &code, None);
+        assert_eq!(names[2].name, "bf_loop@ip1");
+    }
+
+    #[test]
+    fn should_write_perf_map_lines() {
+        let symbols = [CodeSymbol {
+            name: "bf_loop@1:2".to_string(),
+            start: 0x20,
+            end: 0x48,
+        }];
+        assert_eq!(perf_map(0x7f00, &symbols), "7f20 28 bf_loop@1:2\n");
+    }
+
+    #[test]
+    fn should_encode_leb128() {
+        let mut out = Vec::new();
+        uleb128(&mut out, 624485);
+        assert_eq!(out, [0xe5, 0x8e, 0x26]);
+        out.clear();
+        sleb128(&mut out, -123456);
+        assert_eq!(out, [0xc0, 0xbb, 0x78]);
+        out.clear();
+        sleb128(&mut out, 64);
+        assert_eq!(out, [0xc0, 0x00]);
+    }
+
+    #[test]
+    fn should_describe_loaded_code() {
+        let input = "+\n[-]";
+        let ops = generate_ops(input);
+        let code = emit_code(&ops, Target::X86_64).unwrap();
+        let symbols = code_symbols(&ops, &code, None);
+        let positions = source_map(input);
+        let source = Source {
+            path: "a.bf",
+            dir: "/src",
+            positions: &positions,
+        };
+        let object = debug_object(Target::X86_64, 0x10000, &code, &symbols, Some(source));
+
+        let sections = sections(&object);
+        let names: Vec<&str> = sections.iter().map(|s| &s.0[..]).collect();
+        assert_eq!(
+            names,
+            [
+                "",
+                ".text",
+                ".debug_abbrev",
+                ".debug_info",
+                ".debug_line",
+                ".symtab",
+                ".strtab",
+                ".shstrtab"
+            ]
+        );
+        assert_eq!(sections[1].2, 0x10000);
+        let loop_symbol = symbols.iter().find(|s| s.name == "bf_loop@ip1").unwrap();
+        assert!(super::super::elf::tests::symbols(&object).contains(&(
+            "bf_loop@ip1".to_string(),
+            1,
+            loop_symbol.start as u64,
+            (loop_symbol.end - loop_symbol.start) as u64
+        )));
+
+        // the line program starts at the first op and moves to line 2 for the loop
+        let line = &sections[4].3;
+        let mut expected = vec![0, 9, DW_LNE_SET_ADDRESS];
+        expected.extend((0x10000 + code.op_offsets[0] as u64).to_le_bytes());
+        expected.extend([DW_LNS_SET_COLUMN, 1, DW_LNS_COPY, DW_LNS_ADVANCE_PC]);
+        uleb128(
+            &mut expected,
+            (code.op_offsets[1] - code.op_offsets[0]) as u64,
+        );
+        expected.extend([DW_LNS_ADVANCE_LINE, 1, DW_LNS_COPY]);
+        assert!(line.windows(expected.len()).any(|w| w == expected));
+        assert!(line.ends_with(&[0, 1, DW_LNE_END_SEQUENCE]));
+    }
+
+    #[test]
+    fn should_leave_out_line_info_without_source() {
+        let ops = generate_ops("+");
+        let code = emit_code(&ops, Target::Aarch64).unwrap();
+        let object = debug_object(Target::Aarch64, 0, &code, &[], None);
+        assert_eq!(u16::from_le_bytes([object[0x12], object[0x13]]), EM_AARCH64);
+        assert_eq!(symbols(&object), []);
+        assert_eq!(sections(&object).len(), 5);
+    }
+}
@@ -0,0 +1,576 @@
+// Just enough of ELF64 (little-endian) to describe generated code to other tools, to link it, or
+// to load it: sections, a symbol table over them, relocations, and segments
+
+pub const ET_REL: u16 = 1;
+pub const ET_EXEC: u16 = 2;
+pub const EM_X86_64: u16 = 62;
+pub const EM_AARCH64: u16 = 183;
+
+pub const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+pub const SHT_NOBITS: u32 = 8;
+
+pub const SHF_WRITE: u64 = 0x1;
+pub const SHF_ALLOC: u64 = 0x2;
+pub const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+pub const SHN_ABS: u16 = 0xfff1;
+
+pub const STT_OBJECT: u8 = 1;
+pub const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+pub const STT_FILE: u8 = 4;
+
+// the address of the symbol plus the addend, in 64 or 32 bits
+pub const R_X86_64_64: u32 = 1;
+pub const R_X86_64_32: u32 = 10;
+pub const R_AARCH64_ABS64: u32 = 257;
+pub const R_AARCH64_ABS32: u32 = 258;
+
+pub const PT_LOAD: u32 = 1;
+
+pub const PF_X: u32 = 0x1;
+pub const PF_W: u32 = 0x2;
+pub const PF_R: u32 = 0x4;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+const RELA_SIZE: usize = 24;
+
+// Where the data of the first section goes, after the ELF header and `segments` program headers.
+// Each section follows the previous one at the next multiple of its alignment.
+pub fn headers_len(segments: usize) -> usize {
+    EHDR_SIZE + segments * PHDR_SIZE
+}
+
+pub struct Section {
+    name: String,
+    kind: u32,
+    flags: u64,
+    addr: u64,
+    size: u64,
+    align: u64,
+    info: u32,
+    data: Vec<u8>,
+}
+
+impl Section {
+    pub fn progbits(name: &str, flags: u64, data: Vec<u8>) -> Self {
+        Section {
+            name: name.to_string(),
+            kind: SHT_PROGBITS,
+            flags,
+            addr: 0,
+            size: data.len() as u64,
+            align: 1,
+            info: 0,
+            data,
+        }
+    }
+
+    // memory at `addr` that the file only describes, such as code that is already loaded
+    pub fn nobits(name: &str, flags: u64, addr: u64, size: u64) -> Self {
+        Section {
+            name: name.to_string(),
+            kind: SHT_NOBITS,
+            flags,
+            addr,
+            size,
+            align: 1,
+            info: 0,
+            data: Vec::new(),
+        }
+    }
+
+    // where the section is loaded
+    pub fn at(self, addr: u64) -> Self {
+        Section { addr, ..self }
+    }
+
+    pub fn aligned(self, align: u64) -> Self {
+        Section { align, ..self }
+    }
+}
+
+// A `PT_LOAD` segment, which maps `filesz` bytes of the file from `offset` to `vaddr`, and zeroes
+// the rest up to `memsz`
+pub struct Segment {
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+// Where linking writes the address of `section` plus `addend`, in a relocatable file
+pub struct Relocation {
+    pub offset: u64,
+    pub kind: u32,
+    pub section: u16,
+    pub addend: i64,
+}
+
+// `value` is relative to the start of `section` in a relocatable file
+pub struct Symbol {
+    pub name: String,
+    pub kind: u8,
+    pub global: bool,
+    pub section: u16,
+    pub value: u64,
+    pub size: u64,
+}
+
+pub struct Elf {
+    kind: u16,
+    machine: u16,
+    entry: u64,
+    segments: Vec<Segment>,
+    sections: Vec<Section>,
+    symbols: Vec<Symbol>,
+    relocations: Vec<(u16, Vec<Relocation>)>, // and the section they patch
+}
+
+impl Elf {
+    pub fn new(kind: u16, machine: u16) -> Self {
+        Elf {
+            kind,
+            machine,
+            entry: 0,
+            segments: Vec::new(),
+            sections: Vec::new(),
+            symbols: Vec::new(),
+            relocations: Vec::new(),
+        }
+    }
+
+    pub fn set_entry(&mut self, entry: u64) {
+        self.entry = entry;
+    }
+
+    pub fn add_segment(&mut self, segment: Segment) {
+        self.segments.push(segment);
+    }
+
+    // the index of the section in the header table, for symbols to refer to
+    pub fn add_section(&mut self, section: Section) -> u16 {
+        self.sections.push(section);
+        self.sections.len() as u16
+    }
+
+    pub fn add_symbol(&mut self, symbol: Symbol) {
+        self.symbols.push(symbol);
+    }
+
+    // `relocations` patch `section` when it is linked
+    pub fn add_relocations(&mut self, section: u16, relocations: Vec<Relocation>) {
+        self.relocations.push((section, relocations));
+    }
+
+    pub fn write(&self) -> Vec<u8> {
+        // relocations refer to sections through a symbol for each, which comes first with the other
+        // locals, and `sh_info` of the symbol table is the first global
+        let mut targets: Vec<u16> = (self.relocations.iter())
+            .flat_map(|(_, relocations)| relocations.iter().map(|r| r.section))
+            .collect();
+        targets.sort();
+        targets.dedup();
+        let section_symbols: Vec<Symbol> = targets
+            .iter()
+            .map(|&section| Symbol {
+                name: String::new(),
+                kind: STT_SECTION,
+                global: false,
+                section,
+                value: 0,
+                size: 0,
+            })
+            .collect();
+        let mut symbols: Vec<&Symbol> = section_symbols.iter().chain(&self.symbols).collect();
+        symbols.sort_by_key(|symbol| symbol.global);
+        let first_global = 1 + symbols.iter().filter(|symbol| !symbol.global).count();
+
+        let mut strtab = vec![0];
+        let mut symtab = vec![0; SYM_SIZE];
+        for symbol in symbols {
+            symtab.extend((add_string(&mut strtab, &symbol.name)).to_le_bytes());
+            symtab.push((symbol.global as u8) << 4 | symbol.kind);
+            symtab.push(0);
+            symtab.extend(symbol.section.to_le_bytes());
+            symtab.extend(symbol.value.to_le_bytes());
+            symtab.extend(symbol.size.to_le_bytes());
+        }
+
+        let relas = self.relocations.iter().map(|(section, relocations)| {
+            let mut data = Vec::with_capacity(relocations.len() * RELA_SIZE);
+            for relocation in relocations {
+                let symbol = 1 + targets.binary_search(&relocation.section).unwrap() as u64;
+                data.extend(relocation.offset.to_le_bytes());
+                data.extend((symbol << 32 | relocation.kind as u64).to_le_bytes());
+                data.extend(relocation.addend.to_le_bytes());
+            }
+            let name = format!(".rela{}", self.sections[*section as usize - 1].name);
+            Section {
+                kind: SHT_RELA,
+                info: *section as u32,
+                ..Section::progbits(&name, SHF_INFO_LINK, data).aligned(8)
+            }
+        });
+        let relas: Vec<Section> = relas.collect();
+
+        let symtab_index = self.sections.len() + relas.len() + 1;
+        let tables = [
+            Section {
+                kind: SHT_SYMTAB,
+                ..Section::progbits(".symtab", 0, symtab).aligned(8)
+            },
+            Section {
+                kind: SHT_STRTAB,
+                ..Section::progbits(".strtab", 0, strtab)
+            },
+            Section {
+                kind: SHT_STRTAB,
+                ..Section::progbits(".shstrtab", 0, Vec::new())
+            },
+        ];
+        let sections: Vec<&Section> = self.sections.iter().chain(&relas).chain(&tables).collect();
+
+        let mut shstrtab = vec![0];
+        let names: Vec<u32> = sections
+            .iter()
+            .map(|section| add_string(&mut shstrtab, &section.name))
+            .collect();
+
+        let mut file = vec![0; headers_len(self.segments.len())];
+        for (idx, segment) in self.segments.iter().enumerate() {
+            let mut header = Vec::with_capacity(PHDR_SIZE);
+            header.extend(PT_LOAD.to_le_bytes());
+            header.extend(segment.flags.to_le_bytes());
+            header.extend(segment.offset.to_le_bytes());
+            header.extend(segment.vaddr.to_le_bytes());
+            header.extend(segment.vaddr.to_le_bytes()); // physical address
+            header.extend(segment.filesz.to_le_bytes());
+            header.extend(segment.memsz.to_le_bytes());
+            header.extend(segment.align.to_le_bytes());
+            let offset = EHDR_SIZE + idx * PHDR_SIZE;
+            file[offset..offset + PHDR_SIZE].copy_from_slice(&header);
+        }
+
+        let mut headers = vec![0; SHDR_SIZE];
+        for (idx, (section, name)) in sections.iter().zip(names).enumerate() {
+            let data = match &section.name[..] {
+                ".shstrtab" => &shstrtab,
+                _ => &section.data,
+            };
+            let align = section.align as usize;
+            file.resize(file.len().next_multiple_of(align), 0);
+            let offset = file.len() as u64;
+            file.extend(data);
+
+            let size = match section.kind {
+                SHT_NOBITS => section.size,
+                _ => data.len() as u64,
+            };
+            let (link, info, entsize) = match section.kind {
+                SHT_SYMTAB => (
+                    symtab_index as u32 + 1,
+                    first_global as u32,
+                    SYM_SIZE as u64,
+                ),
+                SHT_RELA => (symtab_index as u32, section.info, RELA_SIZE as u64),
+                _ => (0, 0, 0),
+            };
+            headers.extend(name.to_le_bytes());
+            headers.extend(section.kind.to_le_bytes());
+            headers.extend(section.flags.to_le_bytes());
+            headers.extend(section.addr.to_le_bytes());
+            headers.extend(offset.to_le_bytes());
+            headers.extend(size.to_le_bytes());
+            headers.extend(link.to_le_bytes());
+            headers.extend(info.to_le_bytes());
+            headers.extend((align as u64).to_le_bytes());
+            headers.extend(entsize.to_le_bytes());
+            debug_assert_eq!(headers.len(), (idx + 2) * SHDR_SIZE);
+        }
+        file.resize(file.len().next_multiple_of(8), 0);
+        let shoff = file.len() as u64;
+        file.extend(headers);
+
+        let mut header = Vec::with_capacity(EHDR_SIZE);
+        header.extend(b"\x7fELF");
+        header.extend([2, 1, 1]); // 64-bit, little-endian, version 1
+        header.resize(16, 0);
+        header.extend(self.kind.to_le_bytes());
+        header.extend(self.machine.to_le_bytes());
+        header.extend(1u32.to_le_bytes());
+        header.extend(self.entry.to_le_bytes());
+        let phoff = if self.segments.is_empty() {
+            0
+        } else {
+            EHDR_SIZE
+        };
+        header.extend((phoff as u64).to_le_bytes());
+        header.extend(shoff.to_le_bytes());
+        header.extend(0u32.to_le_bytes()); // flags
+        header.extend((EHDR_SIZE as u16).to_le_bytes());
+        header.extend((PHDR_SIZE as u16).to_le_bytes());
+        header.extend((self.segments.len() as u16).to_le_bytes());
+        header.extend((SHDR_SIZE as u16).to_le_bytes());
+        header.extend((sections.len() as u16 + 1).to_le_bytes());
+        header.extend((sections.len() as u16).to_le_bytes()); // .shstrtab is the last
+        file[..EHDR_SIZE].copy_from_slice(&header);
+        file
+    }
+}
+
+// the offset of `string` in `table`
+fn add_string(table: &mut Vec<u8>, string: &str) -> u32 {
+    let offset = table.len() as u32;
+    table.extend(string.as_bytes());
+    table.push(0);
+    offset
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn u16_at(file: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(file[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(file: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(file[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(file: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(file[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn string_at(file: &[u8], offset: usize) -> String {
+        let len = file[offset..].iter().position(|&b| b == 0).unwrap();
+        String::from_utf8(file[offset..offset + len].to_vec()).unwrap()
+    }
+
+    // name, type, address, and the section's bytes in the file
+    pub(crate) fn sections(file: &[u8]) -> Vec<(String, u32, u64, Vec<u8>)> {
+        let shoff = u64_at(file, 0x28) as usize;
+        let count = u16_at(file, 0x3c) as usize;
+        let header = |idx: usize| shoff + idx * SHDR_SIZE;
+        let shstrtab = u64_at(file, header(u16_at(file, 0x3e) as usize) + 24) as usize;
+        (0..count)
+            .map(|idx| {
+                let header = header(idx);
+                let kind = u32_at(file, header + 4);
+                let offset = u64_at(file, header + 24) as usize;
+                let size = match kind {
+                    SHT_NOBITS => 0,
+                    _ => u64_at(file, header + 32) as usize,
+                };
+                (
+                    string_at(file, shstrtab + u32_at(file, header) as usize),
+                    kind,
+                    u64_at(file, header + 16),
+                    file[offset..offset + size].to_vec(),
+                )
+            })
+            .collect()
+    }
+
+    // flags, offset, address, size in the file and size in memory of each segment
+    pub(crate) fn segments(file: &[u8]) -> Vec<(u32, u64, u64, u64, u64)> {
+        let phoff = u64_at(file, 0x20) as usize;
+        let count = u16_at(file, 0x38) as usize;
+        (0..count)
+            .map(|idx| {
+                let header = phoff + idx * PHDR_SIZE;
+                assert_eq!(u32_at(file, header), PT_LOAD);
+                (
+                    u32_at(file, header + 4),
+                    u64_at(file, header + 8),
+                    u64_at(file, header + 16),
+                    u64_at(file, header + 32),
+                    u64_at(file, header + 40),
+                )
+            })
+            .collect()
+    }
+
+    pub(crate) fn entry(file: &[u8]) -> u64 {
+        u64_at(file, 0x18)
+    }
+
+    // name, section index, value and size of each symbol after the null one
+    pub(crate) fn symbols(file: &[u8]) -> Vec<(String, u16, u64, u64)> {
+        let sections = sections(file);
+        let find = |name: &str| &sections.iter().find(|s| s.0 == name).unwrap().3;
+        let (symtab, strtab) = (find(".symtab"), find(".strtab"));
+        symtab
+            .chunks(SYM_SIZE)
+            .skip(1)
+            .map(|sym| {
+                (
+                    string_at(strtab, u32_at(sym, 0) as usize),
+                    u16_at(sym, 6),
+                    u64_at(sym, 8),
+                    u64_at(sym, 16),
+                )
+            })
+            .collect()
+    }
+
+    // offset, type, the section of the symbol, and addend of each relocation in `.rela<name>`
+    pub(crate) fn relocations(file: &[u8], name: &str) -> Vec<(u64, u32, u16, i64)> {
+        let sections = sections(file);
+        let find = |name: &str| &sections.iter().find(|s| s.0 == name).unwrap().3;
+        let symtab = find(".symtab");
+        find(&format!(".rela{name}"))
+            .chunks(RELA_SIZE)
+            .map(|rela| {
+                let symbol = (u64_at(rela, 8) >> 32) as usize * SYM_SIZE;
+                (
+                    u64_at(rela, 0),
+                    u32_at(rela, 8),
+                    u16_at(symtab, symbol + 6),
+                    u64_at(rela, 16) as i64,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_write_sections_and_symbols() {
+        let mut elf = Elf::new(ET_REL, EM_X86_64);
+        let text = elf.add_section(Section::nobits(
+            ".text",
+            SHF_ALLOC | SHF_EXECINSTR,
+            0x1000,
+            0x40,
+        ));
+        elf.add_section(Section::progbits(".data", SHF_ALLOC, vec![1, 2, 3]));
+        elf.add_symbol(Symbol {
+            name: "main".to_string(),
+            kind: STT_FUNC,
+            global: true,
+            section: text,
+            value: 0x10,
+            size: 0x30,
+        });
+        elf.add_symbol(Symbol {
+            name: "a.bf".to_string(),
+            kind: STT_FILE,
+            global: false,
+            section: SHN_ABS,
+            value: 0,
+            size: 0,
+        });
+        let file = elf.write();
+
+        assert_eq!(&file[..4], b"\x7fELF");
+        assert_eq!(u16_at(&file, 0x12), EM_X86_64);
+        let names: Vec<String> = sections(&file).into_iter().map(|s| s.0).collect();
+        assert_eq!(
+            names,
+            ["", ".text", ".data", ".symtab", ".strtab", ".shstrtab"]
+        );
+        assert_eq!(sections(&file)[1].2, 0x1000);
+        assert_eq!(sections(&file)[2].3, [1, 2, 3]);
+        // locals first
+        assert_eq!(
+            symbols(&file),
+            [
+                ("a.bf".to_string(), SHN_ABS, 0, 0),
+                ("main".to_string(), text, 0x10, 0x30)
+            ]
+        );
+    }
+
+    #[test]
+    fn should_write_segments_before_sections() {
+        let mut elf = Elf::new(ET_EXEC, EM_AARCH64);
+        let text = Section::progbits(
+            ".text",
+            SHF_ALLOC | SHF_EXECINSTR,
+            vec![0xc0, 0x03, 0x5f, 0xd6],
+        );
+        let offset = headers_len(1).next_multiple_of(16) as u64;
+        elf.add_section(text.at(0x400000 + offset).aligned(16));
+        elf.add_segment(Segment {
+            flags: PF_R | PF_X,
+            offset: 0,
+            vaddr: 0x400000,
+            filesz: offset + 4,
+            memsz: offset + 4,
+            align: 0x10000,
+        });
+        elf.set_entry(0x400000 + offset);
+        let file = elf.write();
+
+        assert_eq!(u16_at(&file, 0x10), ET_EXEC);
+        assert_eq!(entry(&file), 0x400000 + offset);
+        assert_eq!(
+            segments(&file),
+            [(PF_R | PF_X, 0, 0x400000, offset + 4, offset + 4)]
+        );
+        let offset = offset as usize;
+        assert_eq!(file[offset..offset + 4], [0xc0, 0x03, 0x5f, 0xd6]);
+        assert_eq!(sections(&file)[1].2, 0x400000 + offset as u64);
+    }
+
+    #[test]
+    fn should_relocate_against_section_symbols() {
+        let mut elf = Elf::new(ET_REL, EM_X86_64);
+        let text = elf.add_section(Section::progbits(
+            ".text",
+            SHF_ALLOC | SHF_EXECINSTR,
+            vec![0xc3],
+        ));
+        let data = elf.add_section(Section::progbits(".data", SHF_ALLOC, vec![0; 16]));
+        elf.add_symbol(Symbol {
+            name: "main".to_string(),
+            kind: STT_FUNC,
+            global: true,
+            section: text,
+            value: 0,
+            size: 1,
+        });
+        elf.add_relocations(
+            data,
+            vec![Relocation {
+                offset: 8,
+                kind: R_X86_64_64,
+                section: text,
+                addend: 1,
+            }],
+        );
+        let file = elf.write();
+
+        let names: Vec<String> = sections(&file).into_iter().map(|s| s.0).collect();
+        assert_eq!(
+            names,
+            [
+                "",
+                ".text",
+                ".data",
+                ".rela.data",
+                ".symtab",
+                ".strtab",
+                ".shstrtab"
+            ]
+        );
+        assert_eq!(relocations(&file, ".data"), [(8, R_X86_64_64, text, 1)]);
+        // the section symbol is local, so it comes before `main`
+        assert_eq!(
+            symbols(&file),
+            [
+                (String::new(), text, 0, 0),
+                ("main".to_string(), text, 0, 1)
+            ]
+        );
+    }
+}
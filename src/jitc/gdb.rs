@@ -0,0 +1,139 @@
+// gdb's JIT interface: gdb puts a breakpoint on `__jit_debug_register_code` and, when it hits,
+// reads the object file of `relevant_entry` in `__jit_debug_descriptor` from our memory
+use std::ptr::{self, addr_of, addr_of_mut};
+use std::sync::Mutex;
+
+const JIT_REGISTER_FN: u32 = 1;
+const JIT_UNREGISTER_FN: u32 = 2;
+
+#[repr(C)]
+struct JitCodeEntry {
+    next: *mut JitCodeEntry,
+    prev: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+#[repr(C)]
+pub struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry,
+}
+
+// gdb finds both by name
+#[no_mangle]
+static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: 0,
+    relevant_entry: ptr::null_mut(),
+    first_entry: ptr::null_mut(),
+};
+
+#[no_mangle]
+#[inline(never)]
+extern "C" fn __jit_debug_register_code() {
+    // a side effect, so that calls are not optimized away
+    // Safety: the descriptor is a valid static
+    unsafe { ptr::read_volatile(addr_of!(__jit_debug_descriptor.action_flag)) };
+}
+
+// the descriptor is shared by every thread
+static LOCK: Mutex<()> = Mutex::new(());
+
+// An object file that gdb knows about until this is dropped
+pub struct Registration {
+    entry: *mut JitCodeEntry,
+    _object: Vec<u8>,
+}
+
+// Safety: the entry is only touched with `LOCK` held
+unsafe impl Send for Registration {}
+
+pub fn register(object: Vec<u8>) -> Registration {
+    let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    // Safety: the descriptor and the entries in its list are only touched with `LOCK` held
+    unsafe {
+        let descriptor = &mut *addr_of_mut!(__jit_debug_descriptor);
+        let entry = Box::into_raw(Box::new(JitCodeEntry {
+            next: descriptor.first_entry,
+            prev: ptr::null_mut(),
+            symfile_addr: object.as_ptr(),
+            symfile_size: object.len() as u64,
+        }));
+        if let Some(first) = descriptor.first_entry.as_mut() {
+            first.prev = entry;
+        }
+        descriptor.first_entry = entry;
+        descriptor.relevant_entry = entry;
+        descriptor.action_flag = JIT_REGISTER_FN;
+        __jit_debug_register_code();
+        Registration {
+            entry,
+            _object: object,
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        // Safety: as in `register`, and `entry` is still in the list
+        unsafe {
+            let descriptor = &mut *addr_of_mut!(__jit_debug_descriptor);
+            let entry = &mut *self.entry;
+            match entry.prev.as_mut() {
+                Some(prev) => prev.next = entry.next,
+                None => descriptor.first_entry = entry.next,
+            }
+            if let Some(next) = entry.next.as_mut() {
+                next.prev = entry.prev;
+            }
+            descriptor.relevant_entry = self.entry;
+            descriptor.action_flag = JIT_UNREGISTER_FN;
+            __jit_debug_register_code();
+            drop(Box::from_raw(self.entry));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the object files that gdb would find now
+    fn registered() -> Vec<Vec<u8>> {
+        let _guard = LOCK.lock().unwrap();
+        let mut objects = Vec::new();
+        // Safety: as in `register`
+        unsafe {
+            let mut entry = (*addr_of!(__jit_debug_descriptor)).first_entry;
+            while let Some(current) = entry.as_ref() {
+                let size = current.symfile_size as usize;
+                objects.push(std::slice::from_raw_parts(current.symfile_addr, size).to_vec());
+                entry = current.next;
+            }
+        }
+        objects
+    }
+
+    #[test]
+    fn should_link_and_unlink_entries() {
+        let first = register(b"first".to_vec());
+        let second = register(b"second".to_vec());
+        let third = register(b"third".to_vec());
+        let objects = registered();
+        let position = |name: &[u8]| objects.iter().position(|o| o == name).unwrap();
+        assert!(position(b"third") < position(b"second"));
+        assert!(position(b"second") < position(b"first"));
+
+        drop(second);
+        let objects = registered();
+        assert!(!objects.contains(&b"second".to_vec()));
+        assert!(objects.contains(&b"first".to_vec()));
+        drop(third);
+        drop(first);
+        assert!(!registered().contains(&b"first".to_vec()));
+    }
+}
@@ -0,0 +1,114 @@
+use super::{aarch64, x86_64, MachineCode, Target};
+use crate::ir::{print_op, SourcePos};
+use crate::op::*;
+use std::fmt::Write;
+
+// A listing of `code` for `target`, with a comment before the code of each op that shows the op
+// and, when `positions` is given, where it comes from in the source:
+//
+//     ; prologue
+//     0000  a9bd7bfd  stp x29, x30, [sp, #-48]!
+//     ...
+//     ; 0: inc 3 (1:1)
+//     0020  b9400269  ldr w9, [x19]
+//     0024  11000d29  add w9, w9, #3
+pub fn print_asm(
+    code: &MachineCode,
+    target: Target,
+    ops: &[Op],
+    positions: Option<&[SourcePos]>,
+) -> String {
+    let listing = match target {
+        Target::X86_64 => x86_64::disasm::disassemble(&code.bytes),
+        Target::Aarch64 => aarch64::disasm::disassemble(&code.bytes),
+    };
+    let width = listing
+        .iter()
+        .map(|&(_, len, _)| hex(target, &code.bytes[..len]).len())
+        .max()
+        .unwrap_or(0);
+
+    let mut text = format!(
+        "; {}, {} bytes\n; prologue\n",
+        target.name(),
+        code.bytes.len()
+    );
+    let mut ip = 0;
+    for (pc, len, inst) in listing {
+        // ops without code of their own share the address of the next one
+        while ip < code.op_offsets.len() && code.op_offsets[ip] <= pc {
+            match ops.get(ip) {
+                Some(op) => {
+                    write!(text, "; {ip}: {}", print_op(op)).unwrap();
+                    if let Some(SourcePos { line, column }) = positions.and_then(|p| p.get(ip)) {
+                        write!(text, " ({line}:{column})").unwrap();
+                    }
+                    text.push('\n');
+                }
+                None => text.push_str("; epilogue\n"),
+            }
+            ip += 1;
+        }
+        let bytes = hex(target, &code.bytes[pc..pc + len]);
+        writeln!(text, "{pc:04x}  {bytes:width$}  {inst}").unwrap();
+    }
+    text
+}
+
+// aarch64 instructions as words, which is how the manual shows them, and x86_64 ones as bytes
+fn hex(target: Target, bytes: &[u8]) -> String {
+    match (target, <[u8; 4]>::try_from(bytes)) {
+        (Target::Aarch64, Ok(word)) => format!("{:08x}", u32::from_le_bytes(word)),
+        _ => bytes
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate_ops;
+    use crate::ir::source_map;
+    use crate::jitc::emit_code;
+
+    #[test]
+    fn should_annotate_code_with_ops_and_positions() {
+        let input = "+\n >.";
+        let ops = generate_ops(input);
+        let code = emit_code(&ops, Target::Aarch64).unwrap();
+        let text = print_asm(&code, Target::Aarch64, &ops, Some(&source_map(input)));
+
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], format!("; aarch64, {} bytes", code.bytes.len()));
+        assert_eq!(lines[1], "; prologue");
+        assert_eq!(lines[2], "0000  a9bd7bfd  stp x29, x30, [sp, #-48]!");
+        let comments: Vec<&str> = lines
+            .iter()
+            .filter(|l| l.starts_with(';'))
+            .copied()
+            .collect();
+        assert_eq!(
+            comments[2..],
+            [
+                "; 0: inc 1 (1:1)",
+                "; 1: right 1 (2:2)",
+                "; 2: out 1 (2:3)",
+                "; epilogue"
+            ]
+        );
+        let inc = lines.iter().position(|&l| l == "; 0: inc 1 (1:1)").unwrap();
+        assert_eq!(lines[inc + 1], "0020  b9400269  ldr w9, [x19]");
+    }
+
+    #[test]
+    fn should_list_x86_64_bytes() {
+        let ops = generate_ops("-");
+        let code = emit_code(&ops, Target::X86_64).unwrap();
+        let text = print_asm(&code, Target::X86_64, &ops, None);
+        assert!(text.contains("; 0: dec 1\n"));
+        assert!(text.contains("81 2b 01 00 00 00     sub dword ptr [rbx], 0x1\n"));
+    }
+}
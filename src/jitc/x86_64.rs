@@ -0,0 +1,216 @@
+mod codegen;
+pub mod disasm;
+pub(crate) mod runtime;
+
+use std::mem::size_of;
+
+use crate::jitc::{Backend, OUTPUT_BUFFER_SIZE};
+use crate::op::*;
+use crate::MEM_SIZE;
+
+#[derive(Default)]
+pub struct X86_64 {
+    raw_code: Vec<u8>,
+    traps: Vec<(usize, u64)>, // end of a branch to a trap stub, and the status it returns
+}
+
+impl X86_64 {
+    // the branch target, in the last 4 bytes of `jump`, is patched to a trap stub in the epilogue
+    fn branch_to_trap(&mut self, jump: &[u8], status: u64) {
+        self.raw_code.extend_from_slice(jump);
+        self.traps.push((self.raw_code.len(), status));
+    }
+}
+
+impl Backend for X86_64 {
+    type Label = usize; // byte address of the loop head
+
+    fn prologue(&mut self) {
+        /* System V: the tape comes in rdi, the run context in rsi, and the cell to start at in rdx */
+        /* A dedicated register for data pointer: rbx, which is callee-saved */
+        /* and for the memory boundary: [r12, r13), and the run context: r14, which are callee-saved too */
+        // push rbp
+        // mov rbp, rsp
+        // push rbx
+        // push r12
+        // push r13
+        // push r14      /* rsp is 16-byte aligned from here */
+        // lea rbx, [rdi + rdx * 4]
+        // mov r12, rdi
+        // lea r13, [rdi + MEM_SIZE * 4]
+        // mov r14, rsi
+        self.raw_code.extend_from_slice(&codegen::push_rbp());
+        self.raw_code.extend_from_slice(&codegen::mov_rbp_rsp());
+        self.raw_code.extend_from_slice(&codegen::push_rbx());
+        self.raw_code.extend_from_slice(&codegen::push_r12());
+        self.raw_code.extend_from_slice(&codegen::push_r13());
+        self.raw_code.extend_from_slice(&codegen::push_r14());
+        self.raw_code
+            .extend_from_slice(&codegen::lea_rbx_addrrdi_rdx4());
+        self.raw_code.extend_from_slice(&codegen::mov_r12_rdi());
+        self.raw_code
+            .extend_from_slice(&codegen::lea_r13_addrrdi_i32operand(
+                (MEM_SIZE * size_of::<Operand>()) as i32,
+            ));
+        self.raw_code.extend_from_slice(&codegen::mov_r14_rsi());
+    }
+
+    fn epilogue(&mut self) {
+        /* The status is returned in rax and the cell index of the data pointer in rdx */
+        // xor eax, eax
+        // exit:
+        // mov rdx, rbx
+        // sub rdx, r12
+        // shr rdx, 2
+        // pop r14
+        // pop r13
+        // pop r12
+        // pop rbx
+        // pop rbp
+        // ret
+        self.raw_code.extend_from_slice(&codegen::xor_eax_eax());
+        let exit = self.raw_code.len();
+        self.raw_code.extend_from_slice(&codegen::cell_index_rdx());
+        self.raw_code.extend_from_slice(&codegen::pop_r14());
+        self.raw_code.extend_from_slice(&codegen::pop_r13());
+        self.raw_code.extend_from_slice(&codegen::pop_r12());
+        self.raw_code.extend_from_slice(&codegen::pop_rbx());
+        self.raw_code.extend_from_slice(&codegen::pop_rbp());
+        self.raw_code.extend_from_slice(&codegen::ret());
+
+        // trap stubs, one per bounds check and I/O call:
+        // mov rax, #status
+        // jmp exit
+        for (branch_end, status) in std::mem::take(&mut self.traps) {
+            let stub = self.raw_code.len();
+            let rel32 = (stub - branch_end) as i32;
+            self.raw_code[branch_end - 4..branch_end].copy_from_slice(&rel32.to_le_bytes());
+
+            self.raw_code
+                .extend_from_slice(&codegen::mov_rax_u64operand(status));
+            let jmp_end = self.raw_code.len() + 5;
+            self.raw_code
+                .extend_from_slice(&codegen::jmp_rel32(exit as i32 - jmp_end as i32));
+        }
+    }
+
+    fn add_to_cell(&mut self, amount: Operand) {
+        if amount >= 0 {
+            // add dword [rbx], #operand
+            self.raw_code
+                .extend_from_slice(&codegen::add_addrrbx_i32operand(amount));
+        } else {
+            // sub dword [rbx], #operand
+            self.raw_code
+                .extend_from_slice(&codegen::sub_addrrbx_i32operand(amount.wrapping_neg()));
+        }
+    }
+
+    fn move_pointer(&mut self, amount: Operand, trap: Option<u64>) {
+        let bytes = i32::try_from(amount.unsigned_abs())
+            .ok()
+            .and_then(|cells| cells.checked_mul(size_of::<Operand>() as i32))
+            .expect("moves are less than the memory size");
+        if amount >= 0 {
+            // add rbx, #operand
+            self.raw_code
+                .extend_from_slice(&codegen::add_rbx_i32operand(bytes));
+        } else {
+            // sub rbx, #operand
+            self.raw_code
+                .extend_from_slice(&codegen::sub_rbx_i32operand(bytes));
+        }
+
+        let Some(status) = trap else {
+            return;
+        };
+        if amount >= 0 {
+            // cmp rbx, r13
+            // jae trap
+            self.raw_code.extend_from_slice(&codegen::cmp_rbx_r13());
+            self.branch_to_trap(&codegen::jae_rel32(0), status);
+        } else {
+            // cmp rbx, r12
+            // jb trap
+            self.raw_code.extend_from_slice(&codegen::cmp_rbx_r12());
+            self.branch_to_trap(&codegen::jb_rel32(0), status);
+        }
+    }
+
+    fn trap(&mut self, status: u64) {
+        // jmp trap
+        self.branch_to_trap(&codegen::jmp_rel32(0), status);
+    }
+
+    fn input(&mut self, io_error: u64) {
+        // mov rdi, r14
+        // mov rsi, rbx
+        // call [r14]      /* read, which flushes the output buffer first */
+        // test eax, eax
+        // jnz trap
+        self.raw_code
+            .extend_from_slice(&codegen::call_read_callback());
+        self.branch_to_trap(&codegen::jnz_rel32(0), io_error);
+    }
+
+    fn output(&mut self, not_ascii: u64, io_error: u64) {
+        // mov eax, [rbx]
+        // cmp eax, 127
+        // ja trap
+        self.raw_code
+            .extend_from_slice(&codegen::load_cell_cmp_ascii());
+        self.branch_to_trap(&codegen::ja_rel32(0), not_ascii);
+
+        // mov rcx, [r14 + 24]
+        // mov rdx, [r14 + 16]
+        // mov [rdx + rcx], al
+        // inc rcx
+        // mov [r14 + 24], rcx
+        // cmp rcx, #OUTPUT_BUFFER_SIZE
+        // jb done
+        self.raw_code
+            .extend_from_slice(&codegen::append_al_to_buffer(OUTPUT_BUFFER_SIZE as i32));
+        let flush_size =
+            size_of_val(&codegen::call_flush_callback()) + size_of::<codegen::NearJump>();
+        self.raw_code
+            .extend_from_slice(&codegen::jb_rel8(flush_size as i8));
+
+        // mov rdi, r14
+        // call [r14 + 8]  /* flush */
+        // test eax, eax
+        // jnz trap
+        // done:
+        self.raw_code
+            .extend_from_slice(&codegen::call_flush_callback());
+        self.branch_to_trap(&codegen::jnz_rel32(0), io_error);
+    }
+
+    fn loop_head(&mut self) -> usize {
+        let matching_byte_addr = self.raw_code.len();
+
+        let placeholder: codegen::CondNearBranch = [0; size_of::<codegen::CondNearBranch>()];
+        self.raw_code.extend_from_slice(&placeholder);
+        matching_byte_addr
+    }
+
+    fn loop_tail(&mut self, matching_byte_addr: usize) -> Result<(), &'static str> {
+        // both branches are relative to their own end, and rel32 reaches +-2GiB
+        let addr = matching_byte_addr;
+        let body_start = addr + size_of::<codegen::CondNearBranch>();
+        let body_end = self.raw_code.len() + size_of::<codegen::CondNearBranch>();
+        let amount = i32::try_from(body_end - body_start).map_err(|_| "loop body is too large")?;
+
+        self.raw_code[addr..body_start].copy_from_slice(&codegen::jz_addrrbx_rel32(amount));
+        self.raw_code
+            .extend_from_slice(&codegen::jnz_addrrbx_rel32(-amount));
+        Ok(())
+    }
+
+    fn code_len(&self) -> usize {
+        self.raw_code.len()
+    }
+
+    fn finish(self) -> Result<Vec<u8>, &'static str> {
+        Ok(self.raw_code)
+    }
+}
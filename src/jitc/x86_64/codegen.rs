@@ -0,0 +1,249 @@
+// x86_64 instructions have variable lengths, so every function returns an array of its own size
+
+pub fn push_rbx() -> [u8; 1] {
+    [0x53]
+}
+
+pub fn pop_rbx() -> [u8; 1] {
+    [0x5b]
+}
+
+pub fn push_rbp() -> [u8; 1] {
+    [0x55]
+}
+
+pub fn pop_rbp() -> [u8; 1] {
+    [0x5d]
+}
+
+pub fn mov_rbp_rsp() -> [u8; 3] {
+    [0x48, 0x89, 0xe5]
+}
+
+pub fn push_r12() -> [u8; 2] {
+    [0x41, 0x54]
+}
+
+pub fn pop_r12() -> [u8; 2] {
+    [0x41, 0x5c]
+}
+
+pub fn push_r13() -> [u8; 2] {
+    [0x41, 0x55]
+}
+
+pub fn pop_r13() -> [u8; 2] {
+    [0x41, 0x5d]
+}
+
+pub fn push_r14() -> [u8; 2] {
+    [0x41, 0x56]
+}
+
+pub fn pop_r14() -> [u8; 2] {
+    [0x41, 0x5e]
+}
+
+pub fn ret() -> [u8; 1] {
+    [0xc3]
+}
+
+pub fn lea_rbx_addrrdi_rdx4() -> [u8; 4] {
+    // REX.W 8D /r (ModRM: mod=00, reg=011 -> rbx, rm=100 -> SIB; SIB: scale=10, index=010 -> rdx,
+    // base=111 -> rdi)
+    [0x48, 0x8d, 0x1c, 0x97]
+}
+
+pub fn mov_r12_rdi() -> [u8; 3] {
+    // REX.W+B 89 /r (ModRM: mod=11, reg=111 -> rdi, rm=100 -> r12)
+    [0x49, 0x89, 0xfc]
+}
+
+pub fn lea_r13_addrrdi_i32operand(operand: i32) -> [u8; 7] {
+    // REX.W+R 8D /r (ModRM: mod=10, reg=101 -> r13, rm=111 -> [rdi + disp32])
+    let mut result = [0; 7];
+    result[..3].copy_from_slice(&[0x4c, 0x8d, 0xaf]);
+    result[3..].copy_from_slice(&operand.to_le_bytes());
+    result
+}
+
+pub fn mov_r14_rsi() -> [u8; 3] {
+    // REX.W+B 89 /r (ModRM: mod=11, reg=110 -> rsi, rm=110 -> r14)
+    [0x49, 0x89, 0xf6]
+}
+
+pub fn cell_index_rdx() -> [u8; 10] {
+    // mov rdx, rbx
+    // sub rdx, r12
+    // shr rdx, 2
+    [0x48, 0x89, 0xda, 0x4c, 0x29, 0xe2, 0x48, 0xc1, 0xea, 0x02]
+}
+
+pub fn xor_eax_eax() -> [u8; 2] {
+    [0x31, 0xc0]
+}
+
+pub fn mov_rax_u64operand(operand: u64) -> [u8; 10] {
+    // REX.W + B8+rd io
+    let mut result = [0; 10];
+    result[..2].copy_from_slice(&[0x48, 0xb8]);
+    result[2..].copy_from_slice(&operand.to_le_bytes());
+    result
+}
+
+pub fn cmp_rbx_r12() -> [u8; 3] {
+    // REX.W+R 39 /r (ModRM: mod=11, reg=100 -> r12, rm=011 -> rbx)
+    [0x4c, 0x39, 0xe3]
+}
+
+pub fn cmp_rbx_r13() -> [u8; 3] {
+    // REX.W+R 39 /r (ModRM: mod=11, reg=101 -> r13, rm=011 -> rbx)
+    [0x4c, 0x39, 0xeb]
+}
+
+pub type NearJump = [u8; 6];
+
+pub fn jb_rel32(rel32: i32) -> NearJump {
+    let mut result = [0; 6];
+    result[..2].copy_from_slice(&[0x0f, 0x82]);
+    result[2..].copy_from_slice(&rel32.to_le_bytes());
+    result
+}
+
+pub fn jae_rel32(rel32: i32) -> NearJump {
+    let mut result = [0; 6];
+    result[..2].copy_from_slice(&[0x0f, 0x83]);
+    result[2..].copy_from_slice(&rel32.to_le_bytes());
+    result
+}
+
+pub fn ja_rel32(rel32: i32) -> NearJump {
+    let mut result = [0; 6];
+    result[..2].copy_from_slice(&[0x0f, 0x87]);
+    result[2..].copy_from_slice(&rel32.to_le_bytes());
+    result
+}
+
+pub fn jnz_rel32(rel32: i32) -> NearJump {
+    let mut result = [0; 6];
+    result[..2].copy_from_slice(&[0x0f, 0x85]);
+    result[2..].copy_from_slice(&rel32.to_le_bytes());
+    result
+}
+
+pub fn jmp_rel32(rel32: i32) -> [u8; 5] {
+    let mut result = [0; 5];
+    result[0] = 0xe9;
+    result[1..].copy_from_slice(&rel32.to_le_bytes());
+    result
+}
+
+pub fn add_addrrbx_i32operand(operand: i32) -> [u8; 6] {
+    // 81 /0 id (ModRM: mod=00, reg=000, rm=011 -> [rbx])
+    let mut result = [0; 6];
+    result[..2].copy_from_slice(&[0x81, 0x03]);
+    result[2..].copy_from_slice(&operand.to_le_bytes());
+    result
+}
+
+pub fn sub_addrrbx_i32operand(operand: i32) -> [u8; 6] {
+    // 81 /5 id (ModRM: mod=00, reg=101, rm=011 -> [rbx])
+    let mut result = [0; 6];
+    result[..2].copy_from_slice(&[0x81, 0x2b]);
+    result[2..].copy_from_slice(&operand.to_le_bytes());
+    result
+}
+
+pub fn add_rbx_i32operand(operand: i32) -> [u8; 7] {
+    // REX.W + 81 /0 id (ModRM: mod=11, reg=000, rm=011 -> rbx)
+    let mut result = [0; 7];
+    result[..3].copy_from_slice(&[0x48, 0x81, 0xc3]);
+    result[3..].copy_from_slice(&operand.to_le_bytes());
+    result
+}
+
+pub fn sub_rbx_i32operand(operand: i32) -> [u8; 7] {
+    // REX.W + 81 /5 id (ModRM: mod=11, reg=101, rm=011 -> rbx)
+    let mut result = [0; 7];
+    result[..3].copy_from_slice(&[0x48, 0x81, 0xeb]);
+    result[3..].copy_from_slice(&operand.to_le_bytes());
+    result
+}
+
+pub fn call_read_callback() -> [u8; 11] {
+    let mut result = [0; 11];
+
+    // mov rdi, r14    /* the run context */
+    // mov rsi, rbx
+    // call [r14]
+    // test eax, eax
+    result[..3].copy_from_slice(&[0x4c, 0x89, 0xf7]);
+    result[3..6].copy_from_slice(&[0x48, 0x89, 0xde]);
+    result[6..9].copy_from_slice(&[0x41, 0xff, 0x16]);
+    result[9..11].copy_from_slice(&[0x85, 0xc0]);
+    result
+}
+
+pub fn call_flush_callback() -> [u8; 9] {
+    let mut result = [0; 9];
+
+    // mov rdi, r14
+    // call [r14 + 8]
+    // test eax, eax
+    result[..3].copy_from_slice(&[0x4c, 0x89, 0xf7]);
+    result[3..7].copy_from_slice(&[0x41, 0xff, 0x56, 0x08]);
+    result[7..9].copy_from_slice(&[0x85, 0xc0]);
+    result
+}
+
+pub fn load_cell_cmp_ascii() -> [u8; 5] {
+    // mov eax, [rbx]
+    // cmp eax, 127
+    [0x8b, 0x03, 0x83, 0xf8, 0x7f]
+}
+
+pub fn append_al_to_buffer(capacity: i32) -> [u8; 25] {
+    let mut result = [0; 25];
+
+    // mov rcx, [r14 + 24]    /* length of the output buffer */
+    // mov rdx, [r14 + 16]    /* the output buffer */
+    // mov [rdx + rcx], al
+    // inc rcx
+    // mov [r14 + 24], rcx
+    // cmp rcx, #capacity
+    result[..4].copy_from_slice(&[0x49, 0x8b, 0x4e, 0x18]);
+    result[4..8].copy_from_slice(&[0x49, 0x8b, 0x56, 0x10]);
+    result[8..11].copy_from_slice(&[0x88, 0x04, 0x0a]);
+    result[11..14].copy_from_slice(&[0x48, 0xff, 0xc1]);
+    result[14..18].copy_from_slice(&[0x49, 0x89, 0x4e, 0x18]);
+    result[18..21].copy_from_slice(&[0x48, 0x81, 0xf9]);
+    result[21..].copy_from_slice(&capacity.to_le_bytes());
+    result
+}
+
+pub fn jb_rel8(rel8: i8) -> [u8; 2] {
+    [0x72, rel8 as u8]
+}
+
+pub type CondNearBranch = [u8; 10];
+
+fn test_addrrbx_jcc_rel32(opcode: u8, rel32: i32) -> CondNearBranch {
+    let mut result = [0; 10];
+
+    // mov eax, [rbx]
+    // test eax, eax
+    // jcc rel32     /* relative to the end of this instruction */
+    result[..2].copy_from_slice(&[0x8b, 0x03]);
+    result[2..4].copy_from_slice(&[0x85, 0xc0]);
+    result[4..6].copy_from_slice(&[0x0f, opcode]);
+    result[6..].copy_from_slice(&rel32.to_le_bytes());
+    result
+}
+
+pub fn jz_addrrbx_rel32(rel32: i32) -> CondNearBranch {
+    test_addrrbx_jcc_rel32(0x84, rel32)
+}
+
+pub fn jnz_addrrbx_rel32(rel32: i32) -> CondNearBranch {
+    test_addrrbx_jcc_rel32(0x85, rel32)
+}
@@ -0,0 +1,180 @@
+// Decodes exactly the byte patterns that codegen.rs produces, in Intel syntax
+
+#[derive(Clone, Copy)]
+enum Imm {
+    None,
+    I8,
+    I32,
+    U64,
+    Rel8,  // relative to the end of the instruction
+    Rel32, // same as `Rel8`
+}
+
+// opcode bytes, and the text with `{}` where the immediate goes
+const INSTRUCTIONS: &[(&[u8], &str, Imm)] = &[
+    (&[0x53], "push rbx", Imm::None),
+    (&[0x5b], "pop rbx", Imm::None),
+    (&[0x55], "push rbp", Imm::None),
+    (&[0x5d], "pop rbp", Imm::None),
+    (&[0x41, 0x54], "push r12", Imm::None),
+    (&[0x41, 0x5c], "pop r12", Imm::None),
+    (&[0x41, 0x55], "push r13", Imm::None),
+    (&[0x41, 0x5d], "pop r13", Imm::None),
+    (&[0x41, 0x56], "push r14", Imm::None),
+    (&[0x41, 0x5e], "pop r14", Imm::None),
+    (&[0xc3], "ret", Imm::None),
+    (&[0x48, 0x89, 0xe5], "mov rbp, rsp", Imm::None),
+    (
+        &[0x48, 0x8d, 0x1c, 0x97],
+        "lea rbx, [rdi + rdx * 4]",
+        Imm::None,
+    ),
+    (&[0x49, 0x89, 0xfc], "mov r12, rdi", Imm::None),
+    (&[0x4c, 0x8d, 0xaf], "lea r13, [rdi + {}]", Imm::I32),
+    (&[0x49, 0x89, 0xf6], "mov r14, rsi", Imm::None),
+    (&[0x48, 0x89, 0xda], "mov rdx, rbx", Imm::None),
+    (&[0x4c, 0x29, 0xe2], "sub rdx, r12", Imm::None),
+    (&[0x48, 0xc1, 0xea], "shr rdx, {}", Imm::I8),
+    (&[0x31, 0xc0], "xor eax, eax", Imm::None),
+    (&[0x48, 0xb8], "mov rax, {}", Imm::U64),
+    (&[0x4c, 0x39, 0xe3], "cmp rbx, r12", Imm::None),
+    (&[0x4c, 0x39, 0xeb], "cmp rbx, r13", Imm::None),
+    (&[0x0f, 0x82], "jb {}", Imm::Rel32),
+    (&[0x0f, 0x83], "jae {}", Imm::Rel32),
+    (&[0x0f, 0x84], "jz {}", Imm::Rel32),
+    (&[0x0f, 0x85], "jnz {}", Imm::Rel32),
+    (&[0x0f, 0x87], "ja {}", Imm::Rel32),
+    (&[0xe9], "jmp {}", Imm::Rel32),
+    (&[0x72], "jb {}", Imm::Rel8),
+    (&[0x81, 0x03], "add dword ptr [rbx], {}", Imm::I32),
+    (&[0x81, 0x2b], "sub dword ptr [rbx], {}", Imm::I32),
+    (&[0x48, 0x81, 0xc3], "add rbx, {}", Imm::I32),
+    (&[0x48, 0x81, 0xeb], "sub rbx, {}", Imm::I32),
+    (&[0x4c, 0x89, 0xf7], "mov rdi, r14", Imm::None),
+    (&[0x48, 0x89, 0xde], "mov rsi, rbx", Imm::None),
+    (&[0x41, 0xff, 0x16], "call qword ptr [r14]", Imm::None),
+    (
+        &[0x41, 0xff, 0x56, 0x08],
+        "call qword ptr [r14 + 0x8]",
+        Imm::None,
+    ),
+    (&[0x85, 0xc0], "test eax, eax", Imm::None),
+    (&[0x8b, 0x03], "mov eax, dword ptr [rbx]", Imm::None),
+    (&[0x83, 0xf8], "cmp eax, {}", Imm::I8),
+    (
+        &[0x49, 0x8b, 0x4e, 0x18],
+        "mov rcx, qword ptr [r14 + 0x18]",
+        Imm::None,
+    ),
+    (
+        &[0x49, 0x8b, 0x56, 0x10],
+        "mov rdx, qword ptr [r14 + 0x10]",
+        Imm::None,
+    ),
+    (
+        &[0x88, 0x04, 0x0a],
+        "mov byte ptr [rdx + rcx], al",
+        Imm::None,
+    ),
+    (&[0x48, 0xff, 0xc1], "inc rcx", Imm::None),
+    (
+        &[0x49, 0x89, 0x4e, 0x18],
+        "mov qword ptr [r14 + 0x18], rcx",
+        Imm::None,
+    ),
+    (&[0x48, 0x81, 0xf9], "cmp rcx, {}", Imm::I32),
+];
+
+fn hex(value: i64) -> String {
+    if value < 0 {
+        format!("-{:#x}", value.unsigned_abs())
+    } else {
+        format!("{value:#x}")
+    }
+}
+
+// the instruction at `pc`: its length and text
+fn decode(code: &[u8], pc: usize) -> Option<(usize, String)> {
+    let rest = &code[pc..];
+    let &(opcode, text, imm) = INSTRUCTIONS
+        .iter()
+        .find(|(opcode, _, _)| rest.starts_with(opcode))?;
+    let imm_size = match imm {
+        Imm::None => 0,
+        Imm::I8 | Imm::Rel8 => 1,
+        Imm::I32 | Imm::Rel32 => 4,
+        Imm::U64 => 8,
+    };
+    let len = opcode.len() + imm_size;
+    let bytes = rest.get(opcode.len()..len)?;
+    let imm = match imm {
+        Imm::None => return Some((len, text.to_string())),
+        Imm::I8 => hex(bytes[0] as i8 as i64),
+        Imm::I32 => hex(i32::from_le_bytes(bytes.try_into().unwrap()) as i64),
+        Imm::U64 => format!("{:#x}", u64::from_le_bytes(bytes.try_into().unwrap())),
+        Imm::Rel8 => format!("{:#x}", (pc + len) as i64 + bytes[0] as i8 as i64),
+        Imm::Rel32 => {
+            let rel = i32::from_le_bytes(bytes.try_into().unwrap()) as i64;
+            format!("{:#x}", (pc + len) as i64 + rel)
+        }
+    };
+    Some((len, text.replace("{}", &imm)))
+}
+
+// Each instruction: its byte address, length and text. Bytes that the encoder never produces
+// show up one by one as `.byte`.
+pub fn disassemble(code: &[u8]) -> Vec<(usize, usize, String)> {
+    let mut listing = Vec::new();
+    let mut pc = 0;
+    while pc < code.len() {
+        let (len, text) =
+            decode(code, pc).unwrap_or_else(|| (1, format!(".byte {:#04x}", code[pc])));
+        listing.push((pc, len, text));
+        pc += len;
+    }
+    listing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::codegen;
+    use super::*;
+    use crate::generate_ops;
+    use crate::jitc::{emit_code, Target};
+
+    fn text(code: &[u8]) -> String {
+        let listing = disassemble(code);
+        assert_eq!(listing.len(), 1);
+        listing[0].2.clone()
+    }
+
+    #[test]
+    fn should_decode_immediates_and_targets() {
+        assert_eq!(
+            text(&codegen::add_addrrbx_i32operand(3)),
+            "add dword ptr [rbx], 0x3"
+        );
+        assert_eq!(text(&codegen::sub_rbx_i32operand(-4)), "sub rbx, -0x4");
+        assert_eq!(text(&codegen::mov_rax_u64operand(0x302)), "mov rax, 0x302");
+        assert_eq!(text(&codegen::jmp_rel32(-5)), "jmp 0x0");
+        assert_eq!(text(&codegen::jb_rel8(9)), "jb 0xb");
+        assert_eq!(
+            text(&codegen::lea_r13_addrrdi_i32operand(0x40000)),
+            "lea r13, [rdi + 0x40000]"
+        );
+    }
+
+    #[test]
+    fn should_decode_everything_the_backend_emits() {
+        let program = format!("+[->{}+<]<-[,.]{}", ">".repeat(5000), "+".repeat(70000));
+        let code = emit_code(&generate_ops(&program), Target::X86_64).unwrap();
+        let listing = disassemble(&code.bytes);
+        for (pc, _, text) in &listing {
+            assert!(!text.starts_with('.'), "{text} at {pc:#x}");
+        }
+        // every op starts at an instruction boundary
+        for offset in code.op_offsets {
+            assert!(listing.iter().any(|&(pc, _, _)| pc == offset));
+        }
+    }
+}
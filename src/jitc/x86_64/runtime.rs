@@ -0,0 +1,279 @@
+// The code of a static executable around the generated code: the entry point, which runs it and
+// exits with the message for its status, and the callbacks of its run context, which make Linux
+// system calls themselves. A relocatable object needs only the start of `bf_main`.
+
+use crate::jitc::aot::{Addresses, Runtime, CONTEXT_FAILED, OTHER_CAUSE, TABLE_ENTRY_SIZE};
+use crate::jitc::Trap;
+
+const SYS_READ: u32 = 0;
+const SYS_WRITE: u32 = 1;
+const SYS_EXIT: u32 = 60;
+
+#[derive(Clone, Copy)]
+struct Label(usize);
+
+// Bytes with rel32 branches to labels, which are fixed up in `finish`
+#[derive(Default)]
+struct Code {
+    bytes: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    fixups: Vec<(usize, Label)>, // the end of a rel32, and where it goes
+}
+
+impl Code {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    // the opcode of a register-sized `mov`, and its immediate
+    fn emit_imm64(&mut self, opcode: [u8; 2], value: u64) {
+        self.emit(&opcode);
+        self.emit(&value.to_le_bytes());
+    }
+
+    fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.bytes.len());
+    }
+
+    // `opcode` is followed by a rel32 to `label`
+    fn emit_branch(&mut self, opcode: &[u8], label: Label) {
+        self.emit(opcode);
+        self.emit(&[0; 4]);
+        self.fixups.push((self.bytes.len(), label));
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for (end, label) in std::mem::take(&mut self.fixups) {
+            let target = self.labels[label.0].expect("branch to a label that is never bound");
+            let rel32 = target as i32 - end as i32;
+            self.bytes[end - 4..end].copy_from_slice(&rel32.to_le_bytes());
+        }
+        self.bytes
+    }
+}
+
+const CALL: &[u8] = &[0xe8];
+const JMP: &[u8] = &[0xe9];
+const JE: &[u8] = &[0x0f, 0x84];
+const JNE: &[u8] = &[0x0f, 0x85];
+const JBE: &[u8] = &[0x0f, 0x86];
+const JLE: &[u8] = &[0x0f, 0x8e];
+
+// The generated code follows right after, as `bf_main`
+pub fn runtime(addresses: &Addresses) -> Runtime {
+    let mut code = Code::default();
+    let (main, read, flush) = (code.new_label(), code.new_label(), code.new_label());
+
+    /* _start: the stack is 16-byte aligned, and nothing else needs to be set up */
+    // mov rdi, #tape
+    // mov rsi, #context
+    // xor edx, edx
+    // call main
+    // mov rbx, rax      /* the status */
+    // mov r12, rdx      /* and the data pointer */
+    // mov r13, #context
+    code.emit_imm64([0x48, 0xbf], addresses.tape);
+    code.emit_imm64([0x48, 0xbe], addresses.context);
+    code.emit(&[0x31, 0xd2]);
+    code.emit_branch(CALL, main);
+    code.emit(&[0x48, 0x89, 0xc3]);
+    code.emit(&[0x49, 0x89, 0xd4]);
+    code.emit_imm64([0x49, 0xbd], addresses.context);
+
+    /* I/O errors where writing failed, and cells that do not fit in a byte, look up other messages */
+    // movzx eax, bl
+    // cmp eax, #IO
+    // jne not_io
+    // cmp byte [r13 + FAILED], 0
+    // je flush_rest
+    // add rbx, #OTHER_CAUSE
+    // jmp flush_rest
+    let (not_io, flush_rest) = (code.new_label(), code.new_label());
+    code.emit(&[0x0f, 0xb6, 0xc3]);
+    code.emit(&[0x83, 0xf8, Trap::Io as u8]);
+    code.emit_branch(JNE, not_io);
+    code.emit(&[0x41, 0x80, 0x7d, CONTEXT_FAILED as u8, 0x00]);
+    code.emit_branch(JE, flush_rest);
+    code.emit(&[0x48, 0x81, 0xc3]);
+    code.emit(&(OTHER_CAUSE as u32).to_le_bytes());
+    code.emit_branch(JMP, flush_rest);
+
+    // not_io:
+    // cmp eax, #OUTPUT
+    // jne flush_rest
+    // mov rcx, #tape
+    // mov eax, [rcx + r12 * 4]
+    // cmp eax, 255
+    // jbe flush_rest
+    // add rbx, #OTHER_CAUSE
+    code.bind(not_io);
+    code.emit(&[0x83, 0xf8, Trap::Output as u8]);
+    code.emit_branch(JNE, flush_rest);
+    code.emit_imm64([0x48, 0xb9], addresses.tape);
+    code.emit(&[0x42, 0x8b, 0x04, 0xa1]);
+    code.emit(&[0x3d]);
+    code.emit(&255u32.to_le_bytes());
+    code.emit_branch(JBE, flush_rest);
+    code.emit(&[0x48, 0x81, 0xc3]);
+    code.emit(&(OTHER_CAUSE as u32).to_le_bytes());
+
+    /* What was written before a trap still goes out */
+    // flush_rest:
+    // mov rdi, r13
+    // call flush
+    // test rbx, rbx
+    // jne lookup
+    // cmp byte [r13 + FAILED], 0
+    // je lookup
+    // mov rbx, #end_key
+    let lookup = code.new_label();
+    code.bind(flush_rest);
+    code.emit(&[0x4c, 0x89, 0xef]);
+    code.emit_branch(CALL, flush);
+    code.emit(&[0x48, 0x85, 0xdb]);
+    code.emit_branch(JNE, lookup);
+    code.emit(&[0x41, 0x80, 0x7d, CONTEXT_FAILED as u8, 0x00]);
+    code.emit_branch(JE, lookup);
+    code.emit_imm64([0x48, 0xbb], addresses.end_key);
+
+    /* The table has an entry for every status */
+    // lookup:
+    // mov rcx, #table
+    // next:
+    // cmp rbx, [rcx]
+    // je found
+    // add rcx, #TABLE_ENTRY_SIZE
+    // jmp next
+    let (next, found) = (code.new_label(), code.new_label());
+    code.bind(lookup);
+    code.emit_imm64([0x48, 0xb9], addresses.table);
+    code.bind(next);
+    code.emit(&[0x48, 0x3b, 0x19]);
+    code.emit_branch(JE, found);
+    code.emit(&[0x48, 0x83, 0xc1, TABLE_ENTRY_SIZE as u8]);
+    code.emit_branch(JMP, next);
+
+    // found:
+    // mov r12, [rcx + 24]   /* the exit code, as rcx does not survive system calls */
+    // mov rsi, [rcx + 8]
+    // mov rdx, [rcx + 16]
+    // mov edi, 2
+    // mov eax, #SYS_WRITE
+    // syscall               /* the message, which is empty on success */
+    // mov rdi, r12
+    // mov eax, #SYS_EXIT
+    // syscall
+    code.bind(found);
+    code.emit(&[0x4c, 0x8b, 0x61, 0x18]);
+    code.emit(&[0x48, 0x8b, 0x71, 0x08]);
+    code.emit(&[0x48, 0x8b, 0x51, 0x10]);
+    code.emit(&[0xbf, 0x02, 0x00, 0x00, 0x00]);
+    code.emit(&[0xb8]);
+    code.emit(&SYS_WRITE.to_le_bytes());
+    code.emit(&[0x0f, 0x05]);
+    code.emit(&[0x4c, 0x89, 0xe7]);
+    code.emit(&[0xb8]);
+    code.emit(&SYS_EXIT.to_le_bytes());
+    code.emit(&[0x0f, 0x05]);
+
+    /* flush(context): writes out the buffer, or sets FAILED and returns 1 */
+    // mov r8, rdi
+    // mov rsi, [r8 + 16]
+    // mov rdx, [r8 + 24]
+    // mov qword [r8 + 24], 0
+    // again:
+    // test rdx, rdx
+    // je done
+    // mov edi, 1
+    // mov eax, #SYS_WRITE
+    // syscall
+    // test rax, rax
+    // jle failed
+    // add rsi, rax
+    // sub rdx, rax
+    // jmp again
+    // done:
+    // xor eax, eax
+    // ret
+    // failed:
+    // mov byte [r8 + FAILED], 1
+    // mov eax, 1
+    // ret
+    let (again, done, failed) = (code.new_label(), code.new_label(), code.new_label());
+    code.bind(flush);
+    let flush_offset = code.bytes.len();
+    code.emit(&[0x49, 0x89, 0xf8]);
+    code.emit(&[0x49, 0x8b, 0x70, 0x10]);
+    code.emit(&[0x49, 0x8b, 0x50, 0x18]);
+    code.emit(&[0x49, 0xc7, 0x40, 0x18, 0x00, 0x00, 0x00, 0x00]);
+    code.bind(again);
+    code.emit(&[0x48, 0x85, 0xd2]);
+    code.emit_branch(JE, done);
+    code.emit(&[0xbf, 0x01, 0x00, 0x00, 0x00]);
+    code.emit(&[0xb8]);
+    code.emit(&SYS_WRITE.to_le_bytes());
+    code.emit(&[0x0f, 0x05]);
+    code.emit(&[0x48, 0x85, 0xc0]);
+    code.emit_branch(JLE, failed);
+    code.emit(&[0x48, 0x01, 0xc6]);
+    code.emit(&[0x48, 0x29, 0xc2]);
+    code.emit_branch(JMP, again);
+    code.bind(done);
+    code.emit(&[0x31, 0xc0]);
+    code.emit(&[0xc3]);
+    code.bind(failed);
+    code.emit(&[0x41, 0xc6, 0x40, CONTEXT_FAILED as u8, 0x01]);
+    code.emit(&[0xb8, 0x01, 0x00, 0x00, 0x00]);
+    code.emit(&[0xc3]);
+
+    /* read(context, cell): flushes first, and reads a byte into the cell, which stays 0 at EOF */
+    // push rsi
+    // call flush
+    // pop rsi
+    // test eax, eax
+    // jne return
+    // mov dword [rsi], 0
+    // xor edi, edi
+    // mov edx, 1
+    // mov eax, #SYS_READ
+    // syscall
+    // shr rax, 63           /* 1 for an error, which is negative */
+    // return:
+    // ret
+    let ret = code.new_label();
+    code.bind(read);
+    let read_offset = code.bytes.len();
+    code.emit(&[0x56]);
+    code.emit_branch(CALL, flush);
+    code.emit(&[0x5e]);
+    code.emit(&[0x85, 0xc0]);
+    code.emit_branch(JNE, ret);
+    code.emit(&[0xc7, 0x06, 0x00, 0x00, 0x00, 0x00]);
+    code.emit(&[0x31, 0xff]);
+    code.emit(&[0xba, 0x01, 0x00, 0x00, 0x00]);
+    code.emit(&[0xb8]);
+    code.emit(&SYS_READ.to_le_bytes());
+    code.emit(&[0x0f, 0x05]);
+    code.emit(&[0x48, 0xc1, 0xe8, 0x3f]);
+    code.bind(ret);
+    code.emit(&[0xc3]);
+
+    code.bind(main);
+    Runtime {
+        code: code.finish(),
+        read: read_offset,
+        flush: flush_offset,
+    }
+}
+
+// `bf_main` in a relocatable object, which falls through into the generated code with the data
+// pointer at the first cell, as `_start` calls it
+pub fn object_entry() -> Vec<u8> {
+    // xor edx, edx
+    vec![0x31, 0xd2]
+}
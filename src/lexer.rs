@@ -49,7 +49,7 @@ mod tests {
             OpKind::Jne0Backward,
         ];
 
-        for (op, exp) in ops.into_iter().zip(expected.into_iter()) {
+        for (op, exp) in ops.into_iter().zip(expected) {
             let input = op.to_string();
             let mut lexer = Lexer::new(&input);
             assert_eq!(lexer.next(), Some(exp));
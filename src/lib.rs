@@ -1,11 +1,25 @@
+mod debugger;
 mod interpreter;
 mod ir;
 mod jitc;
 mod lexer;
 mod op;
+mod repl;
+#[cfg(test)]
+mod testing;
+mod tiered;
+mod transpile;
 
-pub use interpreter::interpret;
-pub use jitc::jit_compile;
+pub use debugger::Debugger;
+pub use interpreter::{interpret, Action, Event, History, Interpreter, Status, Watch, WatchHit};
+pub use ir::{generate_ops, load_ops, parse_ir, print_ir, source_map, SourcePos};
+pub use jitc::{
+    build_executable, build_object, emit_code, jit_compile, jit_compile_ops, jit_compile_ops_with,
+    print_asm, JitOptions, JitProgram, MachineCode, RunStats, Target,
+};
+pub use repl::Repl;
+pub use tiered::Tiered;
+pub use transpile::{emit_c, emit_rust, emit_wasm, emit_wat};
 
 pub const MEM_SIZE: usize = 2usize.pow(16);
 pub type Memory = [op::Operand; MEM_SIZE];
@@ -1,34 +1,249 @@
-use bfvm::{interpret, jit_compile, Memory, MEM_SIZE};
-use std::io::{stdin, stdout, Result};
-use std::{env, fs, mem};
+use bfvm::{
+    build_executable, build_object, emit_c, emit_code, emit_rust, emit_wasm, emit_wat,
+    jit_compile_ops_with, load_ops, print_asm, print_ir, source_map, Debugger, History,
+    Interpreter, JitOptions, Memory, Repl, Target, Tiered, MEM_SIZE,
+};
+use std::io::{empty, stdin, stdout, Read, Result, Write};
+use std::{env, fs, process};
+
+// what `--emit=<kind>` writes to stdout instead of running the program
+#[derive(Clone, Copy)]
+enum Emit {
+    Ir,
+    Bin,
+    Asm,
+    Obj,
+    C,
+    Rust,
+    Wasm,
+    Wat,
+}
+
+const EMITTERS: [(&str, Emit, &str); 8] = [
+    ("ir", Emit::Ir, "the ops as textual IR"),
+    ("bin", Emit::Bin, "the JIT's machine code"),
+    ("asm", Emit::Asm, "a listing of the JIT's machine code"),
+    ("obj", Emit::Obj, "an object file that exports `bf_main`"),
+    ("c", Emit::C, "a C99 program, with `#line`s to the source"),
+    ("rust", Emit::Rust, "a Rust module with `run` on a tape"),
+    ("wasm", Emit::Wasm, "a WebAssembly module importing I/O"),
+    ("wat", Emit::Wat, "the WebAssembly module as text"),
+];
+
+fn usage() -> String {
+    let kinds: Vec<_> = EMITTERS.iter().map(|(kind, ..)| *kind).collect();
+    let mut usage = format!(
+        "\
+USAGE: cargo run -r -q -- <filepath> [--no-jit | --tiered] [--debug [--input <filepath>]]
+       cargo run -r -q -- <filepath> [--perf-map] [--gdb]
+       cargo run -r -q -- <filepath> --emit=<{}> [--target=<x86_64|aarch64>]
+       cargo run -r -q -- build <filepath> -o <output> [--target=<x86_64|aarch64>]
+       cargo run -r -q -- repl
+
+Files ending in `.ir` are read as textual IR instead of brainf***. `--tiered` interprets and
+compiles only hot loops. `--perf-map` and `--gdb` describe JIT-compiled code to perf and gdb.
+`build` writes a static Linux executable that runs the program without bfvm. `--emit` writes
+one of these instead of running the program, where `bin`, `asm` and `obj` are for this host or
+for `--target`:",
+        kinds.join("|")
+    );
+    for (kind, _, description) in EMITTERS {
+        usage.push_str(&format!("\n  {kind:<6}{description}"));
+    }
+    usage
+}
 
 fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 || args.len() > 4 {
-        eprintln!("USAGE: cargo run -r -q -- <filepath> [--no-jit]");
-        return Ok(());
+    let mut file_path = None;
+    let mut jit_off = false;
+    let mut tiered = false;
+    let mut debug = false;
+    let mut input_path = None;
+    let mut emit = None;
+    let mut target = Target::host();
+    let mut jit_options = JitOptions::default();
+
+    let mut args = env::args().skip(1).peekable();
+    if args.peek().map(|arg| &arg[..]) == Some("repl") {
+        let mut memory: Memory = [0; MEM_SIZE];
+        return Repl::new(&mut memory).run(stdin().lock(), stdout());
+    }
+    if args.peek().map(|arg| &arg[..]) == Some("build") {
+        args.next();
+        return build(args, target);
     }
 
-    // TODO: make order insensitive
-    let file_path = &args[1];
-    let jit_off = args.get(2).map(|option| &option[..]) == Some("--no-jit");
-    let input = fs::read_to_string(file_path)?;
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--no-jit" => jit_off = true,
+            "--tiered" => tiered = true,
+            "--debug" => debug = true,
+            "--input" if input_path.is_none() => input_path = args.next(),
+            _ if arg.starts_with("--emit=") => {
+                match EMITTERS
+                    .iter()
+                    .find(|(kind, ..)| arg["--emit=".len()..] == **kind)
+                {
+                    Some((_, kind, _)) => emit = Some(*kind),
+                    None => {
+                        eprintln!("{}", usage());
+                        return Ok(());
+                    }
+                }
+            }
+            "--perf-map" => jit_options.perf_map = true,
+            "--gdb" => jit_options.gdb = true,
+            _ if arg.starts_with("--target=") => match Target::from_name(&arg["--target=".len()..])
+            {
+                Some(name) => target = Some(name),
+                None => {
+                    eprintln!("{}", usage());
+                    return Ok(());
+                }
+            },
+            _ if file_path.is_none() && !arg.starts_with("--") => file_path = Some(arg),
+            _ => {
+                eprintln!("{}", usage());
+                return Ok(());
+            }
+        }
+    }
+    let Some(file_path) = file_path else {
+        eprintln!("{}", usage());
+        return Ok(());
+    };
+    let input = fs::read_to_string(&file_path)?;
+    let ops = match load_ops(&file_path, &input) {
+        Ok(ops) => ops,
+        Err(e) => {
+            eprintln!("{e}");
+            return Ok(());
+        }
+    };
+
+    if let Some(kind) = emit {
+        // IR is its own source, and brainf*** maps back to lines and columns
+        let positions = (!file_path.ends_with(".ir")).then(|| source_map(&input));
+        let (source_path, positions) = (
+            positions.is_some().then_some(&file_path[..]),
+            positions.as_deref(),
+        );
+        let emitted = match (kind, target) {
+            (Emit::Ir, _) => Ok(print_ir(&ops).into_bytes()),
+            (Emit::C, _) => Ok(emit_c(&ops, source_path, positions).into_bytes()),
+            (Emit::Rust, _) => Ok(emit_rust(&ops).into_bytes()),
+            (Emit::Wasm, _) => Ok(emit_wasm(&ops)),
+            (Emit::Wat, _) => Ok(emit_wat(&ops).into_bytes()),
+            (Emit::Bin | Emit::Asm | Emit::Obj, None) => {
+                eprintln!(
+                    "JIT compiler is not supported on this architecture with OS, so pass --target"
+                );
+                return Ok(());
+            }
+            (Emit::Bin, Some(target)) => emit_code(&ops, target).map(|code| code.bytes),
+            (Emit::Asm, Some(target)) => emit_code(&ops, target)
+                .map(|code| print_asm(&code, target, &ops, positions).into_bytes()),
+            (Emit::Obj, Some(target)) => build_object(&ops, target, source_path, positions),
+        };
+        match emitted {
+            Ok(bytes) => stdout().lock().write_all(&bytes)?,
+            Err(e) => eprintln!("{e}"),
+        }
+        return Ok(());
+    }
 
     let mut memory: Memory = [0; MEM_SIZE];
-    if jit_off {
+    if debug {
+        // debugger commands come from stdin, so the program reads its input from a file
+        let program_input: Box<dyn Read> = match input_path {
+            Some(path) => Box::new(fs::File::open(path)?),
+            None => Box::new(empty()),
+        };
+        let mut debugger = Debugger::new(ops, &mut memory, History::default());
+        debugger.session(stdin().lock(), stdout(), program_input, stdout())?;
+    } else if jit_off {
+        let stdin = stdin().lock();
+        let stdout = stdout().lock();
+        if let Err(e) = Interpreter::new(ops, &mut memory).run(stdin, stdout) {
+            eprintln!("{e}");
+        }
+    } else if tiered {
         let stdin = stdin().lock();
         let stdout = stdout().lock();
-        if let Err(e) = interpret(&input, &mut memory, stdin, stdout) {
+        if let Err(e) = Tiered::new(ops, &mut memory).run(stdin, stdout) {
             eprintln!("{e}");
         }
     } else {
-        match jit_compile(&input, &mut memory) {
-            Ok(code) => unsafe {
-                (mem::transmute::<*const u8, extern "C" fn()>(code.as_ptr()))();
-            },
+        if !file_path.ends_with(".ir") {
+            jit_options.positions = Some(source_map(&input));
+        }
+        jit_options.source_path = Some(file_path);
+        match jit_compile_ops_with(&ops, &jit_options) {
+            Ok(mut program) => {
+                let stdin = stdin().lock();
+                let stdout = stdout().lock();
+                if let Err(e) = program.run(&mut memory, stdin, stdout) {
+                    eprintln!("{e}");
+                }
+            }
             Err(e) => eprintln!("{e}"),
         }
     }
 
     Ok(())
 }
+
+// exits with 1 on any failure, so that scripts do not go on without the executable
+fn build(mut args: impl Iterator<Item = String>, mut target: Option<Target>) -> Result<()> {
+    let mut file_path = None;
+    let mut output_path = None;
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "-o" if output_path.is_none() => output_path = args.next(),
+            _ if arg.starts_with("--target=") => match Target::from_name(&arg["--target=".len()..])
+            {
+                Some(name) => target = Some(name),
+                None => {
+                    eprintln!("{}", usage());
+                    process::exit(1);
+                }
+            },
+            _ if file_path.is_none() && !arg.starts_with('-') => file_path = Some(arg),
+            _ => {
+                eprintln!("{}", usage());
+                process::exit(1);
+            }
+        }
+    }
+    let (Some(file_path), Some(output_path)) = (file_path, output_path) else {
+        eprintln!("{}", usage());
+        process::exit(1);
+    };
+    let Some(target) = target else {
+        eprintln!("JIT compiler is not supported on this architecture with OS, so pass --target");
+        process::exit(1);
+    };
+
+    let input = fs::read_to_string(&file_path)?;
+    let ops = match load_ops(&file_path, &input) {
+        Ok(ops) => ops,
+        Err(e) => {
+            eprintln!("{e}");
+            process::exit(1);
+        }
+    };
+    let executable = match build_executable(&ops, target) {
+        Ok(executable) => executable,
+        Err(e) => {
+            eprintln!("{e}");
+            process::exit(1);
+        }
+    };
+    fs::write(&output_path, executable)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&output_path, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
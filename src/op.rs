@@ -1,4 +1,4 @@
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OpKind {
     Inc,
     Dec,
@@ -12,7 +12,7 @@ pub enum OpKind {
 
 pub type Operand = i32;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Op {
     pub kind: OpKind,
     pub operand: Operand,
@@ -0,0 +1,272 @@
+use crate::debugger::{format_tape, parse_range};
+use crate::interpreter::*;
+use crate::ir::*;
+use crate::op::Op;
+use crate::*;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+
+const HELP: &str = "\
+:reset              clear the tape and move the data pointer back to 0
+:tape <start..end>  show (and keep showing) the cells in the range
+:load <filepath>    run a file against the current tape
+:ops                show the IR of the last line (or of the unfinished loop)
+:quit";
+
+pub struct Repl<'a> {
+    interpreter: Interpreter<'a>,
+    builder: OpsBuilder,
+    window: Range<usize>,
+}
+
+impl<'a> Repl<'a> {
+    pub fn new(memory: &'a mut Memory) -> Self {
+        Repl {
+            interpreter: Interpreter::new(Vec::new(), memory),
+            builder: OpsBuilder::default(),
+            window: 0..16,
+        }
+    }
+
+    pub fn interpreter(&self) -> &Interpreter<'a> {
+        &self.interpreter
+    }
+
+    // `,` in a line reads from the same input the lines come from
+    pub fn run<I, O>(&mut self, mut input: I, out: O) -> io::Result<()>
+    where
+        I: BufRead,
+        O: Write,
+    {
+        let mut out = LineWriter::new(out);
+        let mut line = String::new();
+        loop {
+            let prompt = if self.builder.open_loops() > 0 {
+                "... "
+            } else {
+                "bf> "
+            };
+            write!(out, "{prompt}")?;
+            out.flush()?;
+
+            line.clear();
+            if input.read_line(&mut line)? == 0 {
+                return writeln!(out);
+            }
+            out.at_line_start = true; // the user typed a newline
+
+            match line.trim().strip_prefix(':') {
+                Some(command) => {
+                    if !self.meta(command, &mut input, &mut out)? {
+                        return Ok(());
+                    }
+                }
+                None => self.eval(&line, &mut input, &mut out)?,
+            }
+        }
+    }
+
+    // returns false on `:quit`
+    fn meta<I, O>(&mut self, command: &str, input: I, out: &mut LineWriter<O>) -> io::Result<bool>
+    where
+        I: BufRead,
+        O: Write,
+    {
+        let (name, arg) = command.split_once(' ').unwrap_or((command, ""));
+        let arg = arg.trim();
+        match name {
+            "reset" => {
+                self.interpreter.reset();
+                self.builder = OpsBuilder::default();
+                self.print_tape(out)?;
+            }
+            "tape" => match parse_range(arg) {
+                Ok(window) => {
+                    self.window = window;
+                    self.print_tape(out)?;
+                }
+                Err(e) => writeln!(out, "{e}")?,
+            },
+            // a file is a whole program of its own, even while a loop is open
+            "load" => match fs::read_to_string(arg) {
+                Ok(program) => {
+                    let mut builder = OpsBuilder::default();
+                    match builder.feed(&program).and_then(|_| builder.finish()) {
+                        Ok(ops) => self.execute(ops, input, out)?,
+                        Err(e) => writeln!(out, "{e}")?,
+                    }
+                }
+                Err(e) => writeln!(out, "cannot load `{arg}` ({e})")?,
+            },
+            "ops" => {
+                let ops = match self.builder.ops() {
+                    [] => self.interpreter.ops(),
+                    pending => pending,
+                };
+                write!(out, "{}", print_ir(ops))?;
+            }
+            "help" | "h" => writeln!(out, "{HELP}")?,
+            "quit" | "q" => return Ok(false),
+            _ => writeln!(out, "unknown command `:{name}` (see `:help`)")?,
+        }
+        Ok(true)
+    }
+
+    fn eval<I, O>(&mut self, source: &str, input: I, out: &mut LineWriter<O>) -> io::Result<()>
+    where
+        I: BufRead,
+        O: Write,
+    {
+        if let Err(e) = self.builder.feed(source) {
+            self.builder = OpsBuilder::default();
+            return writeln!(out, "{e}");
+        }
+        let Some(ops) = self.builder.take() else {
+            return Ok(()); // wait for the rest of the loop
+        };
+        self.execute(ops, input, out)
+    }
+
+    fn execute<I, O>(&mut self, ops: Vec<Op>, input: I, out: &mut LineWriter<O>) -> io::Result<()>
+    where
+        I: BufRead,
+        O: Write,
+    {
+        self.interpreter.load(ops);
+        if let Err(e) = self.interpreter.run(input, &mut *out) {
+            out.finish_line()?;
+            writeln!(out, "{e}")?;
+        }
+        self.print_tape(out)
+    }
+
+    fn print_tape<O: Write>(&self, out: &mut LineWriter<O>) -> io::Result<()> {
+        out.finish_line()?;
+        let (memory, dp) = (self.interpreter.memory(), self.interpreter.dp());
+        writeln!(
+            out,
+            "{} (dp: {dp})",
+            format_tape(memory, dp, self.window.clone())
+        )
+    }
+}
+
+// Remembers whether the program left the cursor in the middle of a line, so that the tape
+// is always shown on a line of its own.
+struct LineWriter<W: Write> {
+    inner: W,
+    at_line_start: bool,
+}
+
+impl<W: Write> LineWriter<W> {
+    fn new(inner: W) -> Self {
+        LineWriter {
+            inner,
+            at_line_start: true,
+        }
+    }
+
+    fn finish_line(&mut self) -> io::Result<()> {
+        if self.at_line_start {
+            return Ok(());
+        }
+        writeln!(self)
+    }
+}
+
+impl<W: Write> Write for LineWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(last) = buf[..written].last() {
+            self.at_line_start = *last == b'\n';
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::io::Cursor;
+
+    fn run_repl(lines: &str) -> (String, Memory) {
+        let mut memory: Memory = [0; MEM_SIZE];
+        let mut out = Vec::new();
+        Repl::new(&mut memory)
+            .run(Cursor::new(lines), &mut out)
+            .unwrap();
+        (String::from_utf8(out).unwrap(), memory)
+    }
+
+    #[test]
+    fn should_keep_tape_across_lines() {
+        let (out, memory) = run_repl("+++\n>++\n<-\n");
+        assert!(out.contains("0..16: [2] 2 0"));
+        assert_eq!(memory[..2], [2, 2]);
+    }
+
+    #[test]
+    fn should_continue_open_loops_on_next_line() {
+        let (out, memory) = run_repl("++++[\n>++\n<-]\n:ops\n");
+        assert_eq!(out.matches("... ").count(), 2);
+        assert!(out.contains("inc 4\nloop {\n    right 1\n    inc 2\n"));
+        assert_eq!(memory[..2], [0, 8]);
+    }
+
+    #[test]
+    fn should_handle_meta_commands() {
+        let (out, memory) = run_repl("+++>+\n:tape 0..2\n:reset\n:tape 2\n:bogus\n+\n:q\n+\n");
+        assert!(out.contains("0..2: 3 [1] (dp: 1)"));
+        assert!(out.contains("0..2: [0] 0 (dp: 0)"));
+        assert!(out.contains("invalid range `2`"));
+        assert!(out.contains("unknown command `:bogus`"));
+        assert_eq!(memory[..2], [1, 0]);
+    }
+
+    #[test]
+    fn should_recover_from_errors() {
+        let (out, memory) = run_repl("+]\n<\n+.\n");
+        assert!(out.contains("(`]` exceeds)"));
+        assert!(out.contains("RUNTIME ERROR: data pointer is negative [IP:0]"));
+        assert!(out.contains("\u{1}\n0..16: [1]"));
+        assert_eq!(memory[0], 1);
+    }
+
+    #[test]
+    fn should_count_error_positions_from_the_start_of_the_line() {
+        let (out, _) = run_repl(
+            "+++>
++]
+[
+-]]
+",
+        );
+        assert!(out.contains("(`]` exceeds) [IDX:1]\n"));
+        // a loop that spans lines counts from its first line
+        assert!(out.contains("(`]` exceeds) [IDX:3]\n"));
+    }
+
+    #[test]
+    fn should_load_files_apart_from_open_loops() {
+        let dir = TempDir::new("repl");
+        let (open, add) = (dir.path().join("open.bf"), dir.path().join("add.bf"));
+        fs::write(&open, "+[").unwrap();
+        fs::write(&add, "+++").unwrap();
+
+        let (out, memory) = run_repl(&format!(":load {}\n+\n", open.display()));
+        assert!(out.contains("invalid program: `[` and `]` should match (1 `[`s left)\n"));
+        assert!(!out.contains("... "));
+        assert_eq!(memory[0], 1);
+
+        // `+++` runs on its own, and then the loop that was open clears the cell
+        let (out, memory) = run_repl(&format!("+[\n:load {}\n-]\n", add.display()));
+        assert!(out.contains("0..16: [3]"));
+        assert_eq!(memory[0], 0);
+    }
+}
@@ -0,0 +1,132 @@
+// What the interpreter does with a program, which tests compare every other engine with
+use crate::interpreter::Interpreter;
+use crate::op::Op;
+use crate::{Memory, MEM_SIZE};
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const HELLO: &str = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.";
+
+// Programs, with their input, that every engine should run as the interpreter does: nested loops,
+// I/O up to EOF, and each runtime error
+pub fn programs() -> Vec<(String, &'static [u8])> {
+    let programs: [(&str, &[u8]); 8] = [
+        (HELLO, b""),
+        (",[.,]", b"echo"),
+        (",,,.", b"ab"),
+        ("+[-][]", b""),
+        ("", b""),
+        ("+.<", b""),
+        ("+[>+]", b""),
+        ("-.", b""),
+    ];
+    let mut programs: Vec<_> = programs
+        .into_iter()
+        .map(|(program, input)| (program.to_string(), input))
+        .collect();
+    programs.push((format!("{}.", "+".repeat(200)), b""));
+    programs
+}
+
+pub struct Run {
+    pub result: Result<(), String>,
+    pub output: Vec<u8>,
+    pub memory: Box<Memory>,
+    pub dp: usize,
+}
+
+impl Run {
+    // as a standalone program exits: with the error on stderr and status 1
+    pub fn process(self) -> Process {
+        let (stderr, exit_code) = match self.result {
+            Ok(()) => (Vec::new(), 0),
+            Err(e) => (format!("{e}\n").into_bytes(), 1),
+        };
+        Process {
+            stdout: self.output,
+            stderr,
+            exit_code,
+        }
+    }
+}
+
+pub fn interpret(ops: &[Op], input: &[u8]) -> Run {
+    let mut memory = Box::new([0; MEM_SIZE]);
+    let mut output = Vec::new();
+    let mut interpreter = Interpreter::new(ops.to_vec(), &mut memory);
+    let result = interpreter
+        .run(Cursor::new(input), &mut output)
+        .map(|_| ())
+        .map_err(|e| e.to_string());
+    let dp = interpreter.dp();
+    Run {
+        result,
+        output,
+        memory,
+        dp,
+    }
+}
+
+// What a program did, when it ran as a process
+#[derive(Debug, PartialEq)]
+pub struct Process {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub exit_code: i32,
+}
+
+// runs `command` with `input` on stdin, and stdout going to /dev/full when `stdout_fails`, as
+// `run_process` does in the emulator
+pub fn run_command(command: &mut Command, input: &[u8], stdout_fails: bool) -> Process {
+    let stdout = if stdout_fails {
+        Stdio::from(
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open("/dev/full")
+                .unwrap(),
+        )
+    } else {
+        Stdio::piped()
+    };
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(stdout)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    let output = child.wait_with_output().unwrap();
+    Process {
+        stdout: output.stdout,
+        stderr: output.stderr,
+        exit_code: output.status.code().unwrap(),
+    }
+}
+
+// A directory of its own in the system's temporary one, which is removed when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(prefix: &str) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "bfvm-{prefix}-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
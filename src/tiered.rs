@@ -0,0 +1,161 @@
+use crate::interpreter::{Interpreter, RuntimeError};
+use crate::jitc::{jit_compile_loop, JitProgram};
+use crate::op::*;
+use crate::Memory;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+// how many times a loop jumps back before it is compiled
+pub const DEFAULT_THRESHOLD: u32 = 1000;
+
+// Starts in the interpreter and counts how often each `]` jumps back. Once a loop crosses the
+// threshold, that loop alone is compiled and run natively from the current data pointer on the
+// same tape, and the interpreter takes over again after it exits. Later entries to the loop go
+// straight to the compiled code. Where the JIT is not supported, everything is interpreted.
+pub struct Tiered<'a> {
+    interpreter: Interpreter<'a>,
+    threshold: u32,
+    back_edges: Vec<u32>,                 // by the ip of `]`
+    compiled: HashMap<usize, JitProgram>, // by the ip of `[`
+}
+
+impl<'a> Tiered<'a> {
+    pub fn new(ops: Vec<Op>, memory: &'a mut Memory) -> Self {
+        Tiered {
+            back_edges: vec![0; ops.len()],
+            interpreter: Interpreter::new(ops, memory),
+            threshold: DEFAULT_THRESHOLD,
+            compiled: HashMap::new(),
+        }
+    }
+
+    pub fn with_threshold(mut self, threshold: u32) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn interpreter(&self) -> &Interpreter<'a> {
+        &self.interpreter
+    }
+
+    // the ips of the `[`s of the loops that have been compiled so far
+    pub fn compiled_loops(&self) -> Vec<usize> {
+        let mut heads: Vec<usize> = self.compiled.keys().copied().collect();
+        heads.sort();
+        heads
+    }
+
+    pub fn run<R, W>(&mut self, mut stdin: R, mut stdout: W) -> Result<(), RuntimeError>
+    where
+        R: Read,
+        W: Write,
+    {
+        loop {
+            let ip = self.interpreter.ip();
+            let Some(&Op { kind, operand }) = self.interpreter.ops().get(ip) else {
+                return Ok(());
+            };
+            let taken = self.interpreter.memory()[self.interpreter.dp()] != 0;
+            let head = match kind {
+                OpKind::Jeq0Forward if taken => Some(ip),
+                OpKind::Jne0Backward if taken => {
+                    let head = operand as usize - 1;
+                    let count = &mut self.back_edges[ip];
+                    if *count < self.threshold {
+                        *count += 1;
+                        // a loop that cannot be compiled stays in the interpreter
+                        if *count == self.threshold {
+                            if let Ok(program) = jit_compile_loop(self.interpreter.ops(), head) {
+                                self.compiled.insert(head, program);
+                            }
+                        }
+                    }
+                    Some(head)
+                }
+                _ => None,
+            };
+
+            match head.and_then(|head| Some((head, self.compiled.get_mut(&head)?))) {
+                Some((head, program)) => self.interpreter.run_loop(head, |tape, dp| {
+                    let stats = program.run_from(tape, dp, &mut stdin, &mut stdout)?;
+                    Ok(stats.dp)
+                })?,
+                None => {
+                    self.interpreter.step(&mut stdin, &mut stdout)?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jitc::Target;
+    use crate::testing::{interpret, programs, Run};
+    use crate::{generate_ops, MEM_SIZE};
+    use std::io::Cursor;
+
+    // and the loops that were compiled
+    fn run(program: &str, input: &[u8], threshold: u32) -> (Run, Vec<usize>) {
+        let mut memory = Box::new([0; MEM_SIZE]);
+        let mut output = Vec::new();
+        let mut tiered = Tiered::new(generate_ops(program), &mut memory).with_threshold(threshold);
+        let result = tiered.run(Cursor::new(input), &mut output);
+        let (dp, compiled) = (tiered.interpreter().dp(), tiered.compiled_loops());
+        let run = Run {
+            result: result.map_err(|e| e.to_string()),
+            output,
+            memory,
+            dp,
+        };
+        (run, compiled)
+    }
+
+    // the loops that are compiled with the lowest threshold
+    fn assert_same_as_interpreter(program: &str, input: &[u8]) -> Vec<usize> {
+        let expected = interpret(&generate_ops(program), input);
+        let mut compiled = Vec::new();
+        for threshold in [1, 2, 3, DEFAULT_THRESHOLD] {
+            let (tiered, loops) = run(program, input, threshold);
+            assert_eq!(tiered.result, expected.result, "{threshold}: {program}");
+            assert_eq!(tiered.output, expected.output, "{threshold}: {program}");
+            assert!(tiered.memory == expected.memory, "{threshold}: {program}");
+            // after an error in compiled code, the interpreter is still where the loop started
+            if expected.result.is_ok() {
+                assert_eq!(tiered.dp, expected.dp, "{threshold}: {program}");
+            }
+            if threshold == 1 {
+                compiled = loops;
+            }
+        }
+        compiled
+    }
+
+    #[test]
+    fn should_run_like_the_interpreter() {
+        for (program, input) in programs() {
+            assert_same_as_interpreter(&program, input);
+        }
+        assert_same_as_interpreter("++++[>+++[>++<-]<-]>>.", b"");
+        assert_same_as_interpreter(",[.,]", b"tiered");
+    }
+
+    #[test]
+    fn should_report_traps_in_compiled_loops_at_their_ip() {
+        assert_same_as_interpreter(">>>+[<+]", b"");
+        assert_same_as_interpreter("+++[->.<]", b"");
+    }
+
+    #[test]
+    fn should_compile_only_hot_loops() {
+        let compiled = assert_same_as_interpreter("+[-]++[>+++[-]<-]", b"");
+        if Target::host().is_some() {
+            // `+[-]` never jumps back, and the outer loop of the nested one jumps back only once
+            assert_eq!(compiled, [5, 8]);
+            assert_eq!(run("+[-]++[>+++[-]<-]", b"", 2).1, [8]);
+        } else {
+            assert_eq!(compiled, []);
+        }
+    }
+}
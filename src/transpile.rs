@@ -0,0 +1,9 @@
+// Programs in other languages that run the ops as the interpreter does: the same output, and the
+// same runtime errors at the same ips
+mod c;
+mod rust;
+mod wasm;
+
+pub use c::emit_c;
+pub use rust::emit_rust;
+pub use wasm::{emit_wasm, emit_wat};
@@ -0,0 +1,234 @@
+use crate::ir::SourcePos;
+use crate::op::*;
+use crate::MEM_SIZE;
+use std::fmt::Write;
+
+const INDENT: &str = "    ";
+
+// Cells are `Operand`s, which wrap around as in the JIT, and unsigned arithmetic does that without
+// undefined behavior
+const PRELUDE: &str = r#"#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+typedef uint32_t cell;
+
+static void fail(const char *message, unsigned long ip) {
+    fflush(stdout);
+    fprintf(stderr, "RUNTIME ERROR: %s [IP:%lu]\n", message, ip);
+    exit(1);
+}
+"#;
+
+// what each kind of op calls, which is left out when no op needs it
+const HELPERS: [(OpKind, &str); 4] = [
+    (
+        OpKind::Left,
+        r#"
+static size_t left(size_t dp, size_t amount, unsigned long ip) {
+    if (amount > dp) fail("data pointer is negative", ip);
+    return dp - amount;
+}
+"#,
+    ),
+    (
+        OpKind::Right,
+        r#"
+static size_t right(size_t dp, size_t amount, unsigned long ip) {
+    if (amount >= TAPE_SIZE - dp) fail("data pointer exceeded memory size", ip);
+    return dp + amount;
+}
+"#,
+    ),
+    (
+        OpKind::Input,
+        r#"
+/* output is flushed before reading, and the cell is 0 at EOF */
+static cell input(cell value, unsigned long count, unsigned long ip) {
+    for (; count > 0; count--) {
+        int c;
+        if (fflush(stdout) != 0) fail("cannot write to stdout", ip);
+        c = getchar();
+        if (c == EOF && ferror(stdin)) fail("cannot read from stdin", ip);
+        value = c == EOF ? 0 : (cell)c;
+    }
+    return value;
+}
+"#,
+    ),
+    (
+        OpKind::Output,
+        r#"
+static void output(cell value, unsigned long count, unsigned long ip) {
+    for (; count > 0; count--) {
+        if (value > 255) fail("cannot reinterpret the byte into char", ip);
+        if (value > 127) fail("the value is not in the ASCII range", ip);
+        if (putchar((int)value) == EOF) fail("cannot write to stdout", ip);
+    }
+}
+"#,
+    ),
+];
+
+// `path` as a C string literal
+fn quote(path: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in path.chars() {
+        match c {
+            '"' | '\\' => write!(quoted, "\\{c}").unwrap(),
+            '\n' => quoted.push_str("\\n"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// A C99 program that runs `ops` with a tape of `MEM_SIZE` cells and stdio. With `source_path`,
+// `#line` directives map the code of each op back to its line in the source. Output is buffered,
+// so a write that fails may be reported where the output is flushed, as by executables.
+pub fn emit_c(ops: &[Op], source_path: Option<&str>, positions: Option<&[SourcePos]>) -> String {
+    let mut c = format!("/* generated by bfvm */\n#define TAPE_SIZE {MEM_SIZE}\n{PRELUDE}");
+    for (kind, helper) in HELPERS {
+        if ops.iter().any(|op| op.kind == kind) {
+            c.push_str(helper);
+        }
+    }
+    // unused variables would warn
+    if ops.is_empty() {
+        c.push_str("\nint main(void) {\n");
+    } else {
+        c.push_str("\nstatic cell tape[TAPE_SIZE];\n\nint main(void) {\n    size_t dp = 0;\n");
+    }
+
+    let source = source_path.map(quote).zip(positions);
+    // ops from the same source line share a line of C, as `#line` cannot tell them apart
+    let (mut last_line, mut next_line) = (None, None); // numbered as in the source
+    let mut depth = 1;
+    for (ip, op) in ops.iter().enumerate() {
+        if op.kind == OpKind::Jne0Backward {
+            depth -= 1;
+        }
+        let (operand, amount) = (op.operand as u32, op.operand as usize);
+        let statement = match op.kind {
+            OpKind::Inc => format!("tape[dp] += {operand}u;"),
+            OpKind::Dec => format!("tape[dp] -= {operand}u;"),
+            OpKind::Left => format!("dp = left(dp, {amount}u, {ip});"),
+            OpKind::Right => format!("dp = right(dp, {amount}u, {ip});"),
+            OpKind::Input => format!("tape[dp] = input(tape[dp], {amount}u, {ip});"),
+            OpKind::Output => format!("output(tape[dp], {amount}u, {ip});"),
+            OpKind::Jeq0Forward => "while (tape[dp]) {".to_string(),
+            OpKind::Jne0Backward => "}".to_string(),
+        };
+        match source
+            .as_ref()
+            .and_then(|(path, s)| Some((path, s.get(ip)?.line)))
+        {
+            Some((_, line)) if last_line == Some(line) => {
+                c.pop();
+                writeln!(c, " {statement}").unwrap();
+            }
+            Some((path, line)) => {
+                if next_line != Some(line) {
+                    writeln!(c, "#line {line} {path}").unwrap();
+                }
+                writeln!(c, "{}{statement}", INDENT.repeat(depth)).unwrap();
+                (last_line, next_line) = (Some(line), Some(line + 1));
+            }
+            None => writeln!(c, "{}{statement}", INDENT.repeat(depth)).unwrap(),
+        }
+        if op.kind == OpKind::Jeq0Forward {
+            depth += 1;
+        }
+    }
+
+    writeln!(
+        c,
+        "    if (fflush(stdout) != 0) fail(\"cannot write to stdout\", {});\n    return 0;\n}}",
+        ops.len()
+    )
+    .unwrap();
+    c
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{interpret, programs, run_command, Process, TempDir};
+    use crate::{generate_ops, source_map};
+    use std::fs;
+    use std::process::Command;
+
+    #[test]
+    fn should_map_each_op_to_its_source_line() {
+        let input = "+\n\n[->\n+<]\n.";
+        let ops = generate_ops(input);
+        let c = emit_c(&ops, Some("dir/a \"b\".bf"), Some(&source_map(input)));
+        let body = c.split("size_t dp = 0;\n").nth(1).unwrap();
+        let expected = [
+            "#line 1 \"dir/a \\\"b\\\".bf\"",
+            "    tape[dp] += 1u;",
+            "#line 3 \"dir/a \\\"b\\\".bf\"",
+            "    while (tape[dp]) { tape[dp] -= 1u; dp = right(dp, 1u, 3);",
+            "        tape[dp] += 1u; dp = left(dp, 1u, 5); }",
+            "    output(tape[dp], 1u, 7);",
+        ];
+        assert_eq!(
+            body.lines().take(expected.len()).collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[test]
+    fn should_leave_out_line_directives_without_source() {
+        let c = emit_c(&generate_ops(",."), None, None);
+        assert!(!c.contains("#line"));
+        assert!(
+            c.contains("    tape[dp] = input(tape[dp], 1u, 0);\n    output(tape[dp], 1u, 1);\n")
+        );
+        assert!(c.contains("#define TAPE_SIZE 65536\n"));
+    }
+
+    // the compiled C program, unless there is no C compiler
+    #[cfg(unix)]
+    fn run_compiled(program: &str, input: &[u8]) -> Option<Process> {
+        let dir = TempDir::new("c");
+        fs::write(
+            dir.path().join("a.c"),
+            emit_c(&generate_ops(program), None, None),
+        )
+        .unwrap();
+        let compiled = Command::new("cc")
+            .current_dir(dir.path())
+            .args([
+                "-std=c99",
+                "-Wall",
+                "-Werror",
+                "-pedantic",
+                "a.c",
+                "-o",
+                "a",
+            ])
+            .status()
+            .ok()?;
+        assert!(compiled.success(), "{program}");
+        Some(run_command(
+            &mut Command::new(dir.path().join("a")),
+            input,
+            false,
+        ))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn should_run_like_the_interpreter() {
+        for (program, input) in programs() {
+            let Some(actual) = run_compiled(&program, input) else {
+                // no C compiler
+                return;
+            };
+            let expected = interpret(&generate_ops(&program), input).process();
+            assert_eq!(actual, expected, "{program}");
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use crate::op::*;
+use crate::MEM_SIZE;
+use std::fmt::Write;
+
+const INDENT: &str = "    ";
+
+// The error type, and the cells and tape size of the interpreter, so that the module needs
+// nothing but std
+const PRELUDE: &str = r#"use std::fmt;
+use std::io::{Read, Write};
+
+pub type Cell = i32;
+
+#[derive(Debug)]
+pub struct RuntimeError {
+    message: String,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RUNTIME ERROR: {}", self.message)
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+"#;
+
+// for programs that can fail at all
+const WITH_IP: &str = r#"
+impl RuntimeError {
+    fn with_ip(ip: usize, message: &str) -> Self {
+        RuntimeError {
+            message: format!("{message} [IP:{ip}]"),
+        }
+    }
+}
+"#;
+
+// what each kind of op calls, which is left out when no op needs it, as unused code would warn
+const HELPERS: [(OpKind, &str); 4] = [
+    (
+        OpKind::Left,
+        r#"
+fn left(dp: &mut usize, amount: usize, ip: usize) -> Result<(), RuntimeError> {
+    if *dp < amount {
+        return Err(RuntimeError::with_ip(ip, "data pointer is negative"));
+    }
+    *dp -= amount;
+    Ok(())
+}
+"#,
+    ),
+    (
+        OpKind::Right,
+        r#"
+fn right(dp: &mut usize, amount: usize, ip: usize) -> Result<(), RuntimeError> {
+    if *dp + amount >= TAPE_SIZE {
+        return Err(RuntimeError::with_ip(
+            ip,
+            "data pointer exceeded memory size",
+        ));
+    }
+    *dp += amount;
+    Ok(())
+}
+"#,
+    ),
+    (
+        OpKind::Input,
+        r#"
+// the cell is 0 at EOF
+fn read_cell<R: Read>(mut input: R, ip: usize) -> Result<Cell, RuntimeError> {
+    let mut byte = [0; 1];
+    input
+        .read(&mut byte)
+        .map_err(|e| RuntimeError::with_ip(ip, &format!("cannot read from stdin ({e})")))?;
+    Ok(byte[0] as Cell)
+}
+"#,
+    ),
+    (
+        OpKind::Output,
+        r#"
+fn write_cell<W: Write>(mut output: W, value: Cell, ip: usize) -> Result<(), RuntimeError> {
+    let byte: u8 = value
+        .try_into()
+        .map_err(|_| RuntimeError::with_ip(ip, "cannot reinterpret the byte into char"))?;
+    if !byte.is_ascii() {
+        return Err(RuntimeError::with_ip(
+            ip,
+            "the value is not in the ASCII range",
+        ));
+    }
+    output
+        .write_all(&[byte])
+        .map_err(|e| RuntimeError::with_ip(ip, &format!("cannot write to stdout ({e})")))
+}
+"#,
+    ),
+];
+
+// A Rust module with `run`, which does what the interpreter does with `ops`, on the first
+// `TAPE_SIZE` cells of a tape as the interpreter's, with the same errors. A shorter tape is an
+// error up front, so that indexing it never panics. Cells wrap around as in release builds and
+// the JIT. It uses nothing but safe code and std.
+pub fn emit_rust(ops: &[Op]) -> String {
+    let uses = |kinds: &[OpKind]| ops.iter().any(|op| kinds.contains(&op.kind));
+    let mut rust =
+        format!("// generated by bfvm\n{PRELUDE}\npub const TAPE_SIZE: usize = {MEM_SIZE};\n");
+    if uses(&HELPERS.map(|(kind, _)| kind)) {
+        rust.push_str(WITH_IP);
+    }
+    for (kind, helper) in HELPERS {
+        if uses(&[kind]) {
+            rust.push_str(helper);
+        }
+    }
+
+    // parameters and variables that the program does not need would warn
+    let unused = |used: bool, name: &str| {
+        if used {
+            format!("mut {name}")
+        } else {
+            format!("_{name}")
+        }
+    };
+    writeln!(
+        rust,
+        "\npub fn run<R: Read, W: Write>(\n    {}: &mut [Cell],\n    {}: R,\n    {}: W,\n) -> Result<(), RuntimeError> {{",
+        if ops.is_empty() { "_tape" } else { "tape" },
+        unused(uses(&[OpKind::Input]), "input"),
+        unused(uses(&[OpKind::Output]), "output"),
+    )
+    .unwrap();
+    if !ops.is_empty() {
+        rust.push_str(
+            "    if tape.len() < TAPE_SIZE {
+        return Err(RuntimeError {
+            message: format!(\"the tape should have at least {TAPE_SIZE} cells\"),
+        });
+    }
+",
+        );
+        let dp = if uses(&[OpKind::Left, OpKind::Right]) {
+            "mut dp"
+        } else {
+            "dp"
+        };
+        writeln!(rust, "    let {dp} = 0;").unwrap();
+    }
+
+    let mut depth = 1;
+    for (ip, op) in ops.iter().enumerate() {
+        if op.kind == OpKind::Jne0Backward {
+            depth -= 1;
+        }
+        let (operand, amount) = (op.operand, op.operand as usize);
+        let repeat = |statement: String| match amount {
+            1 => statement,
+            _ => format!(
+                "for _ in 0..{amount} {{\n{}{INDENT}{statement}\n{}}}",
+                INDENT.repeat(depth),
+                INDENT.repeat(depth)
+            ),
+        };
+        let statement = match op.kind {
+            OpKind::Inc => format!("tape[dp] = tape[dp].wrapping_add({operand});"),
+            OpKind::Dec => format!("tape[dp] = tape[dp].wrapping_sub({operand});"),
+            OpKind::Left => format!("left(&mut dp, {amount}, {ip})?;"),
+            OpKind::Right => format!("right(&mut dp, {amount}, {ip})?;"),
+            OpKind::Input => repeat(format!("tape[dp] = read_cell(&mut input, {ip})?;")),
+            OpKind::Output => repeat(format!("write_cell(&mut output, tape[dp], {ip})?;")),
+            OpKind::Jeq0Forward => "while tape[dp] != 0 {".to_string(),
+            OpKind::Jne0Backward => "}".to_string(),
+        };
+        // as rustfmt writes an empty loop
+        if op.kind == OpKind::Jne0Backward && ops[ip - 1].kind == OpKind::Jeq0Forward {
+            rust.pop();
+            rust.push_str("}\n");
+            continue;
+        }
+        writeln!(rust, "{}{statement}", INDENT.repeat(depth)).unwrap();
+        if op.kind == OpKind::Jeq0Forward {
+            depth += 1;
+        }
+    }
+    rust.push_str("    Ok(())\n}\n");
+    rust
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate_ops;
+    use crate::testing::{interpret, programs, run_command, TempDir};
+    use std::fs;
+    use std::process::Command;
+
+    #[test]
+    fn should_take_a_tape_of_the_interpreter_size() {
+        let rust = emit_rust(&generate_ops(">"));
+        assert!(rust.contains("pub const TAPE_SIZE: usize = 65536;\n"));
+        assert!(rust.contains("    tape: &mut [Cell],\n"));
+        assert!(rust.contains("    if tape.len() < TAPE_SIZE {\n"));
+        assert!(rust.contains("    if *dp + amount >= TAPE_SIZE {\n"));
+    }
+
+    #[test]
+    fn should_nest_loops_in_run() {
+        let rust = emit_rust(&generate_ops("+[->>[-]<<]..,"));
+        let run = rust.split("pub fn run").nth(1).unwrap();
+        let body = run.split_once("{\n").unwrap().1;
+        assert_eq!(
+            body,
+            "    if tape.len() < TAPE_SIZE {
+        return Err(RuntimeError {
+            message: format!(\"the tape should have at least {TAPE_SIZE} cells\"),
+        });
+    }
+    let mut dp = 0;
+    tape[dp] = tape[dp].wrapping_add(1);
+    while tape[dp] != 0 {
+        tape[dp] = tape[dp].wrapping_sub(1);
+        right(&mut dp, 2, 3)?;
+        while tape[dp] != 0 {
+            tape[dp] = tape[dp].wrapping_sub(1);
+        }
+        left(&mut dp, 2, 7)?;
+    }
+    for _ in 0..2 {
+        write_cell(&mut output, tape[dp], 9)?;
+    }
+    tape[dp] = read_cell(&mut input, 10)?;
+    Ok(())
+}
+"
+        );
+    }
+
+    // Builds the modules of `programs` into one binary with rustc, where the first argument
+    // picks the program, and compares each with the interpreter, unless there is no rustc
+    #[cfg(unix)]
+    fn assert_same_as_interpreter(programs: &[(String, &[u8])]) {
+        let dir = TempDir::new("rust");
+        let mut main = String::new();
+        for (idx, (program, _)) in programs.iter().enumerate() {
+            fs::write(
+                dir.path().join(format!("p{idx}.rs")),
+                emit_rust(&generate_ops(program)),
+            )
+            .unwrap();
+            writeln!(main, "mod p{idx};").unwrap();
+        }
+        main.push_str(
+            "fn main() {
+    let (stdin, stdout) = (std::io::stdin().lock(), std::io::stdout().lock());
+    let result = match std::env::args().nth(1).unwrap().parse::<usize>().unwrap() {
+",
+        );
+        for idx in 0..programs.len() {
+            // each module has an error type of its own, and any slice is a tape
+            let run = format!("p{idx}::run(&mut vec![0; p{idx}::TAPE_SIZE], stdin, stdout)");
+            writeln!(main, "        {idx} => {run}.map_err(|e| e.to_string()),").unwrap();
+        }
+        main.push_str(
+            "        _ => unreachable!(),
+    };
+    if let Err(e) = result {
+        eprintln!(\"{e}\");
+        std::process::exit(1);
+    }
+}
+",
+        );
+        fs::write(dir.path().join("main.rs"), main).unwrap();
+
+        let compiled = Command::new("rustc")
+            .current_dir(dir.path())
+            .args(["--edition=2021", "-D", "warnings", "main.rs", "-o", "main"])
+            .status();
+        let Ok(compiled) = compiled else {
+            return;
+        };
+        assert!(compiled.success());
+
+        for (idx, (program, input)) in programs.iter().enumerate() {
+            let expected = interpret(&generate_ops(program), input).process();
+            let mut main = Command::new(dir.path().join("main"));
+            let actual = run_command(main.arg(idx.to_string()), input, false);
+            assert_eq!(actual, expected, "{program}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn should_run_like_the_interpreter() {
+        let mut programs = programs();
+        programs.push(("+-".to_string(), b""));
+        assert_same_as_interpreter(&programs);
+    }
+}
@@ -0,0 +1,794 @@
+use crate::jitc::Trap;
+use crate::op::*;
+use crate::MEM_SIZE;
+use std::fmt::Write;
+
+const INDENT: &str = "  ";
+
+const WASM_PAGE_SIZE: usize = 0x10000;
+// the tape at the start of memory
+const PAGES: u32 = (MEM_SIZE * size_of::<Operand>()).div_ceil(WASM_PAGE_SIZE) as u32;
+
+// functions, with the imports first as in the index space
+const GETCHAR: u32 = 0;
+const PUTCHAR: u32 = 1;
+const RUN: u32 = 2;
+
+// locals and globals of `run`
+const DP: u32 = 0;
+const VALUE: u32 = 1;
+const COUNT: u32 = 2;
+const EXIT_DP: u32 = 0;
+
+// `run` returns `ip << 8 | trap` as an i32, which keeps the ip whole below this
+const MAX_OPS: usize = 1 << 23;
+
+const I32: u8 = 0x7f;
+const EMPTY_BLOCK: u8 = 0x40;
+
+// The instructions that `run` needs, which are written either as binary or as text
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Inst {
+    Block,
+    Loop,
+    If,
+    End,
+    BrIf(u32),
+    Return,
+    Call(u32),
+    Select,
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    GlobalSet(u32),
+    I32Load,
+    I32Store,
+    I32Const(i32),
+    I32Eqz,
+    I32LtS,
+    I32GeS,
+    I32LtU,
+    I32GtU,
+    I32GeU,
+    I32Add,
+    I32Sub,
+    I32Shl,
+}
+
+impl Inst {
+    fn encode(self, out: &mut Vec<u8>) {
+        match self {
+            Inst::Block => out.extend([0x02, EMPTY_BLOCK]),
+            Inst::Loop => out.extend([0x03, EMPTY_BLOCK]),
+            Inst::If => out.extend([0x04, EMPTY_BLOCK]),
+            Inst::End => out.push(0x0b),
+            Inst::BrIf(depth) => index(out, 0x0d, depth),
+            Inst::Return => out.push(0x0f),
+            Inst::Call(function) => index(out, 0x10, function),
+            Inst::Select => out.push(0x1b),
+            Inst::LocalGet(local) => index(out, 0x20, local),
+            Inst::LocalSet(local) => index(out, 0x21, local),
+            Inst::LocalTee(local) => index(out, 0x22, local),
+            Inst::GlobalSet(global) => index(out, 0x24, global),
+            // aligned to 4 bytes, at offset 0
+            Inst::I32Load => out.extend([0x28, 2, 0]),
+            Inst::I32Store => out.extend([0x36, 2, 0]),
+            Inst::I32Const(value) => {
+                out.push(0x41);
+                sleb128(out, value);
+            }
+            Inst::I32Eqz => out.push(0x45),
+            Inst::I32LtS => out.push(0x48),
+            Inst::I32LtU => out.push(0x49),
+            Inst::I32GtU => out.push(0x4b),
+            Inst::I32GeS => out.push(0x4e),
+            Inst::I32GeU => out.push(0x4f),
+            Inst::I32Add => out.push(0x6a),
+            Inst::I32Sub => out.push(0x6b),
+            Inst::I32Shl => out.push(0x74),
+        }
+    }
+
+    fn text(self) -> String {
+        let local = |local| match local {
+            DP => "$dp",
+            VALUE => "$value",
+            _ => "$count",
+        };
+        match self {
+            Inst::Block => "block".to_string(),
+            Inst::Loop => "loop".to_string(),
+            Inst::If => "if".to_string(),
+            Inst::End => "end".to_string(),
+            Inst::BrIf(depth) => format!("br_if {depth}"),
+            Inst::Return => "return".to_string(),
+            Inst::Call(GETCHAR) => "call $getchar".to_string(),
+            Inst::Call(_) => "call $putchar".to_string(),
+            Inst::Select => "select".to_string(),
+            Inst::LocalGet(l) => format!("local.get {}", local(l)),
+            Inst::LocalSet(l) => format!("local.set {}", local(l)),
+            Inst::LocalTee(l) => format!("local.tee {}", local(l)),
+            Inst::GlobalSet(_) => "global.set $exit_dp".to_string(),
+            Inst::I32Load => "i32.load".to_string(),
+            Inst::I32Store => "i32.store".to_string(),
+            Inst::I32Const(value) => format!("i32.const {value}"),
+            Inst::I32Eqz => "i32.eqz".to_string(),
+            Inst::I32LtS => "i32.lt_s".to_string(),
+            Inst::I32LtU => "i32.lt_u".to_string(),
+            Inst::I32GtU => "i32.gt_u".to_string(),
+            Inst::I32GeS => "i32.ge_s".to_string(),
+            Inst::I32GeU => "i32.ge_u".to_string(),
+            Inst::I32Add => "i32.add".to_string(),
+            Inst::I32Sub => "i32.sub".to_string(),
+            Inst::I32Shl => "i32.shl".to_string(),
+        }
+    }
+}
+
+fn uleb128(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb128(out: &mut Vec<u8>, mut value: i32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn index(out: &mut Vec<u8>, opcode: u8, index: u32) {
+    out.push(opcode);
+    uleb128(out, index);
+}
+
+// `run` returns what `bf_main` does: 0 when the program ends, and otherwise the ip and the trap
+fn exit(body: &mut Vec<Inst>, status: u64) {
+    body.extend([
+        Inst::LocalGet(DP),
+        Inst::GlobalSet(EXIT_DP),
+        Inst::I32Const(status as i32),
+        Inst::Return,
+    ]);
+}
+
+fn cell_addr(body: &mut Vec<Inst>) {
+    body.extend([Inst::LocalGet(DP), Inst::I32Const(2), Inst::I32Shl]);
+}
+
+fn load_cell(body: &mut Vec<Inst>) {
+    cell_addr(body);
+    body.push(Inst::I32Load);
+}
+
+// `emit` `amount` times, in a loop on `$count` unless it is once, so that the code stays small
+//
+//     i32.const amount
+//     local.set $count
+//     loop
+//       ...
+//       br_if 0 while `$count` - 1 is not 0
+//     end
+fn repeat(body: &mut Vec<Inst>, amount: u32, emit: impl Fn(&mut Vec<Inst>)) {
+    match amount {
+        0 => {}
+        1 => emit(body),
+        _ => {
+            body.extend([
+                Inst::I32Const(amount as i32),
+                Inst::LocalSet(COUNT),
+                Inst::Loop,
+            ]);
+            emit(body);
+            body.extend([
+                Inst::LocalGet(COUNT),
+                Inst::I32Const(1),
+                Inst::I32Sub,
+                Inst::LocalTee(COUNT),
+                Inst::BrIf(0),
+                Inst::End,
+            ]);
+        }
+    }
+}
+
+// the body of `run`, up to its last `end`
+fn compile(ops: &[Op]) -> Vec<Inst> {
+    assert!(
+        ops.len() < MAX_OPS,
+        "a WebAssembly module has room for fewer than {MAX_OPS} ops"
+    );
+    let mut body = Vec::new();
+    for (ip, op) in ops.iter().enumerate() {
+        let amount = op.operand as u32;
+        match op.kind {
+            OpKind::Inc | OpKind::Dec => {
+                cell_addr(&mut body);
+                load_cell(&mut body);
+                body.push(Inst::I32Const(op.operand));
+                body.push(match op.kind {
+                    OpKind::Inc => Inst::I32Add,
+                    _ => Inst::I32Sub,
+                });
+                body.push(Inst::I32Store);
+            }
+            OpKind::Left => {
+                body.extend([Inst::LocalGet(DP), Inst::I32Const(op.operand), Inst::I32LtU]);
+                body.push(Inst::If);
+                exit(&mut body, Trap::NegativeDataPointer.status(ip));
+                body.push(Inst::End);
+                body.extend([
+                    Inst::LocalGet(DP),
+                    Inst::I32Const(op.operand),
+                    Inst::I32Sub,
+                    Inst::LocalSet(DP),
+                ]);
+            }
+            // which cannot wrap around, as both are below `MEM_SIZE`
+            OpKind::Right if (amount as usize) < MEM_SIZE => {
+                body.extend([
+                    Inst::LocalGet(DP),
+                    Inst::I32Const(op.operand),
+                    Inst::I32Add,
+                    Inst::I32Const(MEM_SIZE as i32),
+                    Inst::I32GeU,
+                    Inst::If,
+                ]);
+                exit(&mut body, Trap::DataPointerOverflow.status(ip));
+                body.push(Inst::End);
+                body.extend([
+                    Inst::LocalGet(DP),
+                    Inst::I32Const(op.operand),
+                    Inst::I32Add,
+                    Inst::LocalSet(DP),
+                ]);
+            }
+            OpKind::Right => exit(&mut body, Trap::DataPointerOverflow.status(ip)),
+            // `getchar` returns a byte, -1 at EOF, where the cell becomes 0, or less on errors
+            OpKind::Input => repeat(&mut body, amount, |body| {
+                body.extend([
+                    Inst::Call(GETCHAR),
+                    Inst::LocalTee(VALUE),
+                    Inst::I32Const(-1),
+                    Inst::I32LtS,
+                    Inst::If,
+                ]);
+                exit(body, Trap::Io.status(ip));
+                body.push(Inst::End);
+                cell_addr(body);
+                body.extend([
+                    Inst::LocalGet(VALUE),
+                    Inst::I32Const(0),
+                    Inst::LocalGet(VALUE),
+                    Inst::I32Const(0),
+                    Inst::I32GeS,
+                    Inst::Select,
+                    Inst::I32Store,
+                ]);
+            }),
+            // `putchar` returns nonzero when it fails
+            OpKind::Output => repeat(&mut body, amount, |body| {
+                load_cell(body);
+                body.extend([
+                    Inst::LocalTee(VALUE),
+                    Inst::I32Const(0x7f),
+                    Inst::I32GtU,
+                    Inst::If,
+                ]);
+                exit(body, Trap::Output.status(ip));
+                body.extend([Inst::End, Inst::LocalGet(VALUE), Inst::Call(PUTCHAR)]);
+                body.push(Inst::If);
+                exit(body, Trap::Io.status(ip));
+                body.push(Inst::End);
+            }),
+            // block
+            //   br_if 0 when the cell is 0
+            //   loop
+            //     ...
+            //     br_if 0 unless the cell is 0
+            //   end
+            // end
+            OpKind::Jeq0Forward => {
+                body.push(Inst::Block);
+                load_cell(&mut body);
+                body.extend([Inst::I32Eqz, Inst::BrIf(0), Inst::Loop]);
+            }
+            OpKind::Jne0Backward => {
+                load_cell(&mut body);
+                body.extend([Inst::BrIf(0), Inst::End, Inst::End]);
+            }
+        }
+    }
+    exit(&mut body, 0);
+    body.push(Inst::End);
+    body
+}
+
+fn section(module: &mut Vec<u8>, id: u8, content: Vec<u8>) {
+    module.push(id);
+    uleb128(module, content.len() as u32);
+    module.extend(content);
+}
+
+fn name(out: &mut Vec<u8>, name: &str) {
+    uleb128(out, name.len() as u32);
+    out.extend(name.as_bytes());
+}
+
+// A WebAssembly module that exports `run` and keeps the tape in its exported `memory`, with I/O
+// through the imported `env.getchar` and `env.putchar`:
+//
+//     (func $getchar (result i32))          ;; a byte, -1 at EOF, or less when reading fails
+//     (func $putchar (param i32) (result i32)) ;; nonzero when writing fails
+//     (func $run (result i32))              ;; 0, or the ip << 8 | trap of `bf_main`
+//
+// After `run` returns, the exported global `dp` is the data pointer, so that a host can tell
+// which cell was not ASCII. It panics on `MAX_OPS` ops or more, whose ips the status cannot hold.
+pub fn emit_wasm(ops: &[Op]) -> Vec<u8> {
+    let mut module = b"\0asm".to_vec();
+    module.extend(1u32.to_le_bytes());
+
+    // () -> i32, for `getchar` and `run`, and (i32) -> i32
+    section(
+        &mut module,
+        1,
+        vec![2, 0x60, 0, 1, I32, 0x60, 1, I32, 1, I32],
+    );
+    let mut imports = vec![2];
+    for (function, ty) in [("getchar", 0), ("putchar", 1)] {
+        name(&mut imports, "env");
+        name(&mut imports, function);
+        imports.extend([0x00, ty]);
+    }
+    section(&mut module, 2, imports);
+    section(&mut module, 3, vec![1, 0]);
+    let mut memory = vec![1, 0];
+    uleb128(&mut memory, PAGES);
+    section(&mut module, 5, memory);
+    // a mutable i32 that starts at 0
+    section(&mut module, 6, vec![1, I32, 1, 0x41, 0, 0x0b]);
+    let mut exports = vec![3];
+    for (export, kind, idx) in [
+        ("run", 0x00, RUN),
+        ("memory", 0x02, 0),
+        ("dp", 0x03, EXIT_DP),
+    ] {
+        name(&mut exports, export);
+        exports.push(kind);
+        uleb128(&mut exports, idx);
+    }
+    section(&mut module, 7, exports);
+
+    // `$dp`, `$value` and `$count`
+    let mut body = vec![1, 3, I32];
+    for inst in compile(ops) {
+        inst.encode(&mut body);
+    }
+    let mut code = vec![1];
+    uleb128(&mut code, body.len() as u32);
+    code.extend(body);
+    section(&mut module, 10, code);
+    module
+}
+
+// the same module as `emit_wasm` in the text format
+pub fn emit_wat(ops: &[Op]) -> String {
+    let mut wat = format!(
+        r#"(module
+  (type (;0;) (func (result i32)))
+  (type (;1;) (func (param i32) (result i32)))
+  (import "env" "getchar" (func $getchar (type 0)))
+  (import "env" "putchar" (func $putchar (type 1)))
+  (memory (export "memory") {PAGES})
+  (global $exit_dp (export "dp") (mut i32) (i32.const 0))
+  (func $run (export "run") (type 0) (result i32)
+    (local $dp i32) (local $value i32) (local $count i32)
+"#
+    );
+    let mut depth = 2;
+    let body = compile(ops);
+    // the last `end` closes the function
+    for inst in &body[..body.len() - 1] {
+        if *inst == Inst::End {
+            depth -= 1;
+        }
+        writeln!(wat, "{}{}", INDENT.repeat(depth), inst.text()).unwrap();
+        if matches!(inst, Inst::Block | Inst::Loop | Inst::If) {
+            depth += 1;
+        }
+    }
+    wat.push_str("  )\n)\n");
+    wat
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate_ops;
+    use crate::testing::{interpret, programs};
+
+    struct Reader<'a> {
+        bytes: &'a [u8],
+        pos: usize,
+    }
+
+    impl Reader<'_> {
+        fn byte(&mut self) -> u8 {
+            self.pos += 1;
+            self.bytes[self.pos - 1]
+        }
+
+        fn bytes(&mut self, len: usize) -> &[u8] {
+            self.pos += len;
+            &self.bytes[self.pos - len..self.pos]
+        }
+
+        fn uleb128(&mut self) -> u32 {
+            let (mut value, mut shift) = (0, 0);
+            loop {
+                let byte = self.byte();
+                value |= ((byte & 0x7f) as u32) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    return value;
+                }
+            }
+        }
+
+        fn sleb128(&mut self) -> i32 {
+            let (mut value, mut shift) = (0i64, 0);
+            loop {
+                let byte = self.byte();
+                value |= ((byte & 0x7f) as i64) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    if byte & 0x40 != 0 {
+                        value |= -1 << shift;
+                    }
+                    return value as i32;
+                }
+            }
+        }
+
+        fn name(&mut self) -> String {
+            let len = self.uleb128() as usize;
+            String::from_utf8(self.bytes(len).to_vec()).unwrap()
+        }
+
+        fn done(&self) -> bool {
+            self.pos == self.bytes.len()
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Inst {
+        match reader.byte() {
+            0x02..=0x04 => {
+                let opcode = reader.bytes[reader.pos - 1];
+                assert_eq!(reader.byte(), EMPTY_BLOCK);
+                [Inst::Block, Inst::Loop, Inst::If][opcode as usize - 2]
+            }
+            0x0b => Inst::End,
+            0x0d => Inst::BrIf(reader.uleb128()),
+            0x0f => Inst::Return,
+            0x10 => Inst::Call(reader.uleb128()),
+            0x1b => Inst::Select,
+            0x20 => Inst::LocalGet(reader.uleb128()),
+            0x21 => Inst::LocalSet(reader.uleb128()),
+            0x22 => Inst::LocalTee(reader.uleb128()),
+            0x24 => Inst::GlobalSet(reader.uleb128()),
+            opcode @ (0x28 | 0x36) => {
+                assert_eq!(reader.bytes(2), [2, 0]);
+                match opcode {
+                    0x28 => Inst::I32Load,
+                    _ => Inst::I32Store,
+                }
+            }
+            0x41 => Inst::I32Const(reader.sleb128()),
+            0x45 => Inst::I32Eqz,
+            0x48 => Inst::I32LtS,
+            0x49 => Inst::I32LtU,
+            0x4b => Inst::I32GtU,
+            0x4e => Inst::I32GeS,
+            0x4f => Inst::I32GeU,
+            0x6a => Inst::I32Add,
+            0x6b => Inst::I32Sub,
+            0x74 => Inst::I32Shl,
+            opcode => panic!("unknown opcode {opcode:#x}"),
+        }
+    }
+
+    // Checks every section that `emit_wasm` writes, and returns the body of `run`
+    fn check_module(module: &[u8]) -> Vec<Inst> {
+        assert_eq!(&module[..8], b"\0asm\x01\0\0\0");
+        let mut reader = Reader {
+            bytes: module,
+            pos: 8,
+        };
+        let mut ids = Vec::new();
+        let mut body = Vec::new();
+        while !reader.done() {
+            let id = reader.byte();
+            let len = reader.uleb128() as usize;
+            let mut section = Reader {
+                bytes: reader.bytes(len),
+                pos: 0,
+            };
+            match id {
+                1 => assert_eq!(
+                    section.bytes(len),
+                    [2, 0x60, 0, 1, I32, 0x60, 1, I32, 1, I32]
+                ),
+                2 => {
+                    assert_eq!(section.uleb128(), 2);
+                    for (function, ty) in [("getchar", 0), ("putchar", 1)] {
+                        assert_eq!(section.name(), "env");
+                        assert_eq!(section.name(), function);
+                        assert_eq!(section.bytes(2), [0x00, ty]);
+                    }
+                }
+                3 => assert_eq!(section.bytes(len), [1, 0]),
+                5 => {
+                    assert_eq!(section.bytes(2), [1, 0]);
+                    let pages = section.uleb128() as usize;
+                    assert!(pages * WASM_PAGE_SIZE >= MEM_SIZE * size_of::<Operand>());
+                }
+                6 => assert_eq!(section.bytes(len), [1, I32, 1, 0x41, 0, 0x0b]),
+                7 => {
+                    assert_eq!(section.uleb128(), 3);
+                    let mut exports = Vec::new();
+                    for _ in 0..3 {
+                        let name = section.name();
+                        exports.push((name, section.byte(), section.uleb128()));
+                    }
+                    assert_eq!(
+                        exports,
+                        [
+                            ("run".to_string(), 0x00, RUN),
+                            ("memory".to_string(), 0x02, 0),
+                            ("dp".to_string(), 0x03, EXIT_DP)
+                        ]
+                    );
+                }
+                10 => {
+                    assert_eq!(section.uleb128(), 1);
+                    let len = section.uleb128() as usize;
+                    let mut code = Reader {
+                        bytes: section.bytes(len),
+                        pos: 0,
+                    };
+                    assert_eq!(code.bytes(3), [1, 3, I32]);
+                    // blocks nest, and the last `end` closes the function
+                    let mut depth = 1;
+                    while depth > 0 {
+                        let inst = decode(&mut code);
+                        match inst {
+                            Inst::Block | Inst::Loop | Inst::If => depth += 1,
+                            Inst::End => depth -= 1,
+                            Inst::BrIf(label) => assert!(label < depth),
+                            Inst::Call(function) => assert!(function < RUN),
+                            Inst::LocalGet(l) | Inst::LocalSet(l) | Inst::LocalTee(l) => {
+                                assert!(l <= COUNT)
+                            }
+                            Inst::GlobalSet(global) => assert_eq!(global, EXIT_DP),
+                            _ => {}
+                        }
+                        body.push(inst);
+                    }
+                    assert!(code.done());
+                }
+                _ => panic!("unexpected section {id}"),
+            }
+            assert!(section.done(), "section {id}");
+            ids.push(id);
+        }
+        assert_eq!(ids, [1, 2, 3, 5, 6, 7, 10]);
+        body
+    }
+
+    // Runs the body of `run` on a stack machine with `getchar` and `putchar` over byte buffers,
+    // and returns the status and the global `dp`
+    fn execute(body: &[Inst], memory: &mut [u8], input: &[u8], output: &mut Vec<u8>) -> (i32, i32) {
+        let mut input = input.iter();
+        let (mut stack, mut locals, mut exit_dp) = (Vec::<i32>::new(), [0i32; 3], 0);
+        // where each block, loop and if ends
+        let mut ends = vec![0; body.len()];
+        let mut open = Vec::new();
+        for (pc, inst) in body.iter().enumerate() {
+            match inst {
+                Inst::Block | Inst::Loop | Inst::If => open.push(pc),
+                Inst::End => {
+                    if let Some(start) = open.pop() {
+                        ends[start] = pc;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        macro_rules! pop {
+            () => {
+                stack.pop().unwrap()
+            };
+        }
+        let mut labels: Vec<usize> = Vec::new(); // the starts of the enclosing blocks
+        let mut pc = 0;
+        loop {
+            let inst = body[pc];
+            pc += 1;
+            match inst {
+                Inst::Block | Inst::Loop => labels.push(pc - 1),
+                Inst::If => match pop!() {
+                    0 => pc = ends[pc - 1] + 1,
+                    _ => labels.push(pc - 1),
+                },
+                Inst::End => {
+                    if labels.pop().is_none() {
+                        return (pop!(), exit_dp);
+                    }
+                }
+                Inst::BrIf(depth) => {
+                    if pop!() != 0 {
+                        let target = labels[labels.len() - 1 - depth as usize];
+                        if body[target] == Inst::Loop {
+                            labels.truncate(labels.len() - depth as usize);
+                            pc = target + 1;
+                        } else {
+                            labels.truncate(labels.len() - 1 - depth as usize);
+                            pc = ends[target] + 1;
+                        }
+                    }
+                }
+                Inst::Return => return (pop!(), exit_dp),
+                Inst::Call(GETCHAR) => stack.push(input.next().map_or(-1, |&b| b as i32)),
+                Inst::Call(_) => {
+                    output.push(pop!() as u8);
+                    stack.push(0);
+                }
+                Inst::Select => {
+                    let (condition, b, a) = (pop!(), pop!(), pop!());
+                    stack.push(if condition != 0 { a } else { b });
+                }
+                Inst::LocalGet(l) => stack.push(locals[l as usize]),
+                Inst::LocalSet(l) => locals[l as usize] = pop!(),
+                Inst::LocalTee(l) => {
+                    locals[l as usize] = pop!();
+                    stack.push(locals[l as usize]);
+                }
+                Inst::GlobalSet(_) => exit_dp = pop!(),
+                Inst::I32Load => {
+                    let addr = pop!() as usize;
+                    let value = i32::from_le_bytes(memory[addr..addr + 4].try_into().unwrap());
+                    stack.push(value);
+                }
+                Inst::I32Store => {
+                    let (value, addr) = (pop!(), pop!() as usize);
+                    memory[addr..addr + 4].copy_from_slice(&value.to_le_bytes());
+                }
+                Inst::I32Const(value) => stack.push(value),
+                Inst::I32Eqz => {
+                    let a = pop!();
+                    stack.push((a == 0) as i32);
+                }
+                _ => {
+                    let (b, a) = (pop!(), pop!());
+                    stack.push(match inst {
+                        Inst::I32LtS => (a < b) as i32,
+                        Inst::I32GeS => (a >= b) as i32,
+                        Inst::I32LtU => ((a as u32) < b as u32) as i32,
+                        Inst::I32GtU => (a as u32 > b as u32) as i32,
+                        Inst::I32GeU => (a as u32 >= b as u32) as i32,
+                        Inst::I32Add => a.wrapping_add(b),
+                        Inst::I32Sub => a.wrapping_sub(b),
+                        _ => a << b,
+                    });
+                }
+            }
+        }
+    }
+
+    fn assert_same_as_interpreter(program: &str, input: &[u8]) {
+        let ops = generate_ops(program);
+        let expected = interpret(&ops, input);
+
+        let body = check_module(&emit_wasm(&ops));
+        let mut linear = vec![0u8; PAGES as usize * WASM_PAGE_SIZE];
+        let mut output = Vec::new();
+        let (status, dp) = execute(&body, &mut linear, input, &mut output);
+        assert_eq!(output, expected.output, "{program}");
+        let tape: Vec<i32> = (linear.chunks(4))
+            .take(MEM_SIZE)
+            .map(|cell| i32::from_le_bytes(cell.try_into().unwrap()))
+            .collect();
+        match expected.result {
+            Ok(()) => {
+                assert_eq!(status, 0, "{program}");
+                assert!(tape[..] == expected.memory[..], "{program}");
+                assert_eq!(dp as usize, expected.dp, "{program}");
+            }
+            Err(e) => {
+                // the host explains a cell that is not ASCII from the data pointer
+                let ip = status >> 8;
+                let message = match status & 0xff {
+                    1 => "data pointer is negative",
+                    2 => "data pointer exceeded memory size",
+                    _ if tape[dp as usize] as u32 > 0xff => "cannot reinterpret the byte into char",
+                    _ => "the value is not in the ASCII range",
+                };
+                assert_eq!(e, format!("RUNTIME ERROR: {message} [IP:{ip}]"));
+            }
+        }
+    }
+
+    #[test]
+    fn should_run_like_the_interpreter() {
+        for (program, input) in programs() {
+            assert_same_as_interpreter(&program, input);
+        }
+    }
+
+    #[test]
+    fn should_return_the_status_of_a_trap() {
+        assert_same_as_interpreter(&">".repeat(MEM_SIZE), b"");
+        assert_same_as_interpreter(&format!("{}.", "+".repeat(256)), b"");
+    }
+
+    #[test]
+    fn should_repeat_io_in_a_loop() {
+        let program = format!(
+            "{}{}>,,,.{}",
+            "+".repeat(65),
+            ".".repeat(300),
+            ",".repeat(5)
+        );
+        assert_same_as_interpreter(&program, b"abcd");
+        assert_same_as_interpreter("+.......-.", b"");
+        assert_same_as_interpreter(&format!("{}..", "+".repeat(128)), b"");
+
+        // the code does not grow with the count
+        for kind in [OpKind::Input, OpKind::Output] {
+            let ops = [Op {
+                kind,
+                operand: 20_000_000,
+            }];
+            let body = check_module(&emit_wasm(&ops));
+            assert_eq!(body.iter().filter(|inst| **inst == Inst::Loop).count(), 1);
+            assert!(body.len() < 50);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "fewer than 8388608 ops")]
+    fn should_refuse_ips_that_the_status_cannot_hold() {
+        let op = Op {
+            kind: OpKind::Output,
+            operand: 1,
+        };
+        emit_wasm(&vec![op; MAX_OPS]);
+    }
+
+    #[test]
+    fn should_write_text_for_the_same_instructions() {
+        let ops = generate_ops("+[-]");
+        let wat = emit_wat(&ops);
+        let body = compile(&ops);
+        let lines: Vec<&str> = wat.lines().skip(9).map(str::trim).collect();
+        assert_eq!(lines.len(), body.len() + 1);
+        for (line, inst) in lines.iter().zip(&body[..body.len() - 1]) {
+            assert_eq!(*line, inst.text());
+        }
+        assert!(wat.contains("      loop\n        local.get $dp\n"));
+        assert_eq!(wat.matches('(').count(), wat.matches(')').count());
+    }
+}